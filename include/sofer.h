@@ -0,0 +1,204 @@
+/* Generated by cbindgen from src/ffi.rs. Don't edit it by hand. */
+
+#ifndef SOFER_H
+#define SOFER_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The UUIDs of the children of a node, as they were when the iterator was made.
+ */
+typedef struct SoferChildren SoferChildren;
+
+/**
+ * An outline. The root node has the nil UUID, "00000000-0000-0000-0000-000000000000".
+ */
+typedef struct SoferTree SoferTree;
+
+/**
+ * The message of the last error in this thread, or NULL if there wasn't any. It's owned by
+ * the library and valid until the next call that fails.
+ */
+const char *sofer_last_error(void);
+
+/**
+ * # Safety
+ *
+ * `str` must be NULL or a string returned by this library that wasn't freed yet. It can't be
+ * used after this call.
+ */
+void sofer_string_free(char *str);
+
+/**
+ * A new outline with only the root.
+ */
+struct SoferTree *sofer_tree_new(void);
+
+/**
+ * # Safety
+ *
+ * `tree` must be NULL or a tree returned by this library that wasn't freed yet. It can't be used
+ * after this call.
+ */
+void sofer_tree_free(struct SoferTree *tree);
+
+/**
+ * Reads an outline from `text` in `format`: "sofer", "lua" or "opml".
+ *
+ * # Safety
+ *
+ * `text` and `format` must be NULL or point to NUL-terminated strings, valid for the length of
+ * the call.
+ */
+struct SoferTree *sofer_tree_load(const char *text, const char *format);
+
+/**
+ * Writes the outline in `format`: "sofer", "lua" or "pretty". With `evaled` not 0, the
+ * formats with one text per node use the evaluated text.
+ *
+ * # Safety
+ *
+ * `tree` must be NULL or a live tree from this library, and `format` NULL or a NUL-terminated
+ * string.
+ */
+char *sofer_tree_save(const struct SoferTree *tree, const char *format, int evaled);
+
+/**
+ * Reads an outline from the SQLite database at `path`.
+ *
+ * # Safety
+ *
+ * `path` must be NULL or point to a NUL-terminated string, valid for the length of the call.
+ */
+struct SoferTree *sofer_tree_load_sqlite(const char *path);
+
+/**
+ * Writes the outline to the SQLite database at `path`, replacing what it had.
+ *
+ * # Safety
+ *
+ * `tree` must be NULL or a live tree from this library, and `path` NULL or a NUL-terminated
+ * string.
+ */
+int sofer_tree_save_sqlite(const struct SoferTree *tree, const char *path);
+
+/**
+ * 1 if the outline has a node with `uuid`, 0 if it doesn't.
+ *
+ * # Safety
+ *
+ * `tree` must be NULL or a live tree from this library, and `uuid` NULL or a NUL-terminated
+ * string.
+ */
+int sofer_tree_contains(const struct SoferTree *tree, const char *uuid);
+
+/**
+ * The text of a node, without evaluating it.
+ *
+ * # Safety
+ *
+ * `tree` must be NULL or a live tree from this library, and `uuid` NULL or a NUL-terminated
+ * string.
+ */
+char *sofer_tree_text(const struct SoferTree *tree, const char *uuid);
+
+/**
+ * The text of a node, evaluated. If its script fails, the error is the last error.
+ *
+ * # Safety
+ *
+ * `tree` must be NULL or a live tree from this library, and `uuid` NULL or a NUL-terminated
+ * string.
+ */
+char *sofer_tree_eval(const struct SoferTree *tree, const char *uuid);
+
+/**
+ * Inserts a node with `text` under `parent`, after the child `after`, or as the first child if
+ * `after` is NULL. Returns the UUID of the new node.
+ *
+ * # Safety
+ *
+ * `tree` must be NULL or a live tree from this library, not used by another thread during the
+ * call. The strings must be NULL or NUL-terminated.
+ */
+char *sofer_tree_insert(struct SoferTree *tree,
+                        const char *parent,
+                        const char *after,
+                        const char *text);
+
+/**
+ * Moves a node under `parent`, after the child `after`, or as the first child if `after` is
+ * NULL.
+ *
+ * # Safety
+ *
+ * `tree` must be NULL or a live tree from this library, not used by another thread during the
+ * call. The strings must be NULL or NUL-terminated.
+ */
+int sofer_tree_move(struct SoferTree *tree,
+                    const char *uuid,
+                    const char *parent,
+                    const char *after);
+
+/**
+ * Deletes a node and its subtree.
+ *
+ * # Safety
+ *
+ * `tree` must be NULL or a live tree from this library, not used by another thread during the
+ * call, and `uuid` NULL or a NUL-terminated string.
+ */
+int sofer_tree_delete(struct SoferTree *tree, const char *uuid);
+
+/**
+ * Replaces the text of a node.
+ *
+ * # Safety
+ *
+ * `tree` must be NULL or a live tree from this library, not used by another thread during the
+ * call. The strings must be NULL or NUL-terminated.
+ */
+int sofer_tree_set_text(struct SoferTree *tree, const char *uuid, const char *text);
+
+/**
+ * Replaces the script of a node, or removes it if `script` is NULL.
+ *
+ * # Safety
+ *
+ * `tree` must be NULL or a live tree from this library, not used by another thread during the
+ * call. The strings must be NULL or NUL-terminated.
+ */
+int sofer_tree_set_script(struct SoferTree *tree, const char *uuid, const char *script);
+
+/**
+ * An iterator over the UUIDs of the children of a node.
+ *
+ * # Safety
+ *
+ * `tree` must be NULL or a live tree from this library, and `uuid` NULL or a NUL-terminated
+ * string. The iterator doesn't borrow the tree, so it can outlive it.
+ */
+struct SoferChildren *sofer_tree_children(const struct SoferTree *tree, const char *uuid);
+
+/**
+ * The UUID of the next child, or NULL when there are no more.
+ *
+ * # Safety
+ *
+ * `children` must be NULL or a live iterator from `sofer_tree_children`, not used by another
+ * thread during the call.
+ */
+char *sofer_children_next(struct SoferChildren *children);
+
+/**
+ * # Safety
+ *
+ * `children` must be NULL or an iterator from `sofer_tree_children` that wasn't freed yet. It
+ * can't be used after this call.
+ */
+void sofer_children_free(struct SoferChildren *children);
+
+#endif /* SOFER_H */
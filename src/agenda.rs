@@ -0,0 +1,269 @@
+use std::fmt;
+use uuid::Uuid;
+
+use date::Date;
+use json::Json;
+use node::{Attribute, TreeNode};
+use task::{self, Workflow};
+
+/* The agenda lists the nodes with a `scheduled` or `due` date (`YYYY-MM-DD`, possibly followed
+ * by a time) day by day over a range of days. Dates before the range that are still pending are
+ * listed as overdue. Closed tasks are left out.
+ */
+
+const WEEKDAYS: [&str; 7] = ["Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Scheduled,
+    Due,
+}
+
+impl Kind {
+    fn attribute(&self) -> &'static str {
+        match *self {
+            Kind::Scheduled => "scheduled",
+            Kind::Due => "due",
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.attribute())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub uuid: Uuid,
+    pub kind: Kind,
+    pub date: String,
+    pub text: String,
+    pub status: Option<String>,
+    /// The first lines of the ancestors, from the top-level one.
+    pub path: Vec<String>,
+}
+
+impl Entry {
+    fn context(&self) -> String {
+        self.path.join(" / ")
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("uuid", Json::string(self.uuid.to_string())),
+            ("kind", Json::string(self.kind.to_string())),
+            ("date", Json::string(self.date.clone())),
+            ("text", Json::string(self.text.clone())),
+            ("status", self.status.clone().map(Json::string).unwrap_or(Json::Null)),
+            ("path", Json::Array(self.path.iter().cloned().map(Json::string).collect())),
+        ])
+    }
+}
+
+pub struct Day {
+    pub date: String,
+    pub weekday: &'static str,
+    pub entries: Vec<Entry>,
+}
+
+pub struct Agenda {
+    pub overdue: Vec<Entry>,
+    pub days: Vec<Day>,
+}
+
+fn first_line(n: &TreeNode) -> String {
+    n.value.raw.lines().next().unwrap_or("").into()
+}
+
+/// The agenda of `tree` for `days` days from `from`, a `YYYY-MM-DD` date, or from today.
+pub fn agenda(tree: &TreeNode, from: Option<&str>, days: usize) -> Result<Agenda, String> {
+    fn collect(n: &TreeNode, workflow: &Workflow, path: &mut Vec<String>, entries: &mut Vec<(Date, Entry)>) {
+        let declared = task::declared_workflow(n);
+        let workflow = declared.as_ref().unwrap_or(workflow);
+        let status = task::status(n);
+        if !status.map(|s| workflow.is_closed(s)).unwrap_or(false) {
+            for &kind in &[Kind::Scheduled, Kind::Due] {
+                if let Some(Attribute::String(_, value)) = n.value.get_attribute(kind.attribute()) {
+                    if let Some(date) = Date::parse(value) {
+                        entries.push((date, Entry {
+                            uuid: n.uuid,
+                            kind,
+                            date: date.to_string(),
+                            text: first_line(n),
+                            status: status.map(String::from),
+                            path: path.clone(),
+                        }));
+                    }
+                }
+            }
+        }
+
+        // The root has no text to show in the paths.
+        let is_root = n.uuid.is_nil();
+        if !is_root {
+            path.push(first_line(n));
+        }
+        for child in n.children() {
+            collect(child, workflow, path, entries);
+        }
+        if !is_root {
+            path.pop();
+        }
+    }
+
+    let from = match from {
+        Some(from) => Date::parse(from).ok_or(format!("Couldn't read date \"{}\"", from))?,
+        None => Date::today(),
+    };
+    let mut entries = Vec::new();
+    collect(tree, &Workflow::default(), &mut Vec::new(), &mut entries);
+
+    let mut overdue = entries.iter().filter(|e| e.0 < from).cloned().collect::<Vec<_>>();
+    overdue.sort_by_key(|e| e.0);
+    let days = (0..days as i64)
+        .map(|i| {
+            let date = from.add_days(i);
+            Day {
+                date: date.to_string(),
+                weekday: WEEKDAYS[date.weekday() as usize],
+                entries: entries.iter().filter(|e| e.0 == date).map(|e| e.1.clone()).collect(),
+            }
+        })
+        .collect();
+    Ok(Agenda { overdue: overdue.into_iter().map(|e| e.1).collect(), days })
+}
+
+impl Agenda {
+    pub fn to_text(&self) -> String {
+        let line = |e: &Entry, date: bool| {
+            let mut line = String::from("  ");
+            if date {
+                line.push_str(&format!("{}  ", e.date));
+            }
+            line.push_str(&format!("{:<9}  ", e.kind.attribute()));
+            if let Some(ref status) = e.status {
+                line.push_str(&format!("{} ", status));
+            }
+            line.push_str(&e.text);
+            if !e.path.is_empty() {
+                line.push_str(&format!("  ({})", e.context()));
+            }
+            line.push('\n');
+            line
+        };
+
+        let mut str = String::new();
+        if !self.overdue.is_empty() {
+            str.push_str("Overdue\n");
+            for e in &self.overdue {
+                str.push_str(&line(e, true));
+            }
+        }
+        for day in &self.days {
+            str.push_str(&format!("{} {}\n", day.date, day.weekday));
+            for e in &day.entries {
+                str.push_str(&line(e, false));
+            }
+        }
+        str
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let line = |e: &Entry, date: bool| {
+            let mut line = String::from("- ");
+            if date {
+                line.push_str(&format!("{} ", e.date));
+            }
+            line.push_str(&format!("*{}* ", e.kind));
+            if let Some(ref status) = e.status {
+                line.push_str(&format!("**{}** ", status));
+            }
+            line.push_str(&e.text);
+            if !e.path.is_empty() {
+                line.push_str(&format!(" ({})", e.context()));
+            }
+            line.push('\n');
+            line
+        };
+
+        let mut str = String::new();
+        if !self.overdue.is_empty() {
+            str.push_str("## Overdue\n\n");
+            for e in &self.overdue {
+                str.push_str(&line(e, true));
+            }
+            str.push('\n');
+        }
+        for day in &self.days {
+            str.push_str(&format!("## {} {}\n\n", day.date, day.weekday));
+            for e in &day.entries {
+                str.push_str(&line(e, false));
+            }
+            if !day.entries.is_empty() {
+                str.push('\n');
+            }
+        }
+        str
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("overdue", Json::Array(self.overdue.iter().map(|e| e.to_json()).collect())),
+            ("days", Json::Array(self.days.iter().map(|day| Json::object(vec![
+                ("date", Json::string(day.date.clone())),
+                ("weekday", Json::string(day.weekday)),
+                ("entries", Json::Array(day.entries.iter().map(|e| e.to_json()).collect())),
+            ])).collect())),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn agenda_by_day() {
+        let tree = TreeNode::import_from_sofer(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  Work
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000001 status="TODO";due="2026-10-20"; Report
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000001 scheduled="2026-10-18T09:00";due="2026-10-16"; Review
+00000000-0000-0000-0000-000000000004 00000000-0000-0000-0000-000000000001 status="DONE";due="2026-10-01"; Old
+00000000-0000-0000-0000-000000000005 00000000-0000-0000-0000-000000000000 due="2026-11-01"; Later
+"#).unwrap();
+        let agenda = agenda(&tree, Some("2026-10-18"), 3).unwrap();
+        assert_eq!(agenda.to_text(), "\
+Overdue
+  2026-10-16  due        Review  (Work)
+2026-10-18 Sunday
+  scheduled  Review  (Work)
+2026-10-19 Monday
+2026-10-20 Tuesday
+  due        TODO Report  (Work)
+");
+        assert_eq!(agenda.to_markdown(), "\
+## Overdue
+
+- 2026-10-16 *due* Review (Work)
+
+## 2026-10-18 Sunday
+
+- *scheduled* Review (Work)
+
+## 2026-10-19 Monday
+
+## 2026-10-20 Tuesday
+
+- *due* **TODO** Report (Work)
+
+");
+        assert_eq!(
+            agenda.to_json().get("days").and_then(|d| match d { Json::Array(days) => days.get(2).cloned(), _ => None })
+                .and_then(|d| d.get("entries").cloned()).map(|e| e.to_string()),
+            Some(r#"[{"uuid":"00000000-0000-0000-0000-000000000002","kind":"due","date":"2026-10-20","text":"Report","status":"TODO","path":["Work"]}]"#.into())
+        );
+        assert!(super::agenda(&tree, Some("tomorrow"), 1).is_err());
+    }
+}
@@ -0,0 +1,218 @@
+use uuid::Uuid;
+
+use date::{self, Date};
+use node::{Attribute, TreeNode};
+use oplog::Operation;
+
+/* Time tracking. Clocking in on a node sets its `clock_started` to the time, and clocking out
+ * adds the interval to its `clock`, a list of UTC times like
+ * `2026-10-18T09:00:00Z/2026-10-18T10:30:00Z,...`. Only one clock runs at a time: clocking in
+ * clocks out of the node that was clocked in.
+ *
+ * Reports sum the time of each node with the time of its descendants.
+ */
+
+const CLOCK: &str = "clock";
+const CLOCK_STARTED: &str = "clock_started";
+
+fn string_attribute<'a>(n: &'a TreeNode, name: &str) -> Option<&'a str> {
+    match n.value.get_attribute(name) {
+        Some(Attribute::String(_, value)) => Some(value),
+        _ => None,
+    }
+}
+
+/// The intervals clocked on `n`, as seconds since the Unix epoch.
+pub fn intervals(n: &TreeNode) -> Vec<(i64, i64)> {
+    string_attribute(n, CLOCK)
+        .map(|clock| {
+            clock.split(',')
+                .filter_map(|interval| {
+                    let mut times = interval.split('/');
+                    let start = date::parse_timestamp(times.next()?)?;
+                    let end = date::parse_timestamp(times.next()?)?;
+                    Some((start, end))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The node clocked in, and since when.
+pub fn running(tree: &TreeNode) -> Option<(Uuid, i64)> {
+    tree.nodes()
+        .into_iter()
+        .filter_map(|n| string_attribute(n, CLOCK_STARTED).and_then(date::parse_timestamp).map(|t| (n.uuid, t)))
+        .next()
+}
+
+/// The changes that clock out of the node clocked in at `now`.
+pub fn clock_out(tree: &TreeNode, now: i64) -> Result<Vec<Operation>, String> {
+    let (uuid, started) = running(tree).ok_or("No clock is running".to_string())?;
+    let n = tree.find(uuid).unwrap();
+    let interval = format!("{}/{}", date::timestamp(started), date::timestamp(now.max(started)));
+    let clock = match string_attribute(n, CLOCK) {
+        Some(clock) if !clock.is_empty() => format!("{},{}", clock, interval),
+        _ => interval,
+    };
+    Ok(vec![
+        Operation::set_attr(tree, uuid, CLOCK_STARTED, None).unwrap(),
+        Operation::set_attr(tree, uuid, CLOCK, Some(Attribute::String(CLOCK.into(), clock))).unwrap(),
+    ])
+}
+
+/// The changes that clock in on the node `uuid` at `now`, clocking out of the one clocked in.
+pub fn clock_in(tree: &TreeNode, uuid: Uuid, now: i64) -> Result<Vec<Operation>, String> {
+    if tree.find(uuid).is_none() {
+        return Err(format!("Couldn't find node with UUID \"{}\"", uuid));
+    }
+    let mut operations = match running(tree) {
+        Some((running, _)) if running == uuid => return Err("The clock is already running on this node".into()),
+        Some(_) => clock_out(tree, now)?,
+        None => Vec::new(),
+    };
+    let started = Attribute::String(CLOCK_STARTED.into(), date::timestamp(now));
+    operations.push(Operation::set_attr(tree, uuid, CLOCK_STARTED, Some(started)).unwrap());
+    Ok(operations)
+}
+
+pub struct Row {
+    pub uuid: Uuid,
+    /// Depth under the root of the report, which is at 0, or whose children are if it's the root
+    /// of the outline.
+    pub depth: usize,
+    pub text: String,
+    /// Seconds clocked on the node itself.
+    pub own: i64,
+    /// Seconds clocked on the node and its descendants.
+    pub total: i64,
+}
+
+/// The time clocked under the node `root`, between the dates `from` and `to` included, for each
+/// node with some time. A running clock counts until `now`.
+pub fn report(tree: &TreeNode, root: Uuid, from: Option<&str>, to: Option<&str>, now: i64) -> Result<Vec<Row>, String> {
+    fn walk(n: &TreeNode, depth: usize, range: (i64, i64), running: Option<(Uuid, i64)>, rows: &mut Vec<Row>) -> i64 {
+        let mut intervals = intervals(n);
+        if let Some((uuid, started)) = running {
+            if uuid == n.uuid {
+                intervals.push((started, range.1));
+            }
+        }
+        let own = intervals.iter().map(|&(start, end)| (end.min(range.1) - start.max(range.0)).max(0)).sum();
+
+        let index = rows.len();
+        rows.push(Row { uuid: n.uuid, depth, text: n.value.raw.lines().next().unwrap_or("").into(), own, total: 0 });
+        let mut total = own;
+        for child in n.children() {
+            total += walk(child, depth + 1, range, running, rows);
+        }
+        if total > 0 {
+            rows[index].total = total;
+        } else {
+            rows.truncate(index);
+        }
+        total
+    }
+
+    let date = |date: &str| Date::parse(date).ok_or(format!("Couldn't read date \"{}\"", date));
+    let start = match from {
+        Some(from) => date(from)?.to_days() * 86400,
+        None => i64::MIN,
+    };
+    let end = match to {
+        Some(to) => (date(to)?.to_days() + 1) * 86400,
+        None => i64::MAX,
+    };
+    let n = tree.find(root).ok_or(format!("Couldn't find node with UUID \"{}\"", root))?;
+    let running = running(tree);
+    let mut rows = Vec::new();
+    // The root of the outline has no text to show.
+    if n.uuid == tree.uuid {
+        for child in n.children() {
+            walk(child, 0, (start, end.min(now)), running, &mut rows);
+        }
+    } else {
+        walk(n, 0, (start, end.min(now)), running, &mut rows);
+    }
+    Ok(rows)
+}
+
+fn duration(seconds: i64) -> String {
+    format!("{}:{:02}", seconds / 3600, seconds / 60 % 60)
+}
+
+pub fn to_table(rows: &[Row]) -> String {
+    let mut str = format!("{:>8}  {:>8}  {}\n", "Total", "Own", "Node");
+    for row in rows {
+        str.push_str(&format!(
+            "{:>8}  {:>8}  {}{}\n",
+            duration(row.total), duration(row.own), "    ".repeat(row.depth), row.text
+        ));
+    }
+    str
+}
+
+pub fn to_csv(rows: &[Row]) -> String {
+    let field = |str: &str| {
+        if str.contains([',', '"', '\n']) {
+            format!("\"{}\"", str.replace('"', "\"\""))
+        } else {
+            str.into()
+        }
+    };
+    let mut str = String::from("uuid,depth,text,own_minutes,total_minutes\n");
+    for row in rows {
+        str.push_str(&format!("{},{},{},{},{}\n", row.uuid, row.depth, field(&row.text), row.own / 60, row.total / 60));
+    }
+    str
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uuid(n: u8) -> Uuid {
+        Uuid::parse_str(&format!("00000000-0000-0000-0000-{:012}", n)).unwrap()
+    }
+
+    fn apply(tree: &mut TreeNode, operations: Vec<Operation>) {
+        for operation in operations {
+            assert!(operation.apply(tree));
+        }
+    }
+
+    #[test]
+    fn clock_in_out_and_report() {
+        let mut tree = TreeNode::import_from_sofer(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 clock="2026-10-17T09:00:00Z/2026-10-17T10:00:00Z"; Work
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000001  Report, draft
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000001  Idle
+"#).unwrap();
+        let day = date::parse_timestamp("2026-10-18").unwrap();
+
+        assert!(clock_out(&tree, day).is_err());
+        let operations = clock_in(&tree, uuid(2), day + 3600).unwrap();
+        apply(&mut tree, operations);
+        assert_eq!(running(&tree), Some((uuid(2), day + 3600)));
+        assert!(clock_in(&tree, uuid(2), day + 3600).is_err());
+
+        // Clocking in elsewhere clocks out first.
+        let operations = clock_in(&tree, uuid(1), day + 5400).unwrap();
+        apply(&mut tree, operations);
+        assert_eq!(intervals(tree.find(uuid(2)).unwrap()), vec![(day + 3600, day + 5400)]);
+        let operations = clock_out(&tree, day + 7200).unwrap();
+        apply(&mut tree, operations);
+        assert_eq!(running(&tree), None);
+
+        let rows = report(&tree, Uuid::nil(), Some("2026-10-18"), None, day + 86400).unwrap();
+        assert_eq!(to_table(&rows), "   Total       Own  Node
+    1:00      0:30  Work
+    0:30      0:30      Report, draft
+");
+        let rows = report(&tree, uuid(1), None, Some("2026-10-18"), day + 86400).unwrap();
+        assert_eq!(to_csv(&rows), "uuid,depth,text,own_minutes,total_minutes
+00000000-0000-0000-0000-000000000001,0,Work,90,120
+00000000-0000-0000-0000-000000000002,1,\"Report, draft\",30,30
+");
+    }
+}
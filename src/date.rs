@@ -0,0 +1,146 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/* Dates are stored in attributes as `YYYY-MM-DD` strings, so they sort and compare correctly as
+ * plain text. This module only does the calendar arithmetic needed on top of that.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    pub fn today() -> Date {
+        Date::from_days(now_seconds().div_euclid(86400))
+    }
+
+    /// Reads a date written `YYYY-MM-DD`, ignoring what follows it, like the time of a timestamp.
+    pub fn parse(str: &str) -> Option<Date> {
+        let mut parts = str.get(..10)?.split('-');
+        let year = parts.next()?.parse().ok()?;
+        let month = parts.next()?.parse().ok()?;
+        let day = parts.next()?.parse().ok()?;
+        let date = Date { year, month, day };
+        if Date::from_days(date.to_days()) == date {
+            Some(date)
+        } else {
+            None
+        }
+    }
+
+    /// Days since 1970-01-01.
+    pub fn to_days(self) -> i64 {
+        let y = if self.month <= 2 { self.year - 1 } else { self.year } as i64;
+        let m = self.month as i64;
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146097 + doe - 719468
+    }
+
+    pub fn from_days(days: i64) -> Date {
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = (yoe + era * 400) as i32 + if month <= 2 { 1 } else { 0 };
+        Date { year, month, day }
+    }
+
+    pub fn add_days(&self, days: i64) -> Date {
+        Date::from_days(self.to_days() + days)
+    }
+
+    /// 0 = Monday, ..., 6 = Sunday.
+    pub fn weekday(&self) -> u32 {
+        (self.to_days() + 3).rem_euclid(7) as u32
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// Seconds since the Unix epoch (UTC).
+pub fn now_seconds() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(_) => 0,
+    }
+}
+
+/// `seconds` since the Unix epoch as a UTC time, `YYYY-MM-DDTHH:MM:SSZ`. It starts with the date,
+/// so it compares with dates as text too.
+pub fn timestamp(seconds: i64) -> String {
+    let time = seconds.rem_euclid(86400);
+    format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        Date::from_days(seconds.div_euclid(86400)), time / 3600, time / 60 % 60, time % 60
+    )
+}
+
+/// Reads a UTC time written `YYYY-MM-DDTHH:MM[:SS][Z]`, or a date, which is its midnight, as
+/// seconds since the Unix epoch.
+pub fn parse_timestamp(str: &str) -> Option<i64> {
+    let days = Date::parse(str)?.to_days();
+    let time = &str[10..];
+    if time.is_empty() {
+        return Some(days * 86400);
+    }
+    if !time.starts_with('T') {
+        return None;
+    }
+    let time = time[1..].trim_end_matches('Z');
+    let mut parts = time.split(':');
+    let hours = parts.next()?.parse::<u32>().ok()? as i64;
+    let minutes = parts.next()?.parse::<u32>().ok()? as i64;
+    let seconds = match parts.next() {
+        Some(seconds) => seconds.parse::<u32>().ok()? as i64,
+        None => 0,
+    };
+    if parts.next().is_some() || hours > 23 || minutes > 59 || seconds > 59 {
+        return None;
+    }
+    Some(days * 86400 + hours * 3600 + minutes * 60 + seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn days_roundtrip() {
+        assert_eq!(Date { year: 1970, month: 1, day: 1 }.to_days(), 0);
+        assert_eq!(Date::from_days(0), Date { year: 1970, month: 1, day: 1 });
+        assert_eq!(Date { year: 2000, month: 3, day: 1 }.to_days(), 11017);
+        for days in -1000..30000 {
+            assert_eq!(Date::from_days(days).to_days(), days);
+        }
+    }
+
+    #[test]
+    fn add_days_and_display() {
+        let date = Date { year: 2024, month: 2, day: 29 };
+        assert_eq!(date.add_days(1).to_string(), "2024-03-01");
+        assert_eq!(date.add_days(-60).to_string(), "2023-12-31");
+        assert_eq!(Date { year: 2026, month: 10, day: 18 }.weekday(), 6);
+        assert_eq!(timestamp(86400 * 365 + 3723), "1971-01-01T01:02:03Z");
+        assert_eq!(Date::parse("2024-02-29T10:00:00Z"), Some(date));
+        assert_eq!(Date::parse("2023-02-29"), None);
+        assert_eq!(Date::parse("soon"), None);
+        assert_eq!(parse_timestamp("1971-01-01T01:02:03Z"), Some(86400 * 365 + 3723));
+        assert_eq!(parse_timestamp("1971-01-01T01:02"), Some(86400 * 365 + 3720));
+        assert_eq!(parse_timestamp("1971-01-01"), Some(86400 * 365));
+        assert_eq!(parse_timestamp("1971-01-01T25:00"), None);
+    }
+}
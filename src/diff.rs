@@ -0,0 +1,318 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use uuid::Uuid;
+
+use json::Json;
+use node::{Attribute, TreeNode};
+
+/* Structural diff between two versions of an outline. Nodes are matched by UUID, so a node
+ * that changed place and text is reported as moved and edited, not as removed and added.
+ */
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    Added { uuid: Uuid, parent: Uuid, text: String },
+    Removed { uuid: Uuid, parent: Uuid, text: String },
+    /// The node has a different parent.
+    Moved { uuid: Uuid, from: Uuid, to: Uuid },
+    /// The node has the same parent, but its order relative to its siblings changed.
+    Reordered { uuid: Uuid, parent: Uuid },
+    TextChanged { uuid: Uuid, old: String, new: String },
+    ScriptChanged { uuid: Uuid, old: Option<String>, new: Option<String> },
+    AttributeChanged { uuid: Uuid, name: String, old: Option<Attribute>, new: Option<Attribute> },
+}
+
+struct Entry<'a> {
+    parent: Uuid,
+    node: &'a TreeNode,
+}
+
+fn entries<'a>(tree: &'a TreeNode) -> (Vec<Uuid>, BTreeMap<Uuid, Entry<'a>>) {
+    fn walk<'a>(n: &'a TreeNode, order: &mut Vec<Uuid>, entries: &mut BTreeMap<Uuid, Entry<'a>>) {
+        for child in n.children() {
+            order.push(child.uuid);
+            entries.insert(child.uuid, Entry { parent: n.uuid, node: child });
+            walk(child, order, entries);
+        }
+    }
+    let mut order = Vec::new();
+    let mut entries = BTreeMap::new();
+    walk(tree, &mut order, &mut entries);
+    (order, entries)
+}
+
+/// Longest common subsequence of `a` and `b`.
+fn lcs(a: &[Uuid], b: &[Uuid]) -> Vec<Uuid> {
+    let mut lengths = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut common = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            common.push(a[i]);
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    common
+}
+
+pub fn diff(old: &TreeNode, new: &TreeNode) -> Vec<Change> {
+    let (old_order, old_entries) = entries(old);
+    let (new_order, new_entries) = entries(new);
+    let mut changes = Vec::new();
+
+    for uuid in &old_order {
+        if !new_entries.contains_key(uuid) {
+            let entry = &old_entries[uuid];
+            changes.push(Change::Removed { uuid: *uuid, parent: entry.parent, text: entry.node.value.raw.clone() });
+        }
+    }
+
+    // The siblings that kept their parent, in their old and new order. The ones outside of the
+    // longest common subsequence of both orders are the ones that were reordered.
+    let stayed = |order: &[Uuid], parent: Uuid| {
+        order
+            .iter()
+            .cloned()
+            .filter(|uuid| match (old_entries.get(uuid), new_entries.get(uuid)) {
+                (Some(o), Some(n)) => o.parent == parent && n.parent == parent,
+                _ => false,
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut in_order = Vec::new();
+    let mut parents = vec![new.uuid];
+    parents.extend(new_order.iter().cloned());
+    for parent in parents {
+        let old_children = match old.find(parent) {
+            Some(n) => n.children().map(|c| c.uuid).collect::<Vec<_>>(),
+            None => continue,
+        };
+        let new_children = new_entries
+            .get(&parent)
+            .map(|e| e.node)
+            .unwrap_or(new)
+            .children()
+            .map(|c| c.uuid)
+            .collect::<Vec<_>>();
+        in_order.append(&mut lcs(&stayed(&old_children, parent), &stayed(&new_children, parent)));
+    }
+
+    for uuid in &new_order {
+        let n = &new_entries[uuid];
+        let o = match old_entries.get(uuid) {
+            Some(o) => o,
+            None => {
+                changes.push(Change::Added { uuid: *uuid, parent: n.parent, text: n.node.value.raw.clone() });
+                continue;
+            }
+        };
+
+        if o.parent != n.parent {
+            changes.push(Change::Moved { uuid: *uuid, from: o.parent, to: n.parent });
+        } else if !in_order.contains(uuid) {
+            changes.push(Change::Reordered { uuid: *uuid, parent: n.parent });
+        }
+
+        if o.node.value.raw != n.node.value.raw {
+            changes.push(Change::TextChanged {
+                uuid: *uuid,
+                old: o.node.value.raw.clone(),
+                new: n.node.value.raw.clone(),
+            });
+        }
+
+        if o.node.value.script != n.node.value.script {
+            changes.push(Change::ScriptChanged {
+                uuid: *uuid,
+                old: o.node.value.script.clone(),
+                new: n.node.value.script.clone(),
+            });
+        }
+
+        let mut names = o.node.value.attributes.iter().map(|a| a.name()).collect::<Vec<_>>();
+        for attr in &n.node.value.attributes {
+            if !names.contains(&attr.name()) {
+                names.push(attr.name());
+            }
+        }
+        for name in names {
+            let (old_attr, new_attr) = (o.node.value.get_attribute(name), n.node.value.get_attribute(name));
+            if old_attr != new_attr {
+                changes.push(Change::AttributeChanged {
+                    uuid: *uuid,
+                    name: name.into(),
+                    old: old_attr.cloned(),
+                    new: new_attr.cloned(),
+                });
+            }
+        }
+    }
+
+    changes
+}
+
+fn attribute_text(attr: &Option<Attribute>) -> String {
+    match *attr {
+        Some(ref attr) => {
+            let exported = attr.export();
+            exported[attr.name().len() + 1..exported.len() - 1].into()
+        }
+        None => "(none)".into(),
+    }
+}
+
+fn script_text(script: &Option<String>) -> String {
+    match *script {
+        Some(ref script) => format!("{:?}", script),
+        None => "(none)".into(),
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Change::Added { uuid, parent, ref text } =>
+                write!(f, "+ {} added under {}: {}", uuid, parent, text),
+            Change::Removed { uuid, parent, ref text } =>
+                write!(f, "- {} removed from {}: {}", uuid, parent, text),
+            Change::Moved { uuid, from, to } =>
+                write!(f, "> {} moved from {} to {}", uuid, from, to),
+            Change::Reordered { uuid, parent } =>
+                write!(f, "> {} reordered under {}", uuid, parent),
+            Change::TextChanged { uuid, ref old, ref new } =>
+                write!(f, "~ {} text: {:?} -> {:?}", uuid, old, new),
+            Change::ScriptChanged { uuid, ref old, ref new } =>
+                write!(f, "~ {} script: {} -> {}", uuid, script_text(old), script_text(new)),
+            Change::AttributeChanged { uuid, ref name, ref old, ref new } =>
+                write!(f, "~ {} attribute {}: {} -> {}", uuid, name, attribute_text(old), attribute_text(new)),
+        }
+    }
+}
+
+impl Change {
+    pub fn to_json(&self) -> Json {
+        let uuid = |uuid: Uuid| Json::string(uuid.to_string());
+        let attribute = |attr: &Option<Attribute>| match *attr {
+            Some(ref attr) => Json::attribute_value(attr),
+            None => Json::Null,
+        };
+        match *self {
+            Change::Added { uuid: u, parent, ref text } => Json::object(vec![
+                ("change", Json::string("added")),
+                ("uuid", uuid(u)),
+                ("parent", uuid(parent)),
+                ("text", Json::string(text.clone())),
+            ]),
+            Change::Removed { uuid: u, parent, ref text } => Json::object(vec![
+                ("change", Json::string("removed")),
+                ("uuid", uuid(u)),
+                ("parent", uuid(parent)),
+                ("text", Json::string(text.clone())),
+            ]),
+            Change::Moved { uuid: u, from, to } => Json::object(vec![
+                ("change", Json::string("moved")),
+                ("uuid", uuid(u)),
+                ("from", uuid(from)),
+                ("to", uuid(to)),
+            ]),
+            Change::Reordered { uuid: u, parent } => Json::object(vec![
+                ("change", Json::string("reordered")),
+                ("uuid", uuid(u)),
+                ("parent", uuid(parent)),
+            ]),
+            Change::TextChanged { uuid: u, ref old, ref new } => Json::object(vec![
+                ("change", Json::string("text-changed")),
+                ("uuid", uuid(u)),
+                ("old", Json::string(old.clone())),
+                ("new", Json::string(new.clone())),
+            ]),
+            Change::ScriptChanged { uuid: u, ref old, ref new } => Json::object(vec![
+                ("change", Json::string("script-changed")),
+                ("uuid", uuid(u)),
+                ("old", old.clone().map(Json::String).unwrap_or(Json::Null)),
+                ("new", new.clone().map(Json::String).unwrap_or(Json::Null)),
+            ]),
+            Change::AttributeChanged { uuid: u, ref name, ref old, ref new } => Json::object(vec![
+                ("change", Json::string("attribute-changed")),
+                ("uuid", uuid(u)),
+                ("name", Json::string(name.clone())),
+                ("old", attribute(old)),
+                ("new", attribute(new)),
+            ]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uuid(n: u8) -> Uuid {
+        Uuid::parse_str(&format!("00000000-0000-0000-0000-0000000000{:02}", n)).unwrap()
+    }
+
+    #[test]
+    fn diff_outlines() {
+        let old = TreeNode::import_from_sofer(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  One
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000000  Two
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000000 done=F; Three
+00000000-0000-0000-0000-000000000004 00000000-0000-0000-0000-000000000001  Four
+00000000-0000-0000-0000-000000000005 00000000-0000-0000-0000-000000000001  Five
+"#).unwrap();
+        let new = TreeNode::import_from_sofer(
+r#"00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000000  Two
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000000 done=T;tag="x"; Three
+00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  One, edited
+00000000-0000-0000-0000-000000000005 00000000-0000-0000-0000-000000000002  Five
+00000000-0000-0000-0000-000000000006 00000000-0000-0000-0000-000000000003  Six
+"#).unwrap();
+        let changes = diff(&old, &new);
+        assert_eq!(
+            changes,
+            vec![
+                Change::Removed { uuid: uuid(4), parent: uuid(1), text: "Four".into() },
+                Change::Moved { uuid: uuid(5), from: uuid(1), to: uuid(2) },
+                Change::AttributeChanged {
+                    uuid: uuid(3),
+                    name: "done".into(),
+                    old: Some(Attribute::Boolean("done".into(), false)),
+                    new: Some(Attribute::Boolean("done".into(), true)),
+                },
+                Change::AttributeChanged {
+                    uuid: uuid(3),
+                    name: "tag".into(),
+                    old: None,
+                    new: Some(Attribute::String("tag".into(), "x".into())),
+                },
+                Change::Added { uuid: uuid(6), parent: uuid(3), text: "Six".into() },
+                Change::Reordered { uuid: uuid(1), parent: Uuid::nil() },
+                Change::TextChanged { uuid: uuid(1), old: "One".into(), new: "One, edited".into() },
+            ]
+        );
+        assert_eq!(
+            changes[2].to_string(),
+            "~ 00000000-0000-0000-0000-000000000003 attribute done: F -> T"
+        );
+        assert_eq!(
+            changes[3].to_json().to_string(),
+            r#"{"change":"attribute-changed","uuid":"00000000-0000-0000-0000-000000000003","name":"tag","old":null,"new":"x"}"#
+        );
+        assert!(diff(&new, &new).is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+use std::cmp::Ordering;
+use std::fmt;
+use rlua::{Lua, LuaError, LuaValue};
+use uuid::Uuid;
+
+use node::TreeNode;
+
+/* A node's script is run as an expression if it is one, or else as a chunk, with globals of its
+ * own on top of the shared ones, so that the scripts run in the same `Lua` don't see each other's.
+ * The value it gives, or what it returns when it's a function called with the node, is a `Value`,
+ * rendered after the text of the node. Errors are `EvalError`s, with the line of the script where
+ * they happened and the Lua traceback, and `TreeNode::eval_all` gathers them as `Diagnostic`s.
+ */
+
+/// The name of the chunk of a script in Lua's messages, like `script:2: attempt to call a nil value`.
+const CHUNK_NAME: &str = "script";
+/// Gives a chunk its own globals, falling back to the shared ones. It goes on the first line of the
+/// chunk so that the lines in messages are the ones of the script.
+const ENVIRONMENT: &str = "local _ENV = setmetatable({}, {__index = _ENV}); ";
+/// How deep tables inside tables are rendered.
+const MAX_DEPTH: usize = 4;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(String),
+    /// The entries of a table, the ones of its sequence first, in order.
+    Table(Vec<(Value, Value)>),
+    /// A value that has no text of its own, like a function, by its type name.
+    Other(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Nil => Ok(()),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Number(x) => write!(f, "{}", x),
+            Value::String(ref s) => write!(f, "{}", s),
+            Value::Table(ref entries) => {
+                let items = entries.iter()
+                    .enumerate()
+                    .map(|(i, (key, value))| {
+                        let value = match *value {
+                            Value::Table(_) => format!("{{{}}}", value),
+                            _ => value.to_string(),
+                        };
+                        if *key == Value::Integer(i as i64 + 1) {
+                            value
+                        } else {
+                            format!("{}={}", key, value)
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                write!(f, "{}", items.join(", "))
+            }
+            Value::Other(ref type_name) => write!(f, "<{}>", type_name),
+        }
+    }
+}
+
+impl Value {
+    fn from_lua(value: LuaValue, depth: usize) -> Value {
+        match value {
+            LuaValue::Nil => Value::Nil,
+            LuaValue::Boolean(b) => Value::Boolean(b),
+            LuaValue::Integer(i) => Value::Integer(i),
+            LuaValue::Number(x) => Value::Number(x),
+            LuaValue::String(s) => Value::String(s.to_str().unwrap_or("<invalid UTF-8>").into()),
+            LuaValue::Table(_) if depth >= MAX_DEPTH => Value::Other("table".into()),
+            LuaValue::Table(table) => {
+                let mut entries = table.pairs::<LuaValue, LuaValue>()
+                    .filter_map(Result::ok)
+                    .map(|(key, value)| (Value::from_lua(key, depth + 1), Value::from_lua(value, depth + 1)))
+                    .collect::<Vec<_>>();
+                entries.sort_by(|a, b| compare_keys(&a.0, &b.0));
+                Value::Table(entries)
+            }
+            LuaValue::Function(_) => Value::Other("function".into()),
+            LuaValue::Thread(_) => Value::Other("thread".into()),
+            LuaValue::LightUserData(_) | LuaValue::UserData(_) => Value::Other("userdata".into()),
+            LuaValue::Error(err) => Value::Other(format!("error: {}", err)),
+        }
+    }
+}
+
+/// Integer keys first, by number, and then the others by their text.
+fn compare_keys(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (&Value::Integer(a), &Value::Integer(b)) => a.cmp(&b),
+        (&Value::Integer(_), _) => Ordering::Less,
+        (_, &Value::Integer(_)) => Ordering::Greater,
+        _ => a.to_string().cmp(&b.to_string()),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvalError {
+    pub message: String,
+    /// The line of the script where the error happened, from 1.
+    pub line: Option<usize>,
+    pub traceback: Option<String>,
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl EvalError {
+    fn from_lua(err: &LuaError) -> EvalError {
+        let (message, traceback) = match *err {
+            LuaError::SyntaxError(ref message) |
+            LuaError::IncompleteStatement(ref message) |
+            LuaError::RuntimeError(ref message) |
+            LuaError::ErrorError(ref message) => match message.find("\nstack traceback:") {
+                Some(i) => (message[..i].to_string(), Some(message[i + 1..].to_string())),
+                None => (message.clone(), None),
+            },
+            LuaError::CallbackError(ref traceback, ref cause) =>
+                (EvalError::from_lua(cause).message, Some(traceback.clone())),
+            ref err => (err.to_string(), None),
+        };
+        let line = script_line(&message).or_else(|| traceback.as_ref().and_then(|t| script_line(t)));
+        let prefix = line.map(|line| format!("{}:{}: ", CHUNK_NAME, line));
+        let message = match prefix {
+            Some(ref prefix) if message.starts_with(prefix.as_str()) => message[prefix.len()..].to_string(),
+            _ => message,
+        };
+        EvalError { message, line, traceback }
+    }
+}
+
+/// The line of the first `script:LINE:` in `text`.
+fn script_line(text: &str) -> Option<usize> {
+    let chunk = format!("{}:", CHUNK_NAME);
+    text.match_indices(chunk.as_str())
+        .filter_map(|(i, _)| {
+            let rest = &text[i + chunk.len()..];
+            let end = rest.find(':')?;
+            rest[..end].parse().ok()
+        })
+        .next()
+}
+
+/// The value of a script, or why it couldn't be computed.
+pub type EvalResult = Result<Value, EvalError>;
+
+/// Runs the script `code` of `node` in `lua`.
+pub fn run(lua: &Lua, code: &str, node: &TreeNode) -> EvalResult {
+    let name = format!("={}", CHUNK_NAME);
+    let chunk = match lua.load(&format!("{}return {}", ENVIRONMENT, code), Some(&name)) {
+        Err(LuaError::SyntaxError(_)) => lua.load(&format!("{}{}", ENVIRONMENT, code), Some(&name)),
+        chunk => chunk,
+    };
+    let result = chunk.and_then(|chunk| chunk.call::<_, LuaValue>(()))
+        .and_then(|value| match value {
+            LuaValue::Function(f) => f.call::<_, LuaValue>(node.to_script_lua(lua)?),
+            value => Ok(value),
+        });
+    match result {
+        Ok(value) => Ok(Value::from_lua(value, 0)),
+        Err(err) => Err(EvalError::from_lua(&err)),
+    }
+}
+
+/// An error in the script of a node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub uuid: Uuid,
+    pub error: EvalError,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.uuid, self.error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node::Node;
+    use tree::Tree;
+
+    fn run_script(code: &str) -> EvalResult {
+        let node = Tree::new_tree(Node::new("Total: ".into(), vec![]));
+        run(&Lua::new(), code, &node)
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(run_script("true"), Ok(Value::Boolean(true)));
+        assert_eq!(run_script("1 + 2").map(|v| v.to_string()), Ok("3".into()));
+        assert_eq!(run_script("1 / 2").map(|v| v.to_string()), Ok("0.5".into()));
+        assert_eq!(run_script("function(n) return n.value.raw end").map(|v| v.to_string()), Ok("Total: ".into()));
+        assert_eq!(run_script("local x = 2\nreturn x * 2").map(|v| v.to_string()), Ok("4".into()));
+        assert_eq!(
+            run_script("{'a', 'b', {1, 2}, x = 3}").map(|v| v.to_string()),
+            Ok("a, b, {1, 2}, x=3".into())
+        );
+        assert_eq!(run_script("nil").map(|v| v.to_string()), Ok("".into()));
+        assert_eq!(run_script("function(n) return print end").map(|v| v.to_string()), Ok("<function>".into()));
+    }
+
+    #[test]
+    fn errors() {
+        let err = run_script("function(n)\n  return n.missing.field\nend").unwrap_err();
+        assert_eq!(err.line, Some(2));
+        assert!(err.message.contains("attempt to index"), "{}", err.message);
+        assert!(err.traceback.unwrap().starts_with("stack traceback:"));
+
+        let err = run_script("local x = \n\n)").unwrap_err();
+        assert_eq!(err.line, Some(3));
+        assert_eq!(err.to_string(), format!("line 3: {}", err.message));
+
+        let err = run_script("error('no data')").unwrap_err();
+        assert_eq!((err.line, err.message.as_str()), (Some(1), "no data"));
+    }
+
+    #[test]
+    fn separate_globals() {
+        let lua = Lua::new();
+        let node = Tree::new_tree(Node::new("".into(), vec![]));
+        assert_eq!(run(&lua, "total = 3\nreturn total", &node), Ok(Value::Integer(3)));
+        assert_eq!(run(&lua, "total", &node), Ok(Value::Nil));
+        assert_eq!(run(&lua, "string.upper('a')", &node), Ok(Value::String("A".into())));
+    }
+}
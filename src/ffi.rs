@@ -0,0 +1,397 @@
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use uuid::Uuid;
+
+use node::{Node, TreeNode};
+use oplog::Operation;
+use sqlite::Database;
+use tree::Tree;
+use {export, import, Format};
+
+/* C interface to the core, for native front-ends. The header is include/sofer.h, generated from
+ * this file with `cbindgen --config cbindgen.toml --output include/sofer.h`.
+ *
+ * Ownership: strings passed in are borrowed and must be valid UTF-8. Strings returned are owned
+ * by the caller and must be freed with `sofer_string_free`; trees and children iterators, with
+ * `sofer_tree_free` and `sofer_children_free`.
+ *
+ * Errors: functions returning a pointer return NULL on error, and functions returning an int
+ * return -1. `sofer_last_error` then describes the error.
+ */
+
+/// An outline. The root node has the nil UUID, "00000000-0000-0000-0000-000000000000".
+pub struct SoferTree {
+    tree: TreeNode,
+}
+
+/// The UUIDs of the children of a node, as they were when the iterator was made.
+pub struct SoferChildren {
+    uuids: Vec<Uuid>,
+    next: usize,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Runs `f`, turning its errors and panics into the last error.
+fn catch<T, F>(error_value: T, f: F) -> T
+    where F: FnOnce() -> Result<T, String> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(message)) => {
+            set_last_error(message);
+            error_value
+        }
+        Err(payload) => {
+            let message = match payload.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => match payload.downcast_ref::<&str>() {
+                    Some(message) => message.to_string(),
+                    None => "Unknown error".into(),
+                },
+            };
+            set_last_error(message);
+            error_value
+        }
+    }
+}
+
+unsafe fn read_str<'a>(str: *const c_char, what: &str) -> Result<&'a str, String> {
+    if str.is_null() {
+        return Err(format!("The {} is NULL", what));
+    }
+    CStr::from_ptr(str).to_str().map_err(|_| format!("The {} isn't valid UTF-8", what))
+}
+
+unsafe fn read_uuid(str: *const c_char, what: &str) -> Result<Uuid, String> {
+    let str = read_str(str, what)?;
+    Uuid::parse_str(str).map_err(|_| format!("Couldn't read UUID \"{}\"", str))
+}
+
+unsafe fn read_optional_uuid(str: *const c_char, what: &str) -> Result<Option<Uuid>, String> {
+    if str.is_null() {
+        Ok(None)
+    } else {
+        read_uuid(str, what).map(Some)
+    }
+}
+
+unsafe fn read_tree<'a>(tree: *const SoferTree) -> Result<&'a TreeNode, String> {
+    tree.as_ref().map(|t| &t.tree).ok_or("The tree is NULL".into())
+}
+
+unsafe fn read_tree_mut<'a>(tree: *mut SoferTree) -> Result<&'a mut TreeNode, String> {
+    tree.as_mut().map(|t| &mut t.tree).ok_or("The tree is NULL".into())
+}
+
+fn new_string(str: String) -> Result<*mut c_char, String> {
+    CString::new(str)
+        .map(CString::into_raw)
+        .map_err(|_| "The text has a NUL character".into())
+}
+
+fn new_tree(tree: TreeNode) -> *mut SoferTree {
+    Box::into_raw(Box::new(SoferTree { tree }))
+}
+
+fn apply(tree: &mut TreeNode, operation: Option<Operation>, uuid: Uuid) -> Result<c_int, String> {
+    let operation = operation.ok_or(format!("Couldn't find node with UUID \"{}\"", uuid))?;
+    if operation.apply(tree) {
+        Ok(0)
+    } else {
+        Err(format!("Couldn't apply the change to node \"{}\"", uuid))
+    }
+}
+
+/// The message of the last error in this thread, or NULL if there wasn't any. It's owned by
+/// the library and valid until the next call that fails.
+#[no_mangle]
+pub extern "C" fn sofer_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match *last.borrow() {
+        Some(ref message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// # Safety
+///
+/// `str` must be NULL or a string returned by this library that wasn't freed yet. It can't be
+/// used after this call.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_string_free(str: *mut c_char) {
+    if !str.is_null() {
+        drop(CString::from_raw(str));
+    }
+}
+
+/// A new outline with only the root.
+#[no_mangle]
+pub extern "C" fn sofer_tree_new() -> *mut SoferTree {
+    new_tree(Tree::new_tree(Node::new(String::new(), Vec::new())))
+}
+
+/// # Safety
+///
+/// `tree` must be NULL or a tree returned by this library that wasn't freed yet. It can't be used
+/// after this call.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_tree_free(tree: *mut SoferTree) {
+    if !tree.is_null() {
+        drop(Box::from_raw(tree));
+    }
+}
+
+/// Reads an outline from `text` in `format`: "sofer", "lua" or "opml".
+///
+/// # Safety
+///
+/// `text` and `format` must be NULL or point to NUL-terminated strings, valid for the length of
+/// the call.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_tree_load(text: *const c_char, format: *const c_char) -> *mut SoferTree {
+    catch(ptr::null_mut(), || {
+        let format: Format = read_str(format, "format")?.parse()?;
+        import(read_str(text, "text")?, format).map(new_tree)
+    })
+}
+
+/// Writes the outline in `format`: "sofer", "lua" or "pretty". With `evaled` not 0, the
+/// formats with one text per node use the evaluated text.
+///
+/// # Safety
+///
+/// `tree` must be NULL or a live tree from this library, and `format` NULL or a NUL-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_tree_save(tree: *const SoferTree, format: *const c_char, evaled: c_int) -> *mut c_char {
+    catch(ptr::null_mut(), || {
+        let format: Format = read_str(format, "format")?.parse()?;
+        new_string(export(read_tree(tree)?, format, evaled != 0)?)
+    })
+}
+
+/// Reads an outline from the SQLite database at `path`.
+///
+/// # Safety
+///
+/// `path` must be NULL or point to a NUL-terminated string, valid for the length of the call.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_tree_load_sqlite(path: *const c_char) -> *mut SoferTree {
+    catch(ptr::null_mut(), || {
+        let database = Database::open(Path::new(read_str(path, "path")?))?;
+        database.load().map(new_tree)
+    })
+}
+
+/// Writes the outline to the SQLite database at `path`, replacing what it had.
+///
+/// # Safety
+///
+/// `tree` must be NULL or a live tree from this library, and `path` NULL or a NUL-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_tree_save_sqlite(tree: *const SoferTree, path: *const c_char) -> c_int {
+    catch(-1, || {
+        let tree = read_tree(tree)?;
+        let mut database = Database::open(Path::new(read_str(path, "path")?))?;
+        database.save(tree).map(|_| 0)
+    })
+}
+
+/// 1 if the outline has a node with `uuid`, 0 if it doesn't.
+///
+/// # Safety
+///
+/// `tree` must be NULL or a live tree from this library, and `uuid` NULL or a NUL-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_tree_contains(tree: *const SoferTree, uuid: *const c_char) -> c_int {
+    catch(-1, || {
+        let uuid = read_uuid(uuid, "UUID")?;
+        Ok(read_tree(tree)?.find(uuid).is_some() as c_int)
+    })
+}
+
+/// The text of a node, without evaluating it.
+///
+/// # Safety
+///
+/// `tree` must be NULL or a live tree from this library, and `uuid` NULL or a NUL-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_tree_text(tree: *const SoferTree, uuid: *const c_char) -> *mut c_char {
+    catch(ptr::null_mut(), || {
+        let uuid = read_uuid(uuid, "UUID")?;
+        let n = read_tree(tree)?.find(uuid).ok_or(format!("Couldn't find node with UUID \"{}\"", uuid))?;
+        new_string(n.value.raw.clone())
+    })
+}
+
+/// The text of a node, evaluated. If its script fails, the error is the last error.
+///
+/// # Safety
+///
+/// `tree` must be NULL or a live tree from this library, and `uuid` NULL or a NUL-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_tree_eval(tree: *const SoferTree, uuid: *const c_char) -> *mut c_char {
+    catch(ptr::null_mut(), || {
+        let uuid = read_uuid(uuid, "UUID")?;
+        let tree = read_tree(tree)?;
+        let n = tree.find(uuid).ok_or(format!("Couldn't find node with UUID \"{}\"", uuid))?;
+        new_string(n.eval(tree).map_err(|err| err.to_string())?)
+    })
+}
+
+/// Inserts a node with `text` under `parent`, after the child `after`, or as the first child if
+/// `after` is NULL. Returns the UUID of the new node.
+///
+/// # Safety
+///
+/// `tree` must be NULL or a live tree from this library, not used by another thread during the
+/// call. The strings must be NULL or NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_tree_insert(
+    tree: *mut SoferTree,
+    parent: *const c_char,
+    after: *const c_char,
+    text: *const c_char,
+) -> *mut c_char {
+    catch(ptr::null_mut(), || {
+        let (parent, after) = (read_uuid(parent, "parent")?, read_optional_uuid(after, "sibling")?);
+        let n = Tree::new_child(Node::new(read_str(text, "text")?.into(), Vec::new()));
+        let uuid = n.uuid;
+        apply(read_tree_mut(tree)?, Some(Operation::Insert { parent, after, node: n }), parent)?;
+        new_string(uuid.to_string())
+    })
+}
+
+/// Moves a node under `parent`, after the child `after`, or as the first child if `after` is
+/// NULL.
+///
+/// # Safety
+///
+/// `tree` must be NULL or a live tree from this library, not used by another thread during the
+/// call. The strings must be NULL or NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_tree_move(
+    tree: *mut SoferTree,
+    uuid: *const c_char,
+    parent: *const c_char,
+    after: *const c_char,
+) -> c_int {
+    catch(-1, || {
+        let uuid = read_uuid(uuid, "UUID")?;
+        let (parent, after) = (read_uuid(parent, "parent")?, read_optional_uuid(after, "sibling")?);
+        let tree = read_tree_mut(tree)?;
+        let operation = Operation::move_to(tree, uuid, parent, after);
+        apply(tree, operation, uuid)
+    })
+}
+
+/// Deletes a node and its subtree.
+///
+/// # Safety
+///
+/// `tree` must be NULL or a live tree from this library, not used by another thread during the
+/// call, and `uuid` NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_tree_delete(tree: *mut SoferTree, uuid: *const c_char) -> c_int {
+    catch(-1, || {
+        let uuid = read_uuid(uuid, "UUID")?;
+        let tree = read_tree_mut(tree)?;
+        let operation = Operation::delete(tree, uuid);
+        apply(tree, operation, uuid)
+    })
+}
+
+/// Replaces the text of a node.
+///
+/// # Safety
+///
+/// `tree` must be NULL or a live tree from this library, not used by another thread during the
+/// call. The strings must be NULL or NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_tree_set_text(tree: *mut SoferTree, uuid: *const c_char, text: *const c_char) -> c_int {
+    catch(-1, || {
+        let uuid = read_uuid(uuid, "UUID")?;
+        let text = read_str(text, "text")?;
+        let tree = read_tree_mut(tree)?;
+        let operation = Operation::set_raw(tree, uuid, text.into());
+        apply(tree, operation, uuid)
+    })
+}
+
+/// Replaces the script of a node, or removes it if `script` is NULL.
+///
+/// # Safety
+///
+/// `tree` must be NULL or a live tree from this library, not used by another thread during the
+/// call. The strings must be NULL or NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_tree_set_script(tree: *mut SoferTree, uuid: *const c_char, script: *const c_char) -> c_int {
+    catch(-1, || {
+        let uuid = read_uuid(uuid, "UUID")?;
+        let script = if script.is_null() { None } else { Some(read_str(script, "script")?.into()) };
+        let tree = read_tree_mut(tree)?;
+        let operation = Operation::set_script(tree, uuid, script);
+        apply(tree, operation, uuid)
+    })
+}
+
+/// An iterator over the UUIDs of the children of a node.
+///
+/// # Safety
+///
+/// `tree` must be NULL or a live tree from this library, and `uuid` NULL or a NUL-terminated
+/// string. The iterator doesn't borrow the tree, so it can outlive it.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_tree_children(tree: *const SoferTree, uuid: *const c_char) -> *mut SoferChildren {
+    catch(ptr::null_mut(), || {
+        let uuid = read_uuid(uuid, "UUID")?;
+        let n = read_tree(tree)?.find(uuid).ok_or(format!("Couldn't find node with UUID \"{}\"", uuid))?;
+        let uuids = n.children().map(|child| child.uuid).collect();
+        Ok(Box::into_raw(Box::new(SoferChildren { uuids, next: 0 })))
+    })
+}
+
+/// The UUID of the next child, or NULL when there are no more.
+///
+/// # Safety
+///
+/// `children` must be NULL or a live iterator from `sofer_tree_children`, not used by another
+/// thread during the call.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_children_next(children: *mut SoferChildren) -> *mut c_char {
+    catch(ptr::null_mut(), || {
+        let children = children.as_mut().ok_or("The iterator is NULL")?;
+        match children.uuids.get(children.next) {
+            Some(uuid) => {
+                children.next += 1;
+                new_string(uuid.to_string())
+            }
+            None => Ok(ptr::null_mut()),
+        }
+    })
+}
+
+/// # Safety
+///
+/// `children` must be NULL or an iterator from `sofer_tree_children` that wasn't freed yet. It
+/// can't be used after this call.
+#[no_mangle]
+pub unsafe extern "C" fn sofer_children_free(children: *mut SoferChildren) {
+    if !children.is_null() {
+        drop(Box::from_raw(children));
+    }
+}
@@ -0,0 +1,261 @@
+use std::fmt;
+use uuid::Uuid;
+
+use date::Date;
+use node::{Attribute, TreeNode};
+use oplog::Operation;
+
+/* Recurring items are tasks with a `repeat` attribute:
+ *  - `daily`, `weekly`, `monthly` or `yearly`,
+ *  - a number of days, weeks, months or years, like `3d`, `2w`, `1m` or `1y`,
+ *  - or the days of the week it's on, like `mon,wed,fri`.
+ * Closing one doesn't close it: its `scheduled` and `due` dates move to their next occurrence
+ * after the day it was done (or it gets a `scheduled` date if it had none), it's reopened, and
+ * the day is added to its `completions`, a list of dates like `2026-10-16,2026-10-18`. Habits
+ * are recurring items whose statistics are computed from that list.
+ */
+
+const REPEAT: &str = "repeat";
+const COMPLETIONS: &str = "completions";
+const DATES: [&str; 2] = ["scheduled", "due"];
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Repeat {
+    Days(u32),
+    Months(u32),
+    /// The days of the week, 0 = Monday, ..., 6 = Sunday.
+    Weekdays(Vec<u32>),
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    let (year, month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+    Date { year, month, day: 1 }.add_days(-1).day
+}
+
+impl Repeat {
+    pub fn parse(str: &str) -> Option<Repeat> {
+        match str {
+            "daily" => return Some(Repeat::Days(1)),
+            "weekly" => return Some(Repeat::Days(7)),
+            "monthly" => return Some(Repeat::Months(1)),
+            "yearly" => return Some(Repeat::Months(12)),
+            _ => (),
+        }
+        if str.len() >= 2 && str.is_char_boundary(str.len() - 1) {
+            let (n, unit) = str.split_at(str.len() - 1);
+            if let Ok(n) = n.parse::<u32>() {
+                return match unit {
+                    _ if n == 0 => None,
+                    "d" => Some(Repeat::Days(n)),
+                    "w" => n.checked_mul(7).map(Repeat::Days),
+                    "m" => Some(Repeat::Months(n)),
+                    "y" => n.checked_mul(12).map(Repeat::Months),
+                    _ => None,
+                };
+            }
+        }
+        let mut weekdays = str.split(',')
+            .map(|day| WEEKDAYS.iter().position(|&d| d == day.trim().to_lowercase()).map(|d| d as u32))
+            .collect::<Option<Vec<_>>>()?;
+        weekdays.sort();
+        weekdays.dedup();
+        Some(Repeat::Weekdays(weekdays))
+    }
+
+    /// The first occurrence strictly after `date`.
+    pub fn next(&self, date: Date) -> Date {
+        match *self {
+            Repeat::Days(n) => date.add_days(n as i64),
+            Repeat::Months(n) => {
+                let months = date.year as i64 * 12 + date.month as i64 - 1 + n as i64;
+                let (year, month) = (months.div_euclid(12) as i32, months.rem_euclid(12) as u32 + 1);
+                Date { year, month, day: date.day.min(days_in_month(year, month)) }
+            }
+            Repeat::Weekdays(ref weekdays) => {
+                let mut next = date.add_days(1);
+                while !weekdays.contains(&next.weekday()) {
+                    next = next.add_days(1);
+                }
+                next
+            }
+        }
+    }
+
+    /// The first occurrence after `today`, counting from `date`.
+    pub fn next_after(&self, date: Date, today: Date) -> Date {
+        let mut next = self.next(date);
+        while next <= today {
+            next = self.next(next);
+        }
+        next
+    }
+}
+
+pub fn repeat(n: &TreeNode) -> Option<Repeat> {
+    match n.value.get_attribute(REPEAT) {
+        Some(Attribute::String(_, rule)) => Repeat::parse(rule),
+        _ => None,
+    }
+}
+
+/// The days the node was done on, in order.
+pub fn completions(n: &TreeNode) -> Vec<Date> {
+    let mut dates = match n.value.get_attribute(COMPLETIONS) {
+        Some(Attribute::String(_, dates)) => dates.split(',').filter_map(Date::parse).collect(),
+        _ => Vec::new(),
+    };
+    dates.sort();
+    dates
+}
+
+/// The changes that record the recurring item `uuid` as done on the day of `now` (in seconds
+/// since the Unix epoch) and reopen it with `state`, or `None` if it isn't a recurring item.
+pub fn complete(tree: &TreeNode, uuid: Uuid, state: &str, now: i64) -> Option<Vec<Operation>> {
+    let n = tree.find(uuid)?;
+    let repeat = repeat(n)?;
+    let today = Date::from_days(now.div_euclid(86400));
+
+    let status = Attribute::String("status".into(), state.into());
+    let mut operations = vec![Operation::set_attr(tree, uuid, "status", Some(status))?];
+    let mut has_date = false;
+    for &name in &DATES {
+        if let Some(Attribute::String(_, value)) = n.value.get_attribute(name) {
+            if let Some(date) = Date::parse(value) {
+                // A time after the date stays the same.
+                let next = format!("{}{}", repeat.next_after(date, today), &value[10..]);
+                operations.push(Operation::set_attr(tree, uuid, name, Some(Attribute::String(name.into(), next)))?);
+                has_date = true;
+            }
+        }
+    }
+    if !has_date {
+        let next = repeat.next(today).to_string();
+        operations.push(Operation::set_attr(tree, uuid, "scheduled", Some(Attribute::String("scheduled".into(), next)))?);
+    }
+
+    let mut dates = completions(n);
+    if !dates.contains(&today) {
+        dates.push(today);
+        dates.sort();
+    }
+    let dates = dates.iter().map(|d| d.to_string()).collect::<Vec<_>>().join(",");
+    operations.push(Operation::set_attr(tree, uuid, COMPLETIONS, Some(Attribute::String(COMPLETIONS.into(), dates)))?);
+    Some(operations)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Stats {
+    pub completions: usize,
+    /// Occurrences in a row done until the last one, or the one before if the last one is
+    /// still to be done.
+    pub current_streak: usize,
+    pub longest_streak: usize,
+    /// Occurrences done out of the ones since the first completion, like `current_streak`
+    /// without the last one if it's still to be done.
+    pub done: usize,
+    pub occurrences: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rate = if self.occurrences > 0 { 100.0 * self.done as f64 / self.occurrences as f64 } else { 0.0 };
+        writeln!(f, "Completions: {}", self.completions)?;
+        writeln!(f, "Current streak: {}", self.current_streak)?;
+        writeln!(f, "Longest streak: {}", self.longest_streak)?;
+        write!(f, "Completion rate: {:.0}% ({}/{})", rate, self.done, self.occurrences)
+    }
+}
+
+/// The statistics of the habit `n` until `today`. Each occurrence since the first completion is
+/// done if the habit was done between it and the next one.
+pub fn stats(n: &TreeNode, today: Date) -> Result<Stats, String> {
+    let repeat = repeat(n).ok_or("The node doesn't have a valid repeat attribute".to_string())?;
+    let dates = completions(n);
+    let mut done = Vec::new();
+    if let Some(&first) = dates.first() {
+        let mut occurrence = first;
+        while occurrence <= today {
+            let next = repeat.next(occurrence);
+            done.push(dates.iter().any(|&d| occurrence <= d && d < next));
+            occurrence = next;
+        }
+    }
+
+    let mut longest_streak = 0;
+    let mut streak = 0;
+    for &d in &done {
+        streak = if d { streak + 1 } else { 0 };
+        longest_streak = longest_streak.max(streak);
+    }
+    let pending = done.last() == Some(&false);
+    let counted = if pending { &done[..done.len() - 1] } else { &done[..] };
+    let current_streak = counted.iter().rev().take_while(|&&d| d).count();
+
+    Ok(Stats {
+        completions: dates.len(),
+        current_streak,
+        longest_streak,
+        done: counted.iter().filter(|&&d| d).count(),
+        occurrences: counted.len(),
+    })
+}
+
+/// The statistics of the habit `uuid` until the day of `now`, in seconds since the Unix epoch.
+pub fn stats_at(tree: &TreeNode, uuid: Uuid, now: i64) -> Result<Stats, String> {
+    let n = tree.find(uuid).ok_or(format!("Couldn't find node with UUID \"{}\"", uuid))?;
+    stats(n, Date::from_days(now.div_euclid(86400)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(str: &str) -> Date {
+        Date::parse(str).unwrap()
+    }
+
+    #[test]
+    fn repeat_rules() {
+        assert_eq!(Repeat::parse("weekly"), Some(Repeat::Days(7)));
+        assert_eq!(Repeat::parse("2w"), Some(Repeat::Days(14)));
+        assert_eq!(Repeat::parse("Fri,mon"), Some(Repeat::Weekdays(vec![0, 4])));
+        assert_eq!(Repeat::parse("0d"), None);
+        assert_eq!(Repeat::parse("4294967295w"), None);
+        assert_eq!(Repeat::parse("400000000y"), None);
+        assert_eq!(Repeat::parse("often"), None);
+
+        assert_eq!(Repeat::Months(1).next(date("2026-01-31")), date("2026-02-28"));
+        assert_eq!(Repeat::Months(12).next(date("2024-02-29")), date("2025-02-28"));
+        // 2026-10-18 is a Sunday.
+        assert_eq!(Repeat::parse("mon,fri").unwrap().next(date("2026-10-18")), date("2026-10-19"));
+        assert_eq!(Repeat::parse("mon,fri").unwrap().next(date("2026-10-19")), date("2026-10-23"));
+        assert_eq!(Repeat::Days(3).next_after(date("2026-10-01"), date("2026-10-18")), date("2026-10-19"));
+    }
+
+    #[test]
+    fn complete_and_stats() {
+        let mut tree = TreeNode::import_from_sofer(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 status="TODO";repeat="daily";scheduled="2026-10-15T07:00";completions="2026-10-14,2026-10-15"; Run
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000000 status="TODO"; Once
+"#).unwrap();
+        let uuid = Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap();
+        let now = date("2026-10-17").to_days() * 86400 + 3600;
+        for operation in complete(&tree, uuid, "TODO", now).unwrap() {
+            assert!(operation.apply(&mut tree));
+        }
+        let n = tree.find(uuid).unwrap();
+        assert_eq!(n.value.get_attribute("scheduled"), Some(&Attribute::String("scheduled".into(), "2026-10-18T07:00".into())));
+        assert_eq!(completions(n), vec![date("2026-10-14"), date("2026-10-15"), date("2026-10-17")]);
+        assert!(complete(&tree, Uuid::parse_str("00000000-0000-0000-0000-000000000002").unwrap(), "TODO", now).is_none());
+
+        assert_eq!(stats(n, date("2026-10-18")), Ok(Stats {
+            completions: 3,
+            current_streak: 1,
+            longest_streak: 2,
+            done: 3,
+            occurrences: 4,
+        }));
+        assert_eq!(stats(n, date("2026-10-17")).unwrap().occurrences, 4);
+    }
+}
@@ -0,0 +1,344 @@
+use uuid::Uuid;
+
+use date::{self, Date};
+use node::{Attribute, Node, TreeNode};
+use task::{self, Workflow};
+use tree;
+
+/* iCalendar. Nodes with a `scheduled` or `due` date are exported as events, or as to-dos if
+ * they're tasks, with their UUID as UID and the path of their ancestors as description. Events
+ * and to-dos are imported as top-level nodes, keeping their UID as UUID when it is one, with
+ * their DTSTART as `scheduled`, DUE as `due`, STATUS as `status` and COMPLETED as
+ * `completed_at`.
+ *
+ * Times without `Z` are floating, like the ones of the attributes, and the time zones of
+ * `TZID` times are ignored.
+ */
+
+const STATUSES: [(&str, &str); 4] = [
+    ("NEEDS-ACTION", "TODO"),
+    ("IN-PROCESS", "DOING"),
+    ("COMPLETED", "DONE"),
+    ("CANCELLED", "CANCELLED"),
+];
+
+fn string_attribute<'a>(n: &'a TreeNode, name: &str) -> Option<&'a str> {
+    match n.value.get_attribute(name) {
+        Some(Attribute::String(_, value)) => Some(value),
+        _ => None,
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut str = String::new();
+    for c in text.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                str.push('\\');
+                str.push(c);
+            }
+            '\n' => str.push_str("\\n"),
+            '\r' => (),
+            c => str.push(c),
+        }
+    }
+    str
+}
+
+fn unescape(text: &str) -> String {
+    let mut str = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') | Some('N') => str.push('\n'),
+                Some(c) => str.push(c),
+                None => (),
+            }
+        } else {
+            str.push(c);
+        }
+    }
+    str
+}
+
+/// Splits `line` into lines of at most 75 bytes, the ones after the first starting with a space.
+fn fold(line: &str) -> String {
+    let mut str = String::new();
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            str.push_str("\r\n ");
+            length = 1;
+        }
+        str.push(c);
+        length += c.len_utf8();
+    }
+    str.push_str("\r\n");
+    str
+}
+
+/// `2026-10-18` as `;VALUE=DATE:20261018` and `2026-10-18T09:00` as `:20261018T090000`.
+fn to_ics_time(value: &str) -> Option<String> {
+    let date = Date::parse(value)?;
+    let day = format!("{:04}{:02}{:02}", date.year, date.month, date.day);
+    let time = &value[10..];
+    if time.is_empty() {
+        return Some(format!(";VALUE=DATE:{}", day));
+    }
+    if !time.starts_with('T') {
+        return None;
+    }
+    let utc = time.ends_with('Z');
+    let mut parts = time[1..].trim_end_matches('Z').split(':');
+    let hours = parts.next()?.parse::<u32>().ok()?;
+    let minutes = parts.next()?.parse::<u32>().ok()?;
+    let seconds = match parts.next() {
+        Some(seconds) => seconds.parse::<u32>().ok()?,
+        None => 0,
+    };
+    Some(format!(":{}T{:02}{:02}{:02}{}", day, hours, minutes, seconds, if utc { "Z" } else { "" }))
+}
+
+/// `20261018` as `2026-10-18` and `20261018T090000` as `2026-10-18T09:00`.
+fn from_ics_time(value: &str) -> Option<String> {
+    let number = |range: ::std::ops::Range<usize>| value.get(range).and_then(|s| s.parse::<u32>().ok());
+    let date = Date { year: number(0..4)? as i32, month: number(4..6)?, day: number(6..8)? };
+    if value.len() == 8 {
+        return Some(date.to_string());
+    }
+    if value.get(8..9) != Some("T") {
+        return None;
+    }
+    let (hours, minutes, seconds) = (number(9..11)?, number(11..13)?, number(13..15)?);
+    let mut str = format!("{}T{:02}:{:02}", date, hours, minutes);
+    if seconds > 0 {
+        str.push_str(&format!(":{:02}", seconds));
+    }
+    if value[15..].starts_with('Z') {
+        str.push('Z');
+    }
+    Some(str)
+}
+
+/// The status of a to-do in the workflow of its task.
+fn to_ics_status(status: &str, workflow: &Workflow) -> &'static str {
+    if workflow.is_closed(status) {
+        if status == "CANCELLED" { "CANCELLED" } else { "COMPLETED" }
+    } else if workflow.open.first().map(|s| s == status).unwrap_or(true) {
+        "NEEDS-ACTION"
+    } else {
+        "IN-PROCESS"
+    }
+}
+
+/// Writes the nodes of `tree` with a date as a calendar, stamped with `now`, in seconds since
+/// the Unix epoch.
+pub fn export(tree: &TreeNode, now: i64) -> String {
+    fn collect(n: &TreeNode, workflow: &Workflow, path: &mut Vec<String>, stamp: &str, str: &mut String) {
+        let declared = task::declared_workflow(n);
+        let workflow = declared.as_ref().unwrap_or(workflow);
+        let text = n.value.raw.lines().next().unwrap_or("").to_string();
+        let scheduled = string_attribute(n, "scheduled").and_then(to_ics_time);
+        let due = string_attribute(n, "due").and_then(to_ics_time);
+
+        if scheduled.is_some() || due.is_some() {
+            let status = task::status(n);
+            let component = if status.is_some() { "VTODO" } else { "VEVENT" };
+            str.push_str(&format!("BEGIN:{}\r\n", component));
+            str.push_str(&fold(&format!("UID:{}", n.uuid)));
+            str.push_str(&format!("DTSTAMP:{}\r\n", stamp));
+            str.push_str(&fold(&format!("SUMMARY:{}", escape(&text))));
+            if !path.is_empty() {
+                str.push_str(&fold(&format!("DESCRIPTION:{}", escape(&path.join(" / ")))));
+            }
+            match (status, scheduled, due) {
+                (Some(status), scheduled, due) => {
+                    if let Some(scheduled) = scheduled {
+                        str.push_str(&format!("DTSTART{}\r\n", scheduled));
+                    }
+                    if let Some(due) = due {
+                        str.push_str(&format!("DUE{}\r\n", due));
+                    }
+                    str.push_str(&format!("STATUS:{}\r\n", to_ics_status(status, workflow)));
+                    if let Some(completed) = string_attribute(n, "completed_at").and_then(date::parse_timestamp) {
+                        str.push_str(&format!("COMPLETED:{}\r\n", ics_timestamp(completed)));
+                    }
+                }
+                // Events only have a start.
+                (None, scheduled, due) => {
+                    str.push_str(&format!("DTSTART{}\r\n", scheduled.or(due).unwrap()));
+                }
+            }
+            str.push_str(&format!("END:{}\r\n", component));
+        }
+
+        // The root has no text to show in the paths.
+        let is_root = n.uuid.is_nil();
+        if !is_root {
+            path.push(text);
+        }
+        for child in n.children() {
+            collect(child, workflow, path, stamp, str);
+        }
+        if !is_root {
+            path.pop();
+        }
+    }
+
+    let mut str = String::from("BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//Sofer//Sofer//EN\r\n");
+    collect(tree, &Workflow::default(), &mut Vec::new(), &ics_timestamp(now), &mut str);
+    str.push_str("END:VCALENDAR\r\n");
+    str
+}
+
+/// `now` as a UTC time like `20261018T090000Z`.
+fn ics_timestamp(now: i64) -> String {
+    to_ics_time(&date::timestamp(now)).unwrap()[1..].into()
+}
+
+/// Reads the events and to-dos of a calendar as the top-level nodes of an outline.
+pub fn import(str: &str) -> Result<TreeNode, String> {
+    // Lines starting with a space or a tab continue the previous one.
+    let mut lines: Vec<String> = Vec::new();
+    for line in str.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(last) = lines.last_mut() {
+                last.push_str(&line[1..]);
+                continue;
+            }
+        }
+        lines.push(line.into());
+    }
+
+    let mut tree = tree::Tree::new_tree(Node::new("".into(), vec![]));
+    // The properties of the event or to-do being read, and how many components deep in it we are.
+    let mut properties: Option<Vec<(String, String)>> = None;
+    let mut depth = 0;
+    for line in lines.iter().filter(|l| !l.is_empty()) {
+        let colon = line.find(':').ok_or(format!("Couldn't read calendar line \"{}\"", line))?;
+        let (name, value) = (&line[..colon], &line[colon + 1..]);
+        // Parameters like `;VALUE=DATE` or `;TZID=...` are left out.
+        let name = name.split(';').next().unwrap().to_uppercase();
+        match (name.as_str(), value) {
+            ("BEGIN", "VEVENT") | ("BEGIN", "VTODO") if properties.is_none() => properties = Some(Vec::new()),
+            ("BEGIN", _) if properties.is_some() => depth += 1,
+            ("END", _) if depth > 0 => depth -= 1,
+            ("END", "VEVENT") | ("END", "VTODO") if properties.is_some() => {
+                let properties = properties.take().unwrap();
+                let property = |name: &str| properties.iter().find(|p| p.0 == name).map(|p| p.1.as_str());
+
+                let text = property("SUMMARY").map(unescape).unwrap_or("".into()).replace('\n', " ");
+                let mut attributes = Vec::new();
+                if let Some(status) = property("STATUS") {
+                    if let Some(&(_, status)) = STATUSES.iter().find(|s| s.0 == status) {
+                        attributes.push(Attribute::String("status".into(), status.into()));
+                    }
+                }
+                for &(name, attribute) in &[("DTSTART", "scheduled"), ("DUE", "due"), ("COMPLETED", "completed_at")] {
+                    if let Some(time) = property(name).and_then(from_ics_time) {
+                        let time = if attribute == "completed_at" {
+                            date::parse_timestamp(&time).map(date::timestamp).unwrap_or(time)
+                        } else {
+                            time
+                        };
+                        attributes.push(Attribute::String(attribute.into(), time));
+                    }
+                }
+
+                let mut n = tree::Tree::new_child(Node::new(text, attributes));
+                // Events repeated with the same UID get their own UUIDs.
+                if let Some(uuid) = property("UID").and_then(|uid| Uuid::parse_str(uid).ok()) {
+                    if !uuid.is_nil() && tree.find(uuid).is_none() {
+                        n.uuid = uuid;
+                    }
+                }
+                let root = tree.uuid;
+                tree.insert(root, n);
+            }
+            (_, _) if depth == 0 => {
+                if let Some(ref mut properties) = properties {
+                    properties.push((name.clone(), value.into()));
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_and_import() {
+        let tree = TreeNode::import_from_sofer(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  Work
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000001 status="TODO";scheduled="2026-10-18T09:00";due="2026-10-20"; Report, draft
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000001 due="2026-10-16"; Review
+00000000-0000-0000-0000-000000000004 00000000-0000-0000-0000-000000000000 status="DONE";completed_at="2026-10-17T08:30:00Z";due="2026-10-17"; Old
+00000000-0000-0000-0000-000000000005 00000000-0000-0000-0000-000000000000  Undated
+"#).unwrap();
+        let ics = export(&tree, date::parse_timestamp("2026-10-18T12:00").unwrap());
+        assert_eq!(ics, "BEGIN:VCALENDAR\r
+VERSION:2.0\r
+PRODID:-//Sofer//Sofer//EN\r
+BEGIN:VTODO\r
+UID:00000000-0000-0000-0000-000000000002\r
+DTSTAMP:20261018T120000Z\r
+SUMMARY:Report\\, draft\r
+DESCRIPTION:Work\r
+DTSTART:20261018T090000\r
+DUE;VALUE=DATE:20261020\r
+STATUS:NEEDS-ACTION\r
+END:VTODO\r
+BEGIN:VEVENT\r
+UID:00000000-0000-0000-0000-000000000003\r
+DTSTAMP:20261018T120000Z\r
+SUMMARY:Review\r
+DESCRIPTION:Work\r
+DTSTART;VALUE=DATE:20261016\r
+END:VEVENT\r
+BEGIN:VTODO\r
+UID:00000000-0000-0000-0000-000000000004\r
+DTSTAMP:20261018T120000Z\r
+SUMMARY:Old\r
+DUE;VALUE=DATE:20261017\r
+STATUS:COMPLETED\r
+COMPLETED:20261017T083000Z\r
+END:VTODO\r
+END:VCALENDAR\r
+");
+
+        let imported = import(&ics).unwrap();
+        assert_eq!(imported.export_to_sofer(false), TreeNode::import_from_sofer(
+r#"00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000000 status="TODO";scheduled="2026-10-18T09:00";due="2026-10-20"; Report, draft
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000000 scheduled="2026-10-16"; Review
+00000000-0000-0000-0000-000000000004 00000000-0000-0000-0000-000000000000 status="DONE";due="2026-10-17";completed_at="2026-10-17T08:30:00Z"; Old
+"#).unwrap().export_to_sofer(false));
+    }
+
+    #[test]
+    fn import_folded_lines_and_alarms() {
+        let tree = import("BEGIN:VCALENDAR\r
+BEGIN:VEVENT\r
+UID:event@example.com\r
+SUMMARY:A long\r
+  meeting\\; with notes\r
+DTSTART;TZID=Europe/Paris:20261019T143000\r
+STATUS:CONFIRMED\r
+BEGIN:VALARM\r
+TRIGGER:-PT15M\r
+DESCRIPTION:Reminder\r
+END:VALARM\r
+END:VEVENT\r
+END:VCALENDAR\r
+").unwrap();
+        let n = tree.children().next().unwrap();
+        assert_eq!(n.value.raw, "A long meeting; with notes");
+        assert_eq!(n.value.attributes, vec![Attribute::String("scheduled".into(), "2026-10-19T14:30".into())]);
+        assert_eq!(fold(&"a".repeat(80)), format!("{}\r\n {}\r\n", "a".repeat(75), "a".repeat(5)));
+    }
+}
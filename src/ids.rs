@@ -0,0 +1,145 @@
+use uuid::Uuid;
+
+use node::TreeNode;
+
+/* Human-friendly names for nodes, accepted wherever a UUID is. A name can be:
+ * - a full UUID, or a prefix of one that only one node has, like `3f2a`,
+ * - a path of indexes from the root, counting from 1, like `1.3.2`,
+ * - a path of texts from the root, matching the first line of each node, like `/Projects/Sofer`,
+ * - `.` or `..`.
+ * Names made only of digits and dots are index paths, and only prefixes if there's no such index.
+ */
+
+/// Minimum length of the UUID prefixes `short_id` gives.
+const SHORT_ID_LENGTH: usize = 4;
+
+/// The shortest prefix of `uuid` that no other UUID in `uuids` starts with.
+pub fn short_id(uuids: &[String], uuid: Uuid) -> String {
+    let uuid = uuid.to_string();
+    let mut length = SHORT_ID_LENGTH;
+    for other in uuids {
+        if *other == uuid {
+            continue;
+        }
+        let common = other.chars().zip(uuid.chars()).take_while(|&(a, b)| a == b).count();
+        length = length.max(common + 1);
+    }
+    uuid[..length.min(uuid.len())].into()
+}
+
+/// The UUIDs of all the nodes of `tree`, to give to `short_id`.
+pub fn all_uuids(tree: &TreeNode) -> Vec<String> {
+    tree.nodes().into_iter().map(|n| n.uuid.to_string()).collect()
+}
+
+fn first_line(n: &TreeNode) -> &str {
+    n.value.raw.lines().next().unwrap_or("").trim()
+}
+
+fn ambiguous<'a, I>(name: &str, candidates: I) -> String
+    where I: Iterator<Item = &'a TreeNode> {
+    let mut message = format!("\"{}\" is ambiguous, it could be:", name);
+    for n in candidates {
+        message.push_str(&format!("\n    {}  {}", n.uuid, first_line(n)));
+    }
+    message
+}
+
+fn index_path(tree: &TreeNode, base: Uuid, path: &str) -> Option<Uuid> {
+    let mut n = tree.find(base)?;
+    for index in path.split('.') {
+        let index: usize = index.parse().ok()?;
+        n = n.children().nth(index.wrapping_sub(1))?;
+    }
+    Some(n.uuid)
+}
+
+/// The node named `name` in `tree`.
+pub fn resolve(tree: &TreeNode, name: &str) -> Result<Uuid, String> {
+    resolve_from(tree, tree.uuid, name)
+}
+
+/// The node named `name` in `tree`, with index paths, `.` and `..` starting at `base`.
+pub fn resolve_from(tree: &TreeNode, base: Uuid, name: &str) -> Result<Uuid, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("A node is needed".into());
+    }
+    if name == "." {
+        return Ok(base);
+    }
+    if name == ".." {
+        return Ok(tree.find_parent(base).map(|p| p.uuid).unwrap_or(tree.uuid));
+    }
+
+    if name.starts_with('/') {
+        let mut n = tree;
+        for text in name.split('/').filter(|t| !t.is_empty()) {
+            let matches = n.children().filter(|child| first_line(child) == text).collect::<Vec<_>>();
+            n = match matches.len() {
+                0 => return Err(format!("No node \"{}\" in \"{}\"", text, name)),
+                1 => matches[0],
+                _ => return Err(ambiguous(text, matches.into_iter())),
+            };
+        }
+        return Ok(n.uuid);
+    }
+
+    if name.starts_with(|c: char| c.is_ascii_digit()) && name.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        match index_path(tree, base, name) {
+            Some(uuid) => return Ok(uuid),
+            // Without dots, it can still be a prefix.
+            None if !name.contains('.') => (),
+            None => return Err(format!("No node at \"{}\"", name)),
+        }
+    }
+
+    let prefix = name.to_lowercase();
+    let matches = tree.nodes()
+        .into_iter()
+        .filter(|n| n.uuid.to_string().starts_with(&prefix))
+        .collect::<Vec<_>>();
+    match matches.len() {
+        0 => Err(format!("No node matches \"{}\"", name)),
+        1 => Ok(matches[0].uuid),
+        _ => Err(ambiguous(name, matches.into_iter())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_names() {
+        let tree = TreeNode::import_from_sofer(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  Projects
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000001  Sofer
+a0000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000002  Bugs
+b0000000-0000-0000-0000-000000000004 00000000-0000-0000-0000-000000000002  Bugs
+"#).unwrap();
+        let sofer = Uuid::parse_str("00000000-0000-0000-0000-000000000002").unwrap();
+        let bugs = Uuid::parse_str("a0000000-0000-0000-0000-000000000003").unwrap();
+
+        assert_eq!(resolve(&tree, "A000"), Ok(bugs));
+        assert_eq!(resolve(&tree, "a0000000-0000-0000-0000-000000000003"), Ok(bugs));
+        assert_eq!(resolve(&tree, "1.1.1"), Ok(bugs));
+        assert_eq!(resolve_from(&tree, sofer, "1"), Ok(bugs));
+        assert_eq!(resolve_from(&tree, sofer, ".."), resolve(&tree, "1"));
+        assert_eq!(resolve(&tree, "/Projects/Sofer/"), Ok(sofer));
+        assert_eq!(resolve(&tree, "/"), Ok(Uuid::nil()));
+
+        assert_eq!(resolve(&tree, "1.2"), Err("No node at \"1.2\"".into()));
+        assert_eq!(resolve(&tree, "c"), Err("No node matches \"c\"".into()));
+        assert!(resolve(&tree, "0000").unwrap_err().contains("is ambiguous"));
+        assert_eq!(resolve(&tree, "/Projects/Sofer/Bugs"), Err(
+            "\"Bugs\" is ambiguous, it could be:\n    \
+             a0000000-0000-0000-0000-000000000003  Bugs\n    \
+             b0000000-0000-0000-0000-000000000004  Bugs".into()
+        ));
+
+        let uuids = all_uuids(&tree);
+        assert_eq!(short_id(&uuids, bugs), "a000");
+        assert_eq!(short_id(&uuids, sofer), "00000000-0000-0000-0000-000000000002");
+    }
+}
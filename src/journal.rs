@@ -0,0 +1,291 @@
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use node::TreeNode;
+use oplog::{read_operation, write_operation, Operation};
+
+/* Journaled storage of an outline: a snapshot in the sofer format, FILE, and the operations made
+ * since then, appended to FILE.journal. Loading replays the journal onto the snapshot, and
+ * compacting writes a new snapshot and starts an empty journal.
+ *
+ * The journal starts with a header that holds a checksum of the snapshot it applies to:
+ *     sofer-journal 1 <checksum>
+ * followed by the operations, in the format of the change log, each one ended by a line with its
+ * own checksum:
+ *     end <checksum>
+ * Appends are synced before returning. An operation cut by a crash at the end of the journal has
+ * no end line yet, and it's dropped; one whose checksum doesn't match is an error. Compaction
+ * writes both files aside, then appends the checksum of the new snapshot to the old journal:
+ *     compacted <checksum>
+ * and only then renames the files over the old ones. If it's interrupted between the two renames,
+ * the old journal ends with the checksum of the new snapshot, which already has its operations,
+ * so it's ignored. A journal that applies to another snapshot is an error.
+ */
+
+const HEADER: &str = "sofer-journal 1";
+/// Starts the line that ends an operation, followed by its checksum.
+const RECORD_END: &str = "end\t";
+/// Starts the line that ends a journal being compacted, followed by the new snapshot's checksum.
+const COMPACTED: &str = "compacted ";
+
+/// Number of operations after which the journal should be compacted.
+pub const COMPACT_AFTER: usize = 1000;
+
+pub struct Journal {
+    snapshot: PathBuf,
+    journal: PathBuf,
+    checksum: String,
+    /// Operations in the journal, or `None` if the journal has to be started over.
+    operations: Option<usize>,
+}
+
+/// FNV-1a hash of the snapshot, to tell which snapshot a journal applies to.
+fn checksum(bytes: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+fn read_to_string_if_exists(path: &Path) -> io::Result<Option<String>> {
+    match fs::File::open(path) {
+        Ok(mut f) => {
+            let mut str = String::new();
+            f.read_to_string(&mut str)?;
+            Ok(Some(str))
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn write_synced(path: &Path, contents: &str) -> io::Result<()> {
+    let mut f = fs::File::create(path)?;
+    f.write_all(contents.as_bytes())?;
+    f.sync_all()
+}
+
+/// Where the journal of the outline stored at `path` is kept.
+pub fn journal_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.journal", path.display()))
+}
+
+impl Journal {
+    /// Loads the outline stored at `path`. A missing snapshot is an empty outline.
+    pub fn open(path: &Path) -> io::Result<(Journal, TreeNode)> {
+        let snapshot = read_to_string_if_exists(path)?.unwrap_or(String::new());
+        let mut tree = TreeNode::import_from_sofer(&snapshot)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let mut journal = Journal {
+            snapshot: path.to_path_buf(),
+            journal: journal_path(path),
+            checksum: checksum(snapshot.as_bytes()),
+            operations: None,
+        };
+
+        let str = match read_to_string_if_exists(&journal.journal)? {
+            Some(str) => str,
+            None => return Ok((journal, tree)),
+        };
+        let malformed = || io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Malformed journal {}", journal.journal.display()),
+        );
+
+        let header = match str.find('\n') {
+            Some(end) => &str[..end],
+            // Cut while being started.
+            None => return Ok((journal, tree)),
+        };
+        if !header.starts_with(HEADER) {
+            return Err(malformed());
+        }
+        if header != format!("{} {}", HEADER, journal.checksum) {
+            // Compaction was interrupted after the snapshot was replaced.
+            if str.ends_with(&format!("\n{}{}\n", COMPACTED, journal.checksum)) {
+                return Ok((journal, tree));
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Journal {} doesn't apply to {}", journal.journal.display(), path.display()),
+            ));
+        }
+
+        // Bytes of the journal that hold whole operations.
+        let mut valid = header.len() + 1;
+        let mut operations = 0;
+        let body = &str[valid..];
+        let mut record_start = 0;
+        let mut pos = 0;
+        while let Some(len) = body[pos..].find('\n') {
+            let line = &body[pos..pos + len];
+            pos += len + 1;
+            if !line.starts_with(RECORD_END) {
+                continue;
+            }
+            let record = &body[record_start..pos - len - 1];
+            if line[RECORD_END.len()..] != checksum(record.as_bytes()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Corrupt operation at byte {} of journal {}", valid, journal.journal.display()),
+                ));
+            }
+            let mut lines = record.split('\n').filter(|l| !l.is_empty());
+            match read_operation(&mut lines) {
+                Some(("done", operation)) if lines.next().is_none() => {
+                    if !operation.apply(&mut tree) {
+                        return Err(malformed());
+                    }
+                }
+                _ => return Err(malformed()),
+            }
+            operations += 1;
+            record_start = pos;
+            valid = header.len() + 1 + pos;
+        }
+
+        // What follows the last end line is an operation cut by a crash.
+        if valid < str.len() {
+            let f = fs::OpenOptions::new().write(true).open(&journal.journal)?;
+            f.set_len(valid as u64)?;
+            f.sync_all()?;
+        }
+        journal.operations = Some(operations);
+        Ok((journal, tree))
+    }
+
+    /// Number of operations in the journal.
+    pub fn len(&self) -> usize {
+        self.operations.unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn append(&mut self, operation: &Operation) -> io::Result<()> {
+        if self.operations.is_none() {
+            write_synced(&self.journal, &format!("{} {}\n", HEADER, self.checksum))?;
+            self.operations = Some(0);
+        }
+
+        let mut str = String::new();
+        write_operation(&mut str, "done", operation);
+        let end = format!("{}{}\n", RECORD_END, checksum(str.as_bytes()));
+        str.push_str(&end);
+        let mut f = fs::OpenOptions::new().append(true).open(&self.journal)?;
+        f.write_all(str.as_bytes())?;
+        f.sync_data()?;
+        self.operations = self.operations.map(|n| n + 1);
+        Ok(())
+    }
+
+    /// Replaces the snapshot with `tree`, which should be the loaded outline with the appended
+    /// operations applied, and empties the journal.
+    pub fn compact(&mut self, tree: &TreeNode) -> io::Result<()> {
+        let snapshot = tree.export_to_sofer(false);
+        let checksum = checksum(snapshot.as_bytes());
+
+        let snapshot_tmp = PathBuf::from(format!("{}.tmp", self.snapshot.display()));
+        let journal_tmp = PathBuf::from(format!("{}.tmp", self.journal.display()));
+        write_synced(&snapshot_tmp, &snapshot)?;
+        write_synced(&journal_tmp, &format!("{} {}\n", HEADER, checksum))?;
+        if self.operations.is_some() {
+            let mut f = fs::OpenOptions::new().append(true).open(&self.journal)?;
+            f.write_all(format!("{}{}\n", COMPACTED, checksum).as_bytes())?;
+            f.sync_data()?;
+        }
+        fs::rename(&snapshot_tmp, &self.snapshot)?;
+        fs::rename(&journal_tmp, &self.journal)?;
+        if let Some(dir) = self.snapshot.parent() {
+            let dir = if dir == Path::new("") { Path::new(".") } else { dir };
+            fs::File::open(dir)?.sync_all()?;
+        }
+
+        self.checksum = checksum;
+        self.operations = Some(0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use node::Node;
+    use tree::Tree;
+    use uuid::Uuid;
+
+    #[test]
+    fn journal_replay_and_compact() {
+        let path = env::temp_dir().join(format!("sofer-journal-test-{}.sofer", Uuid::new_v4()));
+        let journal_path = journal_path(&path);
+        let (mut journal, mut tree) = Journal::open(&path).unwrap();
+        let child = Tree::new_child(Node::new("child".into(), vec![]));
+        let uuid = child.uuid;
+        let operation = Operation::append(&tree, Uuid::nil(), child).unwrap();
+        assert!(operation.apply(&mut tree));
+        journal.append(&operation).unwrap();
+        let operation = Operation::set_raw(&tree, uuid, "edited".into()).unwrap();
+        assert!(operation.apply(&mut tree));
+        journal.append(&operation).unwrap();
+
+        let (journal, replayed) = Journal::open(&path).unwrap();
+        assert_eq!(replayed, tree);
+        assert_eq!(journal.len(), 2);
+
+        // An operation cut by a crash is dropped, and the journal is truncated before it.
+        let complete = fs::metadata(&journal_path).unwrap().len();
+        let operation = Operation::set_raw(&tree, uuid, "lost".into()).unwrap();
+        let mut str = String::new();
+        write_operation(&mut str, "done", &operation);
+        fs::OpenOptions::new().append(true).open(&journal_path).unwrap()
+            .write_all(&str.as_bytes()[..str.len() - 3]).unwrap();
+        let (mut journal, replayed) = Journal::open(&path).unwrap();
+        assert_eq!(replayed, tree);
+        assert_eq!(fs::metadata(&journal_path).unwrap().len(), complete);
+
+        // A whole operation that doesn't match its checksum is an error, and nothing is dropped.
+        let end = format!("{}{}\n", RECORD_END, checksum(str.as_bytes()));
+        let corrupt = str.replace("lost", "lust") + &end;
+        fs::OpenOptions::new().append(true).open(&journal_path).unwrap()
+            .write_all(corrupt.as_bytes()).unwrap();
+        assert!(Journal::open(&path).is_err());
+        assert_eq!(fs::metadata(&journal_path).unwrap().len(), complete + corrupt.len() as u64);
+        let f = fs::OpenOptions::new().write(true).open(&journal_path).unwrap();
+        f.set_len(complete).unwrap();
+
+        journal.compact(&tree).unwrap();
+        let (journal, compacted) = Journal::open(&path).unwrap();
+        assert_eq!(journal.len(), 0);
+        assert_eq!(compacted.find(uuid).unwrap().value.raw, "edited");
+
+        // A journal left behind by a compaction interrupted after the snapshot was replaced ends
+        // with the new snapshot's checksum, and its operations are already in it.
+        let snapshot = fs::read_to_string(&path).unwrap();
+        let old = format!("{} 0000000000000000\n{}{}", HEADER, str, end);
+        fs::write(&journal_path, format!("{}{}{}\n", old, COMPACTED, checksum(snapshot.as_bytes()))).unwrap();
+        let (_, reopened) = Journal::open(&path).unwrap();
+        assert_eq!(reopened.find(uuid).unwrap().value.raw, "edited");
+
+        // Any other journal that doesn't apply to the snapshot is an error, and it's kept.
+        fs::write(&journal_path, &old).unwrap();
+        assert!(Journal::open(&path).is_err());
+        assert_eq!(fs::read_to_string(&journal_path).unwrap(), old);
+
+        // Interrupted before the snapshot was replaced, the journal still applies to it.
+        let current = format!("{} {}\n{}{}", HEADER, checksum(snapshot.as_bytes()), str, end);
+        fs::write(&journal_path, format!("{}{}0123456789abcdef\n", current, COMPACTED)).unwrap();
+        let (journal, reopened) = Journal::open(&path).unwrap();
+        assert_eq!(journal.len(), 1);
+        assert_eq!(reopened.find(uuid).unwrap().value.raw, "lost");
+        assert_eq!(fs::read_to_string(&journal_path).unwrap(), current);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&journal_path).unwrap();
+    }
+}
@@ -0,0 +1,299 @@
+use std::fmt;
+
+use node::Attribute;
+
+/* Minimal JSON values, for the commands that print JSON and the JSON-RPC server. */
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(k, v)| (String::from(k), v)).collect())
+    }
+
+    pub fn string<S: Into<String>>(s: S) -> Json {
+        Json::String(s.into())
+    }
+
+    pub fn attribute_value(attr: &Attribute) -> Json {
+        match *attr {
+            Attribute::String(_, ref v) => Json::String(v.clone()),
+            Attribute::Number(_, v) => Json::Number(v as f64),
+            Attribute::Boolean(_, b) => Json::Bool(b),
+        }
+    }
+
+    /// The field `key` of an object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|&(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            &Json::Number(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            &Json::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn parse(str: &str) -> Result<Json, String> {
+        let mut parser = Parser { chars: str.chars().collect(), i: 0 };
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.i < parser.chars.len() {
+            return Err(format!("Unexpected character at {}", parser.i));
+        }
+        Ok(value)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    i: usize,
+}
+
+impl Parser {
+    fn whitespace(&mut self) {
+        while self.i < self.chars.len() && self.chars[self.i].is_whitespace() {
+            self.i += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.i).cloned()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.peek() == Some(c) {
+            self.i += 1;
+            Ok(())
+        } else {
+            Err(format!("Expected '{}' at {}", c, self.i))
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        for c in word.chars() {
+            self.expect(c)?;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.whitespace();
+        match self.peek() {
+            Some('n') => self.keyword("null", Json::Null),
+            Some('t') => self.keyword("true", Json::Bool(true)),
+            Some('f') => self.keyword("false", Json::Bool(false)),
+            Some('"') => self.string().map(Json::String),
+            Some('[') => {
+                self.i += 1;
+                let mut values = Vec::new();
+                self.whitespace();
+                if self.peek() == Some(']') {
+                    self.i += 1;
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.whitespace();
+                    match self.peek() {
+                        Some(',') => self.i += 1,
+                        Some(']') => {
+                            self.i += 1;
+                            return Ok(Json::Array(values));
+                        }
+                        _ => return Err(format!("Expected ',' or ']' at {}", self.i)),
+                    }
+                }
+            }
+            Some('{') => {
+                self.i += 1;
+                let mut fields = Vec::new();
+                self.whitespace();
+                if self.peek() == Some('}') {
+                    self.i += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.whitespace();
+                    let key = self.string()?;
+                    self.whitespace();
+                    self.expect(':')?;
+                    fields.push((key, self.value()?));
+                    self.whitespace();
+                    match self.peek() {
+                        Some(',') => self.i += 1,
+                        Some('}') => {
+                            self.i += 1;
+                            return Ok(Json::Object(fields));
+                        }
+                        _ => return Err(format!("Expected ',' or '}}' at {}", self.i)),
+                    }
+                }
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let start = self.i;
+                while self.peek().map(|c| c.is_ascii_digit() || "+-.eE".contains(c)).unwrap_or(false) {
+                    self.i += 1;
+                }
+                let number = self.chars[start..self.i].iter().collect::<String>();
+                number.parse().map(Json::Number).map_err(|_| format!("Malformed number at {}", start))
+            }
+            _ => Err(format!("Expected a value at {}", self.i)),
+        }
+    }
+
+    fn hex(&mut self) -> Result<u32, String> {
+        let digits = self.chars.get(self.i..self.i + 4).ok_or("Unfinished escape".to_string())?;
+        let digits = digits.iter().collect::<String>();
+        self.i += 4;
+        u32::from_str_radix(&digits, 16).map_err(|_| format!("Malformed escape at {}", self.i - 4))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            let c = self.peek().ok_or("Unfinished string".to_string())?;
+            self.i += 1;
+            match c {
+                '"' => return Ok(string),
+                '\\' => {
+                    let escaped = self.peek().ok_or("Unfinished string".to_string())?;
+                    self.i += 1;
+                    match escaped {
+                        'n' => string.push('\n'),
+                        'r' => string.push('\r'),
+                        't' => string.push('\t'),
+                        'b' => string.push('\u{8}'),
+                        'f' => string.push('\u{c}'),
+                        'u' => {
+                            let mut code = self.hex()?;
+                            // A surrogate pair.
+                            if (0xd800..0xdc00).contains(&code) && self.chars.get(self.i..self.i + 2) == Some(&['\\', 'u'][..]) {
+                                self.i += 2;
+                                code = 0x10000 + ((code - 0xd800) << 10) + (self.hex()? - 0xdc00);
+                            }
+                            string.push(::std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                        }
+                        c => string.push(c),
+                    }
+                }
+                c => string.push(c),
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(x) => {
+                if !x.is_finite() {
+                    write!(f, "null")
+                } else if x == x.trunc() && x.abs() < 1e15 {
+                    write!(f, "{}", x as i64)
+                } else {
+                    write!(f, "{}", x)
+                }
+            }
+            Json::String(ref s) => write_string(f, s),
+            Json::Array(ref values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(ref fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_display() {
+        let json = Json::object(vec![
+            ("text", Json::string("a \"quoted\"\n\u{1}text")),
+            ("values", Json::Array(vec![Json::Null, Json::Bool(true), Json::Number(3.0), Json::Number(0.5)])),
+            ("empty", Json::Object(vec![])),
+        ]);
+        assert_eq!(
+            json.to_string(),
+            r#"{"text":"a \"quoted\"\n\u0001text","values":[null,true,3,0.5],"empty":{}}"#
+        );
+    }
+
+    #[test]
+    fn json_parse() {
+        let json = Json::parse(r#" {"text": "a \"quoted\"\n\u0001text \ud83d\ude00", "values": [null, true, -3, 0.5e1],
+            "empty": {}, "nested": [[]]} "#).unwrap();
+        assert_eq!(json.get("text").and_then(|t| t.as_str()), Some("a \"quoted\"\n\u{1}text \u{1f600}"));
+        assert_eq!(
+            json.get("values"),
+            Some(&Json::Array(vec![Json::Null, Json::Bool(true), Json::Number(-3.0), Json::Number(5.0)]))
+        );
+        assert_eq!(Json::parse(&json.to_string()), Ok(json));
+        assert!(Json::parse("[1,]").is_err());
+        assert!(Json::parse("{\"a\": 1} x").is_err());
+    }
+}
@@ -0,0 +1,196 @@
+/* The core of Sofer, for front-ends to build on.
+ *
+ * An outline is a `TreeNode`: a `Tree` of `Node`s, each with its text and attributes. It can be
+ * read and written in several formats with `import` and `export`, edited through `Operation`s
+ * (recorded in an `OperationLog` to undo them), evaluated with `TreeNode::eval_all` (see `eval`),
+ * queried, validated, diffed and merged, and stored in a journal or a SQLite database.
+ *
+ * Native front-ends can use it through the C interface in `ffi`.
+ */
+
+extern crate rlua;
+extern crate hlist_macro;
+extern crate uuid;
+extern crate xml;
+extern crate regex;
+#[macro_use]
+extern crate rusqlite;
+
+mod reader;
+mod pattern;
+
+pub mod node;
+pub mod tree;
+pub mod schema;
+pub mod links;
+pub mod query;
+pub mod search;
+pub mod ids;
+pub mod oplog;
+pub mod json;
+pub mod diff;
+pub mod merge;
+pub mod journal;
+pub mod sqlite;
+pub mod ffi;
+pub mod server;
+pub mod shell;
+pub mod task;
+pub mod habit;
+pub mod agenda;
+pub mod clock;
+pub mod ics;
+pub mod tags;
+pub mod eval;
+pub mod date;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use uuid::Uuid;
+
+pub use node::{Attribute, Node, TreeNode};
+pub use tree::Tree;
+pub use oplog::{Operation, OperationLog};
+pub use query::Query;
+
+/// The text formats outlines can be read from and written to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// One `uuid parent attributes text` line per node, with a tab and the script after the text
+    /// if the node has one.
+    Sofer,
+    /// Sofer files written before scripts had their own field, which can only be imported.
+    LegacySofer,
+    /// A Lua table with the values and children of the nodes.
+    Lua,
+    /// OPML, which can only be imported.
+    Opml,
+    /// The text of the nodes, indented, which can only be exported.
+    Pretty,
+    /// iCalendar, with the nodes that have a date as events and to-dos.
+    Ics,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Format, String> {
+        match str {
+            "sofer" => Ok(Format::Sofer),
+            "sofer-legacy" => Ok(Format::LegacySofer),
+            "lua" => Ok(Format::Lua),
+            "opml" => Ok(Format::Opml),
+            "pretty" => Ok(Format::Pretty),
+            "ics" => Ok(Format::Ics),
+            x => Err(format!("Format \"{}\" not supported.", x)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Format::Sofer => "sofer",
+            Format::LegacySofer => "sofer-legacy",
+            Format::Lua => "lua",
+            Format::Opml => "opml",
+            Format::Pretty => "pretty",
+            Format::Ics => "ics",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub fn import(str: &str, format: Format) -> Result<TreeNode, String> {
+    match format {
+        Format::Sofer => TreeNode::import_from_sofer(str),
+        Format::LegacySofer => TreeNode::import_from_legacy_sofer(str),
+        Format::Lua => TreeNode::import_from_lua(str),
+        Format::Opml => TreeNode::import_from_opml(str),
+        Format::Ics => ics::import(str),
+        Format::Pretty => Err(format!("Format \"{}\" can't be imported.", format)),
+    }
+}
+
+/// Writes `tree` in `format`. With `evaled`, formats that only have one text per node use the
+/// evaluated one.
+pub fn export(tree: &TreeNode, format: Format, evaled: bool) -> Result<String, String> {
+    match format {
+        Format::Sofer => Ok(tree.export_to_sofer(evaled)),
+        Format::Lua => Ok(tree.export_to_lua()),
+        Format::Pretty => Ok(tree.print(evaled)),
+        Format::Ics => Ok(ics::export(tree, date::now_seconds())),
+        Format::Opml | Format::LegacySofer => Err(format!("Format \"{}\" can't be exported.", format)),
+    }
+}
+
+/// Replaces the file at `path` with `contents` through a temporary file next to it, so that it's
+/// never left half written. The file keeps its permissions.
+pub fn write_file(path: &Path, contents: &str) -> io::Result<()> {
+    let tmp = PathBuf::from(format!("{}.{}.tmp", path.display(), Uuid::new_v4()));
+    let write = || -> io::Result<()> {
+        let mut f = fs::File::create(&tmp)?;
+        f.write_all(contents.as_bytes())?;
+        f.sync_all()?;
+        match fs::metadata(path) {
+            Ok(metadata) => fs::set_permissions(&tmp, metadata.permissions())?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+        fs::rename(&tmp, path)
+    };
+    write().inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let tree = import(
+            "00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 done=F; One\n",
+            "sofer".parse().unwrap(),
+        ).unwrap();
+        let lua = export(&tree, Format::Lua, false).unwrap();
+        assert_eq!(import(&lua, Format::Lua).unwrap(), tree);
+        assert!(export(&tree, Format::Opml, false).is_err());
+        let opml = import(r#"<opml><body><outline text="One"><outline text="Two"/></outline></body></opml>"#, Format::Opml).unwrap();
+        assert_eq!(opml.nodes().iter().map(|n| n.value.raw.as_str()).collect::<Vec<_>>(), vec!["", "One", "Two"]);
+        assert!(import(r#"<opml><body><outline text="One">"#, Format::Opml).is_err());
+        assert_eq!("html".parse::<Format>(), Err("Format \"html\" not supported.".into()));
+    }
+
+    #[test]
+    fn subtree_export() {
+        let tree = import(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  Projects
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000001  Sofer
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000002  Bugs
+00000000-0000-0000-0000-000000000004 00000000-0000-0000-0000-000000000000  Inbox
+"#, Format::Sofer).unwrap();
+        let sofer = uuid::Uuid::parse_str("00000000-0000-0000-0000-000000000002").unwrap();
+        let inbox = uuid::Uuid::parse_str("00000000-0000-0000-0000-000000000004").unwrap();
+
+        let focused = tree.focus(sofer, None).unwrap();
+        assert_eq!(export(&focused, Format::Pretty, false).unwrap(), "\n    Sofer\n        Bugs\n");
+        assert_eq!(export(&tree.focus(sofer, Some(0)).unwrap(), Format::Pretty, false).unwrap(), "\n    Sofer\n");
+        assert_eq!(export(&tree.focus(tree.uuid, Some(1)).unwrap(), Format::Pretty, false).unwrap(), "\n    Projects\n    Inbox\n");
+
+        // Exported subtrees can be put back under another parent.
+        let exported = export(&focused, Format::Sofer, false).unwrap();
+        let imported = import(&exported, Format::Sofer).unwrap();
+        let mut other = tree.clone();
+        other.remove(sofer);
+        let operation = Operation::append(&other, inbox, imported.subtree(sofer, None).unwrap()).unwrap();
+        assert!(operation.apply(&mut other));
+        assert_eq!(other.find_parent(sofer).unwrap().uuid, inbox);
+        assert_eq!(other.find(sofer).unwrap().children().count(), 1);
+    }
+}
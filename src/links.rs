@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use node::{Node, TreeNode};
+use tree::RemapIds;
+
+/* References to other nodes are written as `((uuid))` in the text of a node. When the node is
+ * evaluated or rendered, they are replaced by the text of the referenced node (transclusion).
+ */
+
+/// Calls `f` with every well-formed reference in `text`, in order. `f` returns the text that
+/// replaces the reference, or `None` to keep it as it is.
+pub fn replace_references<F>(text: &str, mut f: F) -> String
+    where F: FnMut(Uuid) -> Option<String> {
+    let mut str = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("((") {
+        str.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let replacement = rest[2..]
+            .find("))")
+            .and_then(|end| Uuid::parse_str(rest[2..2 + end].trim()).ok().map(|uuid| (uuid, end + 4)))
+            .and_then(|(uuid, len)| f(uuid).map(|text| (text, len)));
+
+        match replacement {
+            Some((text, len)) => {
+                str.push_str(&text);
+                rest = &rest[len..];
+            }
+            None => {
+                str.push_str("((");
+                rest = &rest[2..];
+            }
+        }
+    }
+    str.push_str(rest);
+    str
+}
+
+/// Length of a UUID written with hyphens, like scripts write them in `sofer.get("...")`.
+const UUID_LENGTH: usize = 36;
+
+/// Replaces every UUID written with hyphens in `script` that `ids` has by the one it maps to.
+fn remap_uuids(script: &str, ids: &HashMap<Uuid, Uuid>) -> String {
+    let mut str = String::new();
+    let mut rest = script;
+    while rest.len() >= UUID_LENGTH {
+        let new = if rest.is_char_boundary(UUID_LENGTH) {
+            Uuid::parse_str(&rest[..UUID_LENGTH]).ok().and_then(|uuid| ids.get(&uuid))
+        } else {
+            None
+        };
+        let len = match new {
+            Some(new) => {
+                str.push_str(&new.to_string());
+                UUID_LENGTH
+            }
+            None => {
+                let len = rest.chars().next().map(char::len_utf8).unwrap_or(1);
+                str.push_str(&rest[..len]);
+                len
+            }
+        };
+        rest = &rest[len..];
+    }
+    str.push_str(rest);
+    str
+}
+
+pub fn references(text: &str) -> Vec<Uuid> {
+    let mut uuids = Vec::new();
+    replace_references(text, |uuid| {
+        uuids.push(uuid);
+        None
+    });
+    uuids
+}
+
+/// References between copied nodes, in their texts and in the UUIDs their scripts use, are changed
+/// to point to the copies.
+impl RemapIds for Node {
+    fn remap_ids(&mut self, ids: &HashMap<Uuid, Uuid>) {
+        self.raw = replace_references(&self.raw, |uuid| ids.get(&uuid).map(|new| format!("(({}))", new)));
+        self.script = self.script.as_ref().map(|script| remap_uuids(script, ids));
+    }
+}
+
+impl TreeNode {
+    /// Every node (searching from `self`) whose text references the node `uuid`.
+    pub fn backlinks(&self, uuid: Uuid) -> Vec<TreeNode> {
+        self.nodes()
+            .into_iter()
+            .filter(|n| references(&n.value.raw).contains(&uuid))
+            .cloned()
+            .collect()
+    }
+
+    /// The text renderers show for this node, with its references expanded. `root` is the tree
+    /// the references are looked up in.
+    pub fn rendered_text(&self, root: &TreeNode, evaled: bool) -> String {
+        self.rendered_text_visiting(root, evaled, &mut vec![])
+    }
+
+    fn rendered_text_visiting(&self, root: &TreeNode, evaled: bool, visiting: &mut Vec<Uuid>) -> String {
+        if evaled {
+            if let Some(ref evaled) = self.value.evaled {
+                return evaled.clone();
+            }
+        }
+
+        visiting.push(self.uuid);
+        let text = replace_references(&self.value.raw, |uuid| {
+            if visiting.contains(&uuid) {
+                None
+            } else {
+                root.find(uuid).map(|n| n.rendered_text_visiting(root, evaled, visiting))
+            }
+        });
+        visiting.pop();
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tree::Tree;
+
+    #[test]
+    fn replace() {
+        let uuid = Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap();
+        assert_eq!(
+            replace_references(
+                "see ((00000000-0000-0000-0000-000000000001)), not ((this)) or ((00000000-0000-0000-0000-000000000002))",
+                |u| if u == uuid { Some("one".into()) } else { None }
+            ),
+            "see one, not ((this)) or ((00000000-0000-0000-0000-000000000002))"
+        );
+        assert_eq!(replace_references("unclosed ((", |_| None), "unclosed ((");
+        assert_eq!(
+            references("((00000000-0000-0000-0000-000000000001)) ((00000000000000000000000000000002))"),
+            vec![
+                uuid,
+                Uuid::parse_str("00000000-0000-0000-0000-000000000002").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn backlinks_and_rendered_text() {
+        let mut tree = Tree::new_tree(Node::new("".into(), vec![]));
+        let first = Tree::new_child(Node::new("first".into(), vec![]));
+        let second = Tree::new_child(Node::new(format!("second quotes (({}))", first.uuid), vec![]));
+        let third = Tree::new_child(Node::new("third".into(), vec![]));
+        let fourth = Tree::new_child(Node::new(format!("cycle (({}))", third.uuid), vec![]));
+        tree.insert(Uuid::nil(), first.clone());
+        tree.insert(Uuid::nil(), second.clone());
+        tree.insert(first.uuid, third.clone());
+        tree.insert(third.uuid, fourth.clone());
+        tree.find_mut(third.uuid).unwrap().value.raw = format!("third quotes (({}))", fourth.uuid);
+
+        assert_eq!(
+            tree.backlinks(first.uuid).iter().map(|n| n.uuid).collect::<Vec<_>>(),
+            vec![second.uuid]
+        );
+        assert_eq!(
+            tree.find(second.uuid).unwrap().rendered_text(&tree, false),
+            "second quotes first"
+        );
+        assert_eq!(
+            tree.find(third.uuid).unwrap().rendered_text(&tree, false),
+            format!("third quotes cycle (({}))", third.uuid)
+        );
+    }
+
+    #[test]
+    fn deep_clone_remaps_references() {
+        let mut tree = Tree::new_tree(Node::new("".into(), vec![]));
+        let outside = Tree::new_child(Node::new("outside".into(), vec![]));
+        let first = Tree::new_child(Node::new("first".into(), vec![]));
+        let child = Tree::new_child(Node::new("child".into(), vec![]));
+        tree.insert(Uuid::nil(), outside.clone());
+        tree.insert(Uuid::nil(), first.clone());
+        tree.insert(first.uuid, child.clone());
+        tree.find_mut(first.uuid).unwrap().value.raw = format!("(({})) and (({}))", child.uuid, outside.uuid);
+
+        let (copy, ids) = tree.find(first.uuid).unwrap().deep_clone_with_new_ids();
+        assert_eq!(copy.value.raw, format!("(({})) and (({}))", ids[&child.uuid], outside.uuid));
+        tree.insert(Uuid::nil(), copy.clone());
+        assert_eq!(tree.find(copy.uuid).unwrap().rendered_text(&tree, false), "child and outside");
+    }
+
+    #[test]
+    fn deep_clone_remaps_scripts() {
+        let mut tree = Tree::new_tree(Node::new("".into(), vec![]));
+        let outside = Tree::new_child(Node::new("outside".into(), vec![]));
+        let first = Tree::new_child(Node::new("Sum: ".into(), vec![]));
+        let child = Tree::new_child(Node::new("child".into(), vec![]));
+        tree.insert(Uuid::nil(), outside.clone());
+        tree.insert(Uuid::nil(), first.clone());
+        tree.insert(first.uuid, child.clone());
+        let script = |child: Uuid, outside: Uuid| format!(
+            "function(n) return sofer.get(\"{}\").value.raw .. sofer.get('{}').value.raw end",
+            child, outside
+        );
+        tree.find_mut(first.uuid).unwrap().value.script = Some(script(child.uuid, outside.uuid));
+
+        let (copy, ids) = tree.find(first.uuid).unwrap().deep_clone_with_new_ids();
+        assert_eq!(copy.value.script, Some(script(ids[&child.uuid], outside.uuid)));
+        tree.insert(Uuid::nil(), copy.clone());
+        tree.find_mut(ids[&child.uuid]).unwrap().value.raw = "copied child".into();
+        assert_eq!(tree.find(copy.uuid).unwrap().eval(&tree), Ok("Sum: copied childoutside".into()));
+    }
+}
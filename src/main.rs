@@ -1,23 +1,102 @@
-extern crate rlua;
-extern crate hlist_macro;
 extern crate uuid;
 extern crate clap;
-extern crate xml;
-
-mod reader;
-mod node;
-mod tree;
+extern crate sofer_core;
 
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::io::prelude::*;
-use std::fs::File;
+use std::fs::{self, File};
+use std::path::Path;
+use std::time::SystemTime;
 use clap::{Arg, App, SubCommand};
 use uuid::Uuid;
-use tree::Tree;
-use node::Node;
+use sofer_core::{Attribute, Format, Node, Operation, OperationLog, Query, Tree, TreeNode};
+use sofer_core::eval::Diagnostic;
+use sofer_core::{agenda, clock, date, diff, habit, ids, journal, json, merge, search, server, shell, sqlite, tags, task};
+
+fn read_file(file_name: &str) -> String {
+    let mut f = match File::open(file_name) {
+        Ok(f) => f,
+        Err(err) => panic!("{}", err),
+    };
+    let mut buffer = Vec::new();
+    let _ = f.read_to_end(&mut buffer);
+    match String::from_utf8(buffer) {
+        Ok(string) => string,
+        Err(err) => panic!("{}", err),
+    }
+}
+
+/// Exits if the outline in `file_name` has a journal, as the file alone is missing the changes
+/// stored there.
+fn refuse_journaled(file_name: &str) {
+    if journal::journal_path(Path::new(file_name)).exists() {
+        eprintln!("{} has a journal: use --journal, or compact it into {}", file_name, file_name);
+        std::process::exit(1);
+    }
+}
+
+/// Prints the error of a node's script, with its traceback, to stderr.
+fn print_diagnostic(diagnostic: &Diagnostic) {
+    eprintln!("{}", diagnostic);
+    if let Some(ref traceback) = diagnostic.error.traceback {
+        eprintln!("{}", traceback);
+    }
+}
+
+/// What a file was like when it was read, to tell whether it changed before writing over it.
+#[derive(PartialEq)]
+struct Stamp {
+    modified: Option<SystemTime>,
+    hash: u64,
+}
+
+impl Stamp {
+    fn new(path: &Path, contents: &str) -> Stamp {
+        let mut hasher = DefaultHasher::new();
+        contents.hash(&mut hasher);
+        Stamp {
+            modified: fs::metadata(path).and_then(|m| m.modified()).ok(),
+            hash: hasher.finish(),
+        }
+    }
+}
+
+/// Replaces the file at `path` with `contents` (see `sofer_core::write_file`), unless the file
+/// changed since `stamp`. With `backup`, the old file is kept at `path` followed by `backup`.
+fn write_in_place(path: &Path, contents: &str, stamp: &Stamp, backup: Option<&str>) -> Result<(), String> {
+    let current = fs::read_to_string(path).map_err(|err| format!("Couldn't read {}: {}", path.display(), err))?;
+    if Stamp::new(path, &current) != *stamp {
+        return Err(format!("{} changed since it was read, not writing over it", path.display()));
+    }
+    let write = || -> std::io::Result<()> {
+        if let Some(suffix) = backup {
+            fs::copy(path, format!("{}{}", path.display(), suffix))?;
+        }
+        sofer_core::write_file(path, contents)
+    };
+    write().map_err(|err| format!("Couldn't write {}: {}", path.display(), err))
+}
+
+/// The node named `name`, as a UUID or one of the names of `ids`. Exits if there's none, or if
+/// the name is ambiguous.
+fn resolve(tree: &TreeNode, name: &str) -> Uuid {
+    ids::resolve(tree, name).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    })
+}
+
+fn import(str: &str, format: Option<&str>) -> TreeNode {
+    let format = format.unwrap_or("sofer").parse().unwrap_or_else(|err: String| panic!("{}", err));
+    sofer_core::import(str, format).unwrap_or_else(|err| panic!("{}", err))
+}
 
 fn main() {
     let matches = App::new("sofer")
         .version("0.0.0")
+        .after_help("Nodes can be given by UUID, by a prefix of their UUID (3f2a), by a path of indexes \
+                     from the root (1.3.2) or by a path of texts (/Projects/Sofer/Bugs).")
         .arg(Arg::with_name("file")
             .short("f")
             .long("file")
@@ -36,12 +115,69 @@ fn main() {
             .takes_value(true)
             .help("Set exporting format")
         )
+        .arg(Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("File to export to, for formats that aren't printed (sqlite)")
+        )
+        .arg(Arg::with_name("in-place")
+            .short("i")
+            .long("in-place")
+            .requires("file")
+            .conflicts_with_all(&["journal", "to", "evaled"])
+            .help("Write the changed outline back to FILE, in the format it was read in, instead of printing it")
+        )
+        .arg(Arg::with_name("backup")
+            .long("backup")
+            .takes_value(true)
+            .value_name("SUFFIX")
+            .requires("in-place")
+            .help("Keep the file as it was before writing in place, with SUFFIX added to its name")
+        )
+        .arg(Arg::with_name("root")
+            .long("root")
+            .takes_value(true)
+            .value_name("NODE")
+            .conflicts_with("in-place")
+            .help("Export only this node and its descendants")
+        )
+        .arg(Arg::with_name("depth")
+            .long("depth")
+            .takes_value(true)
+            .value_name("N")
+            .conflicts_with("in-place")
+            .help("Export only N levels of nodes below the root")
+        )
+        .arg(Arg::with_name("tag")
+            .long("tag")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("TAG")
+            .conflicts_with("in-place")
+            .help("Export only the nodes with one of these tags, like #work or @home, and their ancestors")
+        )
         .arg(Arg::with_name("evaled")
             .long("evaled")
             .help("If the exporting format only allows one text, choose to export the evaled text")
         )
+        .arg(Arg::with_name("log")
+            .long("log")
+            .help("Record the changes in FILE.log, so that they can be undone (with --in-place, --journal or --from sqlite)")
+        )
+        .arg(Arg::with_name("journal")
+            .long("journal")
+            .help("Store the changes to FILE by appending them to FILE.journal, instead of printing the whole outline")
+        )
         .subcommand(SubCommand::with_name("tree-node")
             .subcommand(SubCommand::with_name("eval")
+                .about("Prints the evaluated text of a node, or the errors of the scripts of every node without UUID")
+                .arg(Arg::with_name("UUID"))
+            )
+            .subcommand(SubCommand::with_name("backlinks")
+                .about("Lists the nodes that reference the given one")
                 .arg(Arg::with_name("UUID").required(true))
             )
         )
@@ -55,64 +191,406 @@ fn main() {
                 .arg(Arg::with_name("UUID").required(true))
                 .arg(Arg::with_name("CONTENT").required(true))
             )
+            .subcommand(SubCommand::with_name("delete")
+                .arg(Arg::with_name("UUID").required(true))
+            )
+            .subcommand(SubCommand::with_name("move")
+                .arg(Arg::with_name("UUID").required(true))
+                .arg(Arg::with_name("PARENT").required(true))
+                .arg(Arg::with_name("after")
+                    .long("after")
+                    .takes_value(true)
+                    .value_name("UUID")
+                    .help("Sibling to put the node after. By default it becomes the last child.")
+                )
+            )
+            .subcommand(SubCommand::with_name("copy")
+                .about("Copies a node and its descendants, with new UUIDs, as the last child of PARENT")
+                .arg(Arg::with_name("UUID").required(true))
+                .arg(Arg::with_name("PARENT").required(true))
+            )
+            .subcommand(SubCommand::with_name("paste")
+                .about("Adds the nodes of a file, with new UUIDs, as the last children of PARENT")
+                .arg(Arg::with_name("SNIPPET").required(true))
+                .arg(Arg::with_name("PARENT").required(true))
+                .arg(Arg::with_name("format")
+                    .long("format")
+                    .takes_value(true)
+                    .help("Format of the file, sofer by default. With ics, its events are added.")
+                )
+            )
+            .subcommand(SubCommand::with_name("edit")
+                .arg(Arg::with_name("UUID").required(true))
+                .arg(Arg::with_name("CONTENT").required(true))
+            )
+            .subcommand(SubCommand::with_name("set-script")
+                .about("Sets the Lua script of a node, or removes it if no script is given")
+                .arg(Arg::with_name("UUID").required(true))
+                .arg(Arg::with_name("SCRIPT"))
+            )
+            .subcommand(SubCommand::with_name("set-attr")
+                .about("Sets an attribute, or removes it if no value is given")
+                .arg(Arg::with_name("UUID").required(true))
+                .arg(Arg::with_name("NAME").required(true))
+                .arg(Arg::with_name("VALUE"))
+            )
+        )
+        .subcommand(SubCommand::with_name("task")
+            .about("Manages the tasks of the outline, the nodes with a status attribute")
+            .subcommand(SubCommand::with_name("toggle")
+                .about("Closes an open task, reopens a closed one, or makes a node a task")
+                .arg(Arg::with_name("UUID").required(true))
+            )
+            .subcommand(SubCommand::with_name("set")
+                .about("Sets the status of a task, or removes it if no state is given")
+                .arg(Arg::with_name("UUID").required(true))
+                .arg(Arg::with_name("STATE"))
+            )
+            .subcommand(SubCommand::with_name("list")
+                .about("Lists the tasks, with their status")
+                .arg(Arg::with_name("state")
+                    .long("state")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Only list tasks in this state, or in any open or closed state with \"open\" or \"closed\"")
+                )
+            )
+        )
+        .subcommand(SubCommand::with_name("habit")
+            .about("Tracks habits, the recurring items with a repeat attribute")
+            .subcommand(SubCommand::with_name("stats")
+                .about("Shows the streaks and completion rate of a habit")
+                .arg(Arg::with_name("UUID").required(true))
+            )
+        )
+        .subcommand(SubCommand::with_name("clock")
+            .about("Tracks the time spent on nodes")
+            .subcommand(SubCommand::with_name("in")
+                .about("Starts the clock on a node, stopping the one running")
+                .arg(Arg::with_name("UUID").required(true))
+            )
+            .subcommand(SubCommand::with_name("out")
+                .about("Stops the clock running")
+            )
+            .subcommand(SubCommand::with_name("report")
+                .about("Sums the time spent on each node and its descendants")
+                .arg(Arg::with_name("root")
+                    .long("root")
+                    .takes_value(true)
+                    .value_name("NODE")
+                    .help("Node to report on. By default, the whole outline.")
+                )
+                .arg(Arg::with_name("from")
+                    .long("from")
+                    .takes_value(true)
+                    .value_name("DATE")
+                    .help("First day to count, as YYYY-MM-DD")
+                )
+                .arg(Arg::with_name("to")
+                    .long("to")
+                    .takes_value(true)
+                    .value_name("DATE")
+                    .help("Last day to count, as YYYY-MM-DD")
+                )
+                .arg(Arg::with_name("csv")
+                    .long("csv")
+                    .help("Print the report as CSV, with times in minutes")
+                )
+            )
+        )
+        .subcommand(SubCommand::with_name("agenda")
+            .about("Lists the scheduled and due nodes day by day, after the overdue ones")
+            .arg(Arg::with_name("from")
+                .long("from")
+                .takes_value(true)
+                .value_name("DATE")
+                .help("First day of the agenda, as YYYY-MM-DD. By default, today.")
+            )
+            .arg(Arg::with_name("days")
+                .long("days")
+                .takes_value(true)
+                .value_name("N")
+                .help("Number of days in the agenda. By default, 7.")
+            )
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["text", "json", "markdown"])
+                .help("Output format. By default, text.")
+            )
+        )
+        .subcommand(SubCommand::with_name("undo")
+            .about("Undoes the last change recorded in FILE.log")
+        )
+        .subcommand(SubCommand::with_name("redo")
+            .about("Redoes the last change undone from FILE.log")
+        )
+        .subcommand(SubCommand::with_name("compact")
+            .about("Writes the changes stored in FILE.journal into FILE")
         )
         .subcommand(SubCommand::with_name("reader")
             .subcommand(SubCommand::with_name("read"))
         )
+        .subcommand(SubCommand::with_name("tags")
+            .about("Lists the #tags and @contexts of the outline, with how many nodes have them")
+        )
+        .subcommand(SubCommand::with_name("validate")
+            .about("Checks the attributes of every node against the schemas declared in the outline")
+        )
+        .subcommand(SubCommand::with_name("query")
+            .about("Exports the nodes matching a query")
+            .arg(Arg::with_name("QUERY").required(true))
+            .arg(Arg::with_name("tree")
+                .long("tree")
+                .help("Keep the ancestors of the matching nodes instead of listing them")
+            )
+        )
+        .subcommand(SubCommand::with_name("search")
+            .about("Searches the outlines of a directory, updating its full-text index")
+            .arg(Arg::with_name("TERMS").required(true).multiple(true))
+            .arg(Arg::with_name("dir")
+                .long("dir")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Directory with the outlines and the index. Defaults to the current one.")
+            )
+            .arg(Arg::with_name("limit")
+                .long("limit")
+                .takes_value(true)
+                .help("Maximum number of results")
+            )
+        )
+        .subcommand(SubCommand::with_name("diff")
+            .about("Shows the changes between two versions of an outline, matching nodes by UUID")
+            .arg(Arg::with_name("OLD").required(true))
+            .arg(Arg::with_name("NEW").required(true))
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Print the changes as a JSON array")
+            )
+        )
+        .subcommand(SubCommand::with_name("merge")
+            .about("Merges the changes made to two versions of an outline since their common base")
+            .arg(Arg::with_name("BASE").required(true))
+            .arg(Arg::with_name("OURS").required(true))
+            .arg(Arg::with_name("THEIRS").required(true))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("File to write the merged outline to, instead of stdout")
+            )
+        )
+        .subcommand(SubCommand::with_name("serve")
+            .about("Keeps the outline in memory and answers JSON-RPC requests, one per line, on stdin and stdout")
+        )
+        .subcommand(SubCommand::with_name("shell")
+            .about("Edits an outline interactively, with commands like ls, cd, add and save")
+            .arg(Arg::with_name("FILE").required(true))
+        )
         .subcommand(SubCommand::with_name("uuid")
             .subcommand(SubCommand::with_name("new"))
         )
         .get_matches();
 
-    let str = match matches.value_of("file") {
-        Some(file_name) => {
-            let mut f = match File::open(file_name) {
-                Ok(f) => f,
-                Err(err) => panic!("{}", err),
-            };
-            let mut buffer = Vec::new();
-            let _ = f.read_to_end(&mut buffer);
-            match String::from_utf8(Vec::from(buffer)) {
-                Ok(string) => string,
-                Err(err) => panic!("{}", err),
+    // Diffing reads two outlines instead of one.
+    if let ("diff", Some(sub)) = matches.subcommand() {
+        let old = import(&read_file(sub.value_of("OLD").unwrap()), matches.value_of("from"));
+        let new = import(&read_file(sub.value_of("NEW").unwrap()), matches.value_of("from"));
+        let changes = diff::diff(&old, &new);
+        if sub.is_present("json") {
+            println!("{}", json::Json::Array(changes.iter().map(|c| c.to_json()).collect()));
+        } else {
+            for change in &changes {
+                println!("{}", change);
             }
         }
-        None => {
-            let mut stdio = std::io::stdin();
-            let mut str = String::new();
-            let _ = stdio.read_to_string(&mut str);
-            str
+        if !changes.is_empty() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Merging reads three outlines instead of one. It also works as a git merge driver, which
+    // gets the merged outline written over ours and fails when there are conflicts.
+    if let ("merge", Some(sub)) = matches.subcommand() {
+        let base = import(&read_file(sub.value_of("BASE").unwrap()), matches.value_of("from"));
+        let ours = import(&read_file(sub.value_of("OURS").unwrap()), matches.value_of("from"));
+        let theirs = import(&read_file(sub.value_of("THEIRS").unwrap()), matches.value_of("from"));
+        let merge = merge::merge(&base, &ours, &theirs);
+        let merged = merge.tree.export_to_sofer(false);
+        match sub.value_of("output") {
+            Some(path) => {
+                let mut f = File::create(path).unwrap_or_else(|err| panic!("{}", err));
+                f.write_all(merged.as_bytes()).unwrap_or_else(|err| panic!("{}", err));
+            }
+            None => print!("{}", merged),
+        }
+        for conflict in &merge.conflicts {
+            eprintln!("CONFLICT {}", conflict);
+        }
+        if !merge.conflicts.is_empty() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Serving reads requests from stdin instead of an outline. --file is opened at the start.
+    if let ("serve", Some(_)) = matches.subcommand() {
+        let mut server = server::Server::new();
+        if let Some(path) = matches.value_of("file") {
+            refuse_journaled(path);
+            let format = matches.value_of("from").unwrap_or("sofer").parse().unwrap_or_else(|err: String| panic!("{}", err));
+            server.open(Path::new(path), format).unwrap_or_else(|err| panic!("{}", err));
+        }
+        let stdin = std::io::stdin();
+        let stdout = std::io::stdout();
+        server.run(stdin.lock(), stdout.lock()).expect("Couldn't talk over stdio");
+        return;
+    }
+
+    // The shell reads commands from stdin and keeps its own outline.
+    if let ("shell", Some(sub)) = matches.subcommand() {
+        refuse_journaled(sub.value_of("FILE").unwrap());
+        let mut shell = shell::Shell::open(Path::new(sub.value_of("FILE").unwrap()))
+            .unwrap_or_else(|err| panic!("{}", err));
+        let stdin = std::io::stdin();
+        let stdout = std::io::stdout();
+        shell.run(stdin.lock(), stdout.lock()).expect("Couldn't talk over stdio");
+        return;
+    }
+
+    // Searching reads the outlines of a directory instead of a single outline.
+    if let ("search", Some(sub)) = matches.subcommand() {
+        let dir = Path::new(sub.value_of("dir").unwrap_or("."));
+        let index_path = dir.join(search::INDEX_FILE_NAME);
+        let limit = sub.value_of("limit").map(|l| l.parse().expect("Couldn't read limit")).unwrap_or(20);
+
+        let mut index = search::Index::load(&index_path);
+        let files = search::outline_files(dir).expect("Couldn't list the outlines");
+        let (updated, errors) = index.update(&files);
+        for err in &errors {
+            eprintln!("Skipping {}", err);
+        }
+        if updated > 0 || !errors.is_empty() {
+            index.save(&index_path).expect("Couldn't save the index");
         }
-    };
 
-    let mut treenode = match matches.value_of("from") {
-        Some("lua") =>
-            node::TreeNode::import_from_lua(&str),
-        Some("opml") =>
-            node::TreeNode::import_from_opml(&str),
-        Some(x) =>
-            panic!("Format \"{}\" not supported.", x),
-        None =>
-            node::TreeNode::import_from_sofer(&str),
+        let terms = sub.values_of("TERMS").unwrap().collect::<Vec<_>>().join(" ");
+        for hit in index.search(&terms).iter().take(limit) {
+            println!("{} {} {}", hit.uuid, hit.file, hit.path);
+            println!("    {}", hit.snippet);
+        }
+        return;
+    }
+
+    // In place, the outline is only ever replaced by a changed version of itself, in a format
+    // that reads back as the same outline.
+    if matches.is_present("in-place") {
+        if matches.subcommand_name() == Some("query") {
+            eprintln!("--in-place can't be used with query, which only prints the matching nodes");
+            std::process::exit(1);
+        }
+        let format = matches.value_of("from").unwrap_or("sofer");
+        if format != "sofer" && format != "lua" {
+            eprintln!("--in-place can't write the {} format, which doesn't keep the whole outline", format);
+            std::process::exit(1);
+        }
+    }
+
+    let use_journal = matches.is_present("journal") || matches.subcommand_name() == Some("compact");
+    let mut database = None;
+    let mut stamp = None;
+    let (mut journal, mut treenode) = if use_journal {
+        let path = matches.value_of("file").expect("The journal needs a --file");
+        let (journal, treenode) = journal::Journal::open(Path::new(path)).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        });
+        (Some(journal), treenode)
+    } else if matches.value_of("from") == Some("sqlite") {
+        let path = matches.value_of("file").expect("Reading from SQLite needs a --file");
+        let db = sqlite::Database::open(Path::new(path)).unwrap_or_else(|err| panic!("{}", err));
+        let treenode = db.load().unwrap_or_else(|err| panic!("{}", err));
+        database = Some(db);
+        (None, treenode)
+    } else {
+        let str = match matches.value_of("file") {
+            Some(file_name) => {
+                refuse_journaled(file_name);
+                let str = read_file(file_name);
+                stamp = Some(Stamp::new(Path::new(file_name), &str));
+                str
+            }
+            None => {
+                let mut stdio = std::io::stdin();
+                let mut str = String::new();
+                let _ = stdio.read_to_string(&mut str);
+                str
+            }
+        };
+        (None, import(&str, matches.value_of("from")))
     };
 
     let mut export = false;
 
-    if matches.is_present("evaled") {
-        treenode.eval_all();
+    let log_changes = match matches.subcommand_name() {
+        Some("undo") | Some("redo") => true,
+        _ => matches.is_present("log"),
+    };
+    // The log must follow the outline in FILE, so the changes have to be written there.
+    let persisted = matches.is_present("in-place") || use_journal || database.is_some();
+    if log_changes && !persisted {
+        eprintln!("The change log needs the changes to be stored in FILE: use --in-place, --journal or --from sqlite");
+        std::process::exit(1);
+    }
+    let log_path = if log_changes {
+        Some(format!("{}.log", matches.value_of("file").expect("The change log needs a --file")))
+    } else {
+        None
+    };
+    let mut log = match log_path {
+        Some(ref path) => OperationLog::load(Path::new(path)).unwrap_or_else(|err| panic!("{}", err)),
+        None => OperationLog::new(),
+    };
+
+    if matches.is_present("evaled") && matches.subcommand_name() != Some("tree-node") {
+        for diagnostic in treenode.eval_all() {
+            print_diagnostic(&diagnostic);
+        }
     }
 
     match matches.subcommand() {
         ("tree-node", Some(sub)) => {
             match sub.subcommand() {
                 ("eval", Some(subsub)) => {
-                    println!(
-                        "{}",
-                        treenode
-                            .find(Uuid::parse_str(subsub.value_of("UUID").unwrap()).expect("Couldn't read UUID"))
-                            .expect(&format!("Couldn't find node with UUID \"{}\"", subsub.value_of("UUID").unwrap()))
-                            .eval()
-                        );
+                    let diagnostics = match subsub.value_of("UUID") {
+                        Some(uuid) => {
+                            let uuid = resolve(&treenode, uuid);
+                            match treenode.find(uuid).unwrap().eval(&treenode) {
+                                Ok(text) => {
+                                    println!("{}", text);
+                                    vec![]
+                                }
+                                Err(error) => vec![Diagnostic { uuid, error }],
+                            }
+                        }
+                        None => treenode.eval_all(),
+                    };
+                    for diagnostic in &diagnostics {
+                        print_diagnostic(diagnostic);
+                    }
+                    if !diagnostics.is_empty() {
+                        std::process::exit(1);
+                    }
+                }
+                ("backlinks", Some(subsub)) => {
+                    let uuid = resolve(&treenode, subsub.value_of("UUID").unwrap());
+                    for n in treenode.backlinks(uuid) {
+                        println!("{} {}", n.uuid, n.value.raw);
+                    }
                 }
                 _ => (),
             }
@@ -120,22 +598,215 @@ fn main() {
         ("tree", Some(sub)) => {
             match sub.subcommand() {
                 ("insert", Some(subsub)) => {
-                    let uuid = Uuid::parse_str(subsub.value_of("UUID").unwrap()).expect("Couldn't read UUID");
+                    let uuid = resolve(&treenode, subsub.value_of("UUID").unwrap());
                     let content = subsub.value_of("CONTENT").unwrap();
-                    treenode.insert(uuid, Tree::new_child(Node::new(content.into(), Vec::new())));
+                    let operation = Operation::append(&treenode, uuid, Tree::new_child(Node::new(content.into(), Vec::new())))
+                        .unwrap_or_else(|| panic!("Couldn't find node with UUID \"{}\"", uuid));
+                    log.apply(&mut treenode, operation);
 
                     export = true;
                 }
                 ("insert-next-to", Some(subsub)) => {
-                    let uuid = Uuid::parse_str(subsub.value_of("UUID").unwrap()).expect("Couldn't read UUID");
+                    let uuid = resolve(&treenode, subsub.value_of("UUID").unwrap());
+                    let content = subsub.value_of("CONTENT").unwrap();
+                    let operation = Operation::insert_next_to(&treenode, uuid, Tree::new_child(Node::new(content.into(), Vec::new())))
+                        .unwrap_or_else(|| panic!("Couldn't find node with UUID \"{}\"", uuid));
+                    log.apply(&mut treenode, operation);
+
+                    export = true;
+                }
+                ("delete", Some(subsub)) => {
+                    let uuid = resolve(&treenode, subsub.value_of("UUID").unwrap());
+                    let operation = Operation::delete(&treenode, uuid)
+                        .unwrap_or_else(|| panic!("Couldn't find node with UUID \"{}\"", uuid));
+                    log.apply(&mut treenode, operation);
+
+                    export = true;
+                }
+                ("move", Some(subsub)) => {
+                    let uuid = resolve(&treenode, subsub.value_of("UUID").unwrap());
+                    let parent = resolve(&treenode, subsub.value_of("PARENT").unwrap());
+                    let after = match subsub.value_of("after") {
+                        Some(after) => Some(resolve(&treenode, after)),
+                        None => treenode
+                            .find(parent)
+                            .unwrap_or_else(|| panic!("Couldn't find node with UUID \"{}\"", parent))
+                            .children()
+                            .map(|n| n.uuid)
+                            .filter(|&child| child != uuid)
+                            .last(),
+                    };
+                    let operation = Operation::move_to(&treenode, uuid, parent, after)
+                        .unwrap_or_else(|| panic!("Couldn't find node with UUID \"{}\"", uuid));
+                    if !log.apply(&mut treenode, operation) {
+                        panic!("Couldn't move node \"{}\" there", uuid);
+                    }
+
+                    export = true;
+                }
+                ("copy", Some(subsub)) => {
+                    let uuid = resolve(&treenode, subsub.value_of("UUID").unwrap());
+                    let parent = resolve(&treenode, subsub.value_of("PARENT").unwrap());
+                    let (copy, _) = treenode.find(uuid).unwrap().deep_clone_with_new_ids();
+                    let operation = Operation::append(&treenode, parent, copy)
+                        .unwrap_or_else(|| panic!("Couldn't find node with UUID \"{}\"", parent));
+                    if !log.apply(&mut treenode, operation) {
+                        panic!("Couldn't copy node \"{}\" there", uuid);
+                    }
+
+                    export = true;
+                }
+                ("paste", Some(subsub)) => {
+                    let parent = resolve(&treenode, subsub.value_of("PARENT").unwrap());
+                    // The snippet's root is copied too, to remap the references between its nodes.
+                    let snippet = import(&read_file(subsub.value_of("SNIPPET").unwrap()), subsub.value_of("format"));
+                    let (snippet, _) = snippet.deep_clone_with_new_ids();
+                    for uuid in snippet.children().map(|n| n.uuid).collect::<Vec<_>>() {
+                        let operation = Operation::append(&treenode, parent, snippet.subtree(uuid, None).unwrap())
+                            .unwrap_or_else(|| panic!("Couldn't find node with UUID \"{}\"", parent));
+                        log.apply(&mut treenode, operation);
+                    }
+
+                    export = true;
+                }
+                ("edit", Some(subsub)) => {
+                    let uuid = resolve(&treenode, subsub.value_of("UUID").unwrap());
                     let content = subsub.value_of("CONTENT").unwrap();
-                    treenode.insert_next_to(uuid, Tree::new_child(Node::new(content.into(), Vec::new())));
+                    let operation = Operation::set_raw(&treenode, uuid, content.into())
+                        .unwrap_or_else(|| panic!("Couldn't find node with UUID \"{}\"", uuid));
+                    log.apply(&mut treenode, operation);
+
+                    export = true;
+                }
+                ("set-script", Some(subsub)) => {
+                    let uuid = resolve(&treenode, subsub.value_of("UUID").unwrap());
+                    let script = subsub.value_of("SCRIPT").map(String::from);
+                    let operation = Operation::set_script(&treenode, uuid, script)
+                        .unwrap_or_else(|| panic!("Couldn't find node with UUID \"{}\"", uuid));
+                    log.apply(&mut treenode, operation);
+
+                    export = true;
+                }
+                ("set-attr", Some(subsub)) => {
+                    let uuid = resolve(&treenode, subsub.value_of("UUID").unwrap());
+                    let name = subsub.value_of("NAME").unwrap();
+                    let value = subsub.value_of("VALUE").map(|value| Attribute::from_text(name, value));
+                    let operation = Operation::set_attr(&treenode, uuid, name, value)
+                        .unwrap_or_else(|| panic!("Couldn't find node with UUID \"{}\"", uuid));
+                    log.apply(&mut treenode, operation);
 
                     export = true;
                 }
                 _ => (),
             }
         }
+        ("task", Some(sub)) => {
+            let now = date::now_seconds();
+            let operations = match sub.subcommand() {
+                ("toggle", Some(subsub)) => {
+                    let uuid = resolve(&treenode, subsub.value_of("UUID").unwrap());
+                    task::toggle(&treenode, uuid, now)
+                }
+                ("set", Some(subsub)) => {
+                    let uuid = resolve(&treenode, subsub.value_of("UUID").unwrap());
+                    task::set_status(&treenode, uuid, subsub.value_of("STATE"), now)
+                }
+                ("list", Some(subsub)) => {
+                    let states = subsub.values_of("state").map(|s| s.collect()).unwrap_or(Vec::new());
+                    for task in task::list(&treenode, &states) {
+                        println!("{} {} {}", task.status, task.uuid, task.text);
+                    }
+                    Ok(Vec::new())
+                }
+                _ => Ok(Vec::new()),
+            };
+            let operations = operations.unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            });
+            if !operations.is_empty() {
+                for operation in operations {
+                    log.apply(&mut treenode, operation);
+                }
+
+                export = true;
+            }
+        }
+        ("habit", Some(sub)) => {
+            if let ("stats", Some(subsub)) = sub.subcommand() {
+                let uuid = resolve(&treenode, subsub.value_of("UUID").unwrap());
+                let now = date::now_seconds();
+                match habit::stats_at(&treenode, uuid, now) {
+                    Ok(stats) => println!("{}", stats),
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+        ("clock", Some(sub)) => {
+            let operations = match sub.subcommand() {
+                ("in", Some(subsub)) => {
+                    let uuid = resolve(&treenode, subsub.value_of("UUID").unwrap());
+                    clock::clock_in(&treenode, uuid, date::now_seconds())
+                }
+                ("out", Some(_)) => clock::clock_out(&treenode, date::now_seconds()),
+                ("report", Some(subsub)) => {
+                    let root = subsub.value_of("root").map(|root| resolve(&treenode, root)).unwrap_or(treenode.uuid);
+                    clock::report(&treenode, root, subsub.value_of("from"), subsub.value_of("to"), date::now_seconds()).map(|rows| {
+                        if subsub.is_present("csv") {
+                            print!("{}", clock::to_csv(&rows));
+                        } else {
+                            print!("{}", clock::to_table(&rows));
+                        }
+                        Vec::new()
+                    })
+                }
+                _ => Ok(Vec::new()),
+            };
+            let operations = operations.unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            });
+            if !operations.is_empty() {
+                for operation in operations {
+                    log.apply(&mut treenode, operation);
+                }
+
+                export = true;
+            }
+        }
+        ("agenda", Some(sub)) => {
+            let days = sub.value_of("days").map(|d| d.parse().expect("Couldn't read days")).unwrap_or(7);
+            let agenda = agenda::agenda(&treenode, sub.value_of("from"), days).unwrap_or_else(|err| panic!("{}", err));
+            match sub.value_of("format") {
+                Some("json") => println!("{}", agenda.to_json()),
+                Some("markdown") => print!("{}", agenda.to_markdown()),
+                _ => print!("{}", agenda.to_text()),
+            }
+        }
+        ("undo", Some(_)) => {
+            if !log.can_undo() {
+                eprintln!("Nothing to undo.");
+            } else if !log.undo(&mut treenode) {
+                panic!("Couldn't undo the last change: the outline doesn't match the change log");
+            }
+
+            export = true;
+        }
+        ("redo", Some(_)) => {
+            if !log.can_redo() {
+                eprintln!("Nothing to redo.");
+            } else if !log.redo(&mut treenode) {
+                panic!("Couldn't redo the last change: the outline doesn't match the change log");
+            }
+
+            export = true;
+        }
+        ("compact", Some(_)) => {
+            journal.as_mut().unwrap().compact(&treenode).expect("Couldn't compact the journal");
+        }
         ("reader", Some(sub)) => {
             match sub.subcommand() {
                 ("read", Some(_)) => {
@@ -144,6 +815,31 @@ fn main() {
                 _ => (),
             }
         }
+        ("tags", Some(_)) => {
+            for (tag, count) in tags::count(&treenode) {
+                println!("{:>5}  {}", count, tag);
+            }
+        }
+        ("validate", Some(_)) => {
+            let violations = treenode.validate();
+            for violation in &violations {
+                println!("{}", violation);
+            }
+            if !violations.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        ("query", Some(sub)) => {
+            let query = Query::parse(sub.value_of("QUERY").unwrap())
+                .unwrap_or_else(|err| panic!("Couldn't parse query: {}", err));
+            treenode = if sub.is_present("tree") {
+                treenode.query_pruned(&query)
+            } else {
+                treenode.query_flat(&query)
+            };
+
+            export = true;
+        }
         ("uuid", Some(sub)) => {
             match sub.subcommand() {
                 ("new", Some(_)) => {
@@ -155,16 +851,93 @@ fn main() {
         (command, _) => println!("Command \"{}\" not recognized.", command),
     }
 
+    // With a journal, changes are stored instead of printed.
+    if let Some(ref mut journal) = journal {
+        if !log.applied().is_empty() {
+            for operation in log.applied() {
+                journal.append(operation).expect("Couldn't append to the journal");
+            }
+            if journal.len() >= journal::COMPACT_AFTER {
+                journal.compact(&treenode).expect("Couldn't compact the journal");
+            }
+            export = false;
+        }
+    }
+
+    // Same with a database, where they are row updates.
+    if let Some(ref mut database) = database {
+        if !log.applied().is_empty() {
+            match database.apply(log.applied()) {
+                Ok(true) => (),
+                Ok(false) => {
+                    eprintln!("Couldn't apply the changes to the database");
+                    std::process::exit(1);
+                }
+                Err(err) => {
+                    eprintln!("Couldn't update the database: {}", err);
+                    std::process::exit(1);
+                }
+            }
+            export = false;
+        }
+    }
+
+    // And written back in place only if there are any.
+    if matches.is_present("in-place") && log.applied().is_empty() {
+        export = false;
+    }
+
     if export {
+        if matches.is_present("root") || matches.is_present("depth") {
+            let root = matches.value_of("root").map(|root| resolve(&treenode, root)).unwrap_or(treenode.uuid);
+            let depth = matches.value_of("depth").map(|depth| depth.parse().expect("Couldn't read depth"));
+            treenode = treenode.focus(root, depth).unwrap();
+        }
+        if let Some(tags) = matches.values_of("tag") {
+            let query = tags
+                .map(|tag| Query::Tag(tags::normalize(tag)))
+                .fold(None, |query, tag| match query {
+                    Some(query) => Some(Query::Or(Box::new(query), Box::new(tag))),
+                    None => Some(tag),
+                })
+                .unwrap();
+            treenode = treenode.query_pruned(&query);
+        }
         match matches.value_of("to") {
-            Some("lua") =>
-                println!("{}", treenode.export_to_lua()),
-            Some("pretty") =>
-                println!("{}", treenode.print(matches.is_present("evaled"))),
-            Some(x) =>
-                println!("Format \"{}\" not supported.", x),
-            None =>
-                println!("{}", treenode.export_to_sofer(matches.is_present("evaled"))),
+            Some("sqlite") => {
+                let path = matches.value_of("output").expect("Exporting to SQLite needs an --output");
+                sqlite::Database::open(Path::new(path))
+                    .and_then(|mut db| db.save(&treenode))
+                    .unwrap_or_else(|err| panic!("{}", err));
+            }
+            format => {
+                let in_place = matches.is_present("in-place");
+                let format = if in_place { matches.value_of("from") } else { format };
+                let exported = format
+                    .unwrap_or("sofer")
+                    .parse()
+                    .and_then(|format: Format| sofer_core::export(&treenode, format, matches.is_present("evaled")));
+                match exported {
+                    Ok(exported) if in_place => {
+                        let path = Path::new(matches.value_of("file").unwrap());
+                        let stamp = stamp.expect("Writing in place needs a file in a text format");
+                        if let Err(err) = write_in_place(path, &exported, &stamp, matches.value_of("backup")) {
+                            eprintln!("{}", err);
+                            std::process::exit(1);
+                        }
+                    }
+                    Ok(exported) => println!("{}", exported),
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        std::process::exit(1);
+                    }
+                }
+            }
         }
     }
+
+    // Last, so that the log never has changes that didn't make it to FILE.
+    if let Some(ref path) = log_path {
+        log.save(Path::new(path)).expect("Couldn't save the change log");
+    }
 }
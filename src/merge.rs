@@ -0,0 +1,416 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use uuid::Uuid;
+
+use node::{Attribute, Node, TreeNode};
+use tree::Tree;
+
+/* Three-way merge of outlines. Nodes are matched by UUID and every property of a node (whether
+ * it exists, its text, each of its attributes and its parent) is merged on its own: a change made
+ * by only one side is taken, and different changes made by both sides are a conflict.
+ *
+ * On conflict, our version is kept, the node gets a `conflict=T;` attribute and a child node
+ * with `conflict="theirs";` describing their version.
+ */
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conflict {
+    pub uuid: Uuid,
+    pub message: String,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.uuid, self.message)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Merge {
+    pub tree: TreeNode,
+    pub conflicts: Vec<Conflict>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Version {
+    parent: Uuid,
+    value: Node,
+}
+
+struct Side {
+    order: Vec<Uuid>,
+    nodes: BTreeMap<Uuid, Version>,
+    children: BTreeMap<Uuid, Vec<Uuid>>,
+}
+
+impl Side {
+    fn new(tree: &TreeNode) -> Side {
+        fn walk(n: &TreeNode, side: &mut Side) {
+            side.children.insert(n.uuid, n.children().map(|c| c.uuid).collect());
+            for child in n.children() {
+                side.order.push(child.uuid);
+                side.nodes.insert(child.uuid, Version { parent: n.uuid, value: child.value.clone() });
+                walk(child, side);
+            }
+        }
+        let mut side = Side { order: Vec::new(), nodes: BTreeMap::new(), children: BTreeMap::new() };
+        walk(tree, &mut side);
+        side
+    }
+
+    fn children(&self, parent: Uuid) -> Vec<Uuid> {
+        self.children.get(&parent).cloned().unwrap_or(Vec::new())
+    }
+}
+
+/// Merges one property: `None` if both sides changed it differently.
+fn merge3<T: PartialEq + Clone>(base: Option<&T>, ours: Option<&T>, theirs: Option<&T>) -> Option<Option<T>> {
+    if ours == theirs || theirs == base {
+        Some(ours.cloned())
+    } else if ours == base {
+        Some(theirs.cloned())
+    } else {
+        None
+    }
+}
+
+fn attribute_text(attr: Option<&Attribute>) -> String {
+    match attr {
+        Some(attr) => attr.export(),
+        None => "(none)".into(),
+    }
+}
+
+struct Merger {
+    base: Side,
+    ours: Side,
+    theirs: Side,
+    merged: BTreeMap<Uuid, Version>,
+    conflicts: Vec<Conflict>,
+    /// Descriptions of their side of the conflicts, by node.
+    notes: BTreeMap<Uuid, Vec<String>>,
+}
+
+impl Merger {
+    fn conflict(&mut self, uuid: Uuid, message: String, note: String) {
+        self.conflicts.push(Conflict { uuid, message });
+        self.notes.entry(uuid).or_default().push(note);
+    }
+
+    fn merge_node(&mut self, uuid: Uuid) {
+        let (base, ours, theirs) = (
+            self.base.nodes.get(&uuid).cloned(),
+            self.ours.nodes.get(&uuid).cloned(),
+            self.theirs.nodes.get(&uuid).cloned(),
+        );
+
+        let (ours, theirs) = match (base.is_some(), ours, theirs) {
+            (_, None, None) => return,
+            (false, Some(v), None) | (false, None, Some(v)) => {
+                self.merged.insert(uuid, v);
+                return;
+            }
+            (true, Some(v), None) | (true, None, Some(v)) => {
+                let ours_deleted = !self.ours.nodes.contains_key(&uuid);
+                if Some(&v) == base.as_ref() {
+                    return;
+                }
+                let message = if ours_deleted {
+                    "deleted in ours, modified in theirs"
+                } else {
+                    "modified in ours, deleted in theirs"
+                };
+                self.conflict(uuid, message.into(), format!("CONFLICT: {}", message));
+                self.merged.insert(uuid, v);
+                return;
+            }
+            (_, Some(ours), Some(theirs)) => (ours, theirs),
+        };
+        let base = base.as_ref();
+
+        let mut merged = ours.clone();
+
+        match merge3(base.map(|v| &v.value.raw), Some(&ours.value.raw), Some(&theirs.value.raw)) {
+            Some(raw) => merged.value.raw = raw.unwrap(),
+            None => self.conflict(
+                uuid,
+                "text changed in both sides".into(),
+                format!("CONFLICT text: {}", theirs.value.raw),
+            ),
+        }
+
+        match merge3(base.map(|v| &v.value.script), Some(&ours.value.script), Some(&theirs.value.script)) {
+            Some(script) => merged.value.script = script.unwrap(),
+            None => self.conflict(
+                uuid,
+                "script changed in both sides".into(),
+                format!("CONFLICT script: {}", theirs.value.script.as_deref().unwrap_or("(none)")),
+            ),
+        }
+
+        let mut names = Vec::new();
+        for version in vec![base, Some(&ours), Some(&theirs)].into_iter().flatten() {
+            for attr in &version.value.attributes {
+                if !names.contains(&attr.name().to_string()) {
+                    names.push(attr.name().to_string());
+                }
+            }
+        }
+        let mut attributes = Vec::new();
+        for name in names {
+            let (b, o, t) = (
+                base.and_then(|v| v.value.get_attribute(&name)),
+                ours.value.get_attribute(&name),
+                theirs.value.get_attribute(&name),
+            );
+            match merge3(b, o, t) {
+                Some(attr) => attributes.extend(attr),
+                None => {
+                    attributes.extend(o.cloned());
+                    self.conflict(
+                        uuid,
+                        format!("attribute \"{}\" changed in both sides", name),
+                        format!("CONFLICT attribute {}: {}", name, attribute_text(t)),
+                    );
+                }
+            }
+        }
+        merged.value.attributes = attributes;
+
+        match merge3(base.map(|v| &v.parent), Some(&ours.parent), Some(&theirs.parent)) {
+            Some(parent) => merged.parent = parent.unwrap(),
+            None => self.conflict(
+                uuid,
+                "moved to different parents in both sides".into(),
+                format!("CONFLICT moved under (({})) in theirs", theirs.parent),
+            ),
+        }
+
+        self.merged.insert(uuid, merged);
+    }
+
+    /// Brings back deleted nodes that still have children, and breaks the cycles two moves can
+    /// make by putting the nodes back where they were in the base.
+    fn repair(&mut self, root: Uuid) {
+        loop {
+            let orphan_parents = self.merged
+                .values()
+                .map(|v| v.parent)
+                .filter(|parent| *parent != root && !self.merged.contains_key(parent))
+                .collect::<Vec<_>>();
+            let parent = match orphan_parents.first() {
+                Some(parent) => *parent,
+                None => break,
+            };
+            let version = self.ours.nodes.get(&parent)
+                .or(self.theirs.nodes.get(&parent))
+                .or(self.base.nodes.get(&parent))
+                .cloned()
+                .unwrap();
+            let message = "deleted in one side, but the other side has new children under it";
+            self.conflict(parent, message.into(), format!("CONFLICT: {}", message));
+            self.merged.insert(parent, version);
+        }
+
+        let uuids = self.merged.keys().cloned().collect::<Vec<_>>();
+        for uuid in uuids {
+            let mut current = uuid;
+            let mut steps = 0;
+            while current != root && steps <= self.merged.len() {
+                current = self.merged[&current].parent;
+                steps += 1;
+            }
+            if current != root {
+                let parent = self.base.nodes.get(&uuid).map(|v| v.parent).unwrap_or(root);
+                self.merged.get_mut(&uuid).unwrap().parent = parent;
+                self.conflict(
+                    uuid,
+                    "moves in both sides make a cycle".into(),
+                    "CONFLICT: moves in both sides make a cycle, the node was left where it was".into(),
+                );
+            }
+        }
+    }
+
+    fn ordered_children(&self, parent: Uuid) -> Vec<Uuid> {
+        let stays = |uuid: &Uuid| self.merged.get(uuid).map(|v| v.parent == parent).unwrap_or(false);
+        let common = |children: Vec<Uuid>, other: &Side| {
+            children.into_iter().filter(|u| other.nodes.contains_key(u)).collect::<Vec<_>>()
+        };
+        let base = self.base.children(parent).into_iter().filter(&stays).collect::<Vec<_>>();
+        let ours = self.ours.children(parent).into_iter().filter(&stays).collect::<Vec<_>>();
+        let theirs = self.theirs.children(parent).into_iter().filter(&stays).collect::<Vec<_>>();
+
+        // If we didn't reorder the children, their order wins.
+        let (primary, secondary) = if common(ours.clone(), &self.base) == common(base.clone(), &self.ours) {
+            (theirs, ours)
+        } else {
+            (ours, theirs)
+        };
+
+        let mut children = primary;
+        for (i, uuid) in secondary.iter().enumerate() {
+            if children.contains(uuid) {
+                continue;
+            }
+            let position = secondary[..i]
+                .iter()
+                .rev()
+                .filter_map(|previous| children.iter().position(|c| c == previous))
+                .next()
+                .map(|p| p + 1)
+                .unwrap_or(0);
+            children.insert(position, *uuid);
+        }
+        for uuid in self.base.order.iter().chain(self.merged.keys()) {
+            if stays(uuid) && !children.contains(uuid) {
+                children.push(*uuid);
+            }
+        }
+        children
+    }
+
+    fn build(&self, uuid: Uuid, value: Node) -> TreeNode {
+        let mut n = TreeNode {
+            value,
+            uuid,
+            first_child: None,
+            next_sibling: None,
+        };
+        let mut children = self.ordered_children(uuid)
+            .into_iter()
+            .map(|child| self.build(child, self.merged[&child].value.clone()))
+            .collect::<Vec<_>>();
+
+        if let Some(notes) = self.notes.get(&uuid) {
+            n.value.attributes.retain(|a| a.name() != "conflict");
+            n.value.attributes.push(Attribute::Boolean("conflict".into(), true));
+            for note in notes {
+                children.push(Tree::new_child(Node::new(
+                    note.clone(),
+                    vec![Attribute::String("conflict".into(), "theirs".into())],
+                )));
+            }
+        }
+        n.set_children(children);
+        n
+    }
+}
+
+pub fn merge(base: &TreeNode, ours: &TreeNode, theirs: &TreeNode) -> Merge {
+    let mut merger = Merger {
+        base: Side::new(base),
+        ours: Side::new(ours),
+        theirs: Side::new(theirs),
+        merged: BTreeMap::new(),
+        conflicts: Vec::new(),
+        notes: BTreeMap::new(),
+    };
+
+    let mut uuids = merger.base.order.clone();
+    for uuid in merger.ours.order.iter().chain(merger.theirs.order.iter()) {
+        if !uuids.contains(uuid) {
+            uuids.push(*uuid);
+        }
+    }
+    for uuid in uuids {
+        merger.merge_node(uuid);
+    }
+    merger.repair(ours.uuid);
+
+    Merge {
+        tree: merger.build(ours.uuid, ours.value.clone()),
+        conflicts: merger.conflicts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(text: &str) -> TreeNode {
+        TreeNode::import_from_sofer(text).unwrap()
+    }
+
+    fn shape(tree: &TreeNode) -> Vec<(i32, String)> {
+        tree.traverse()
+            .into_iter()
+            .skip(1)
+            .map(|(depth, n)| (depth, format!("{}{}", n.export_attributes(), n.value.raw)))
+            .collect()
+    }
+
+    const BASE: &str =
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  One
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000000  Two
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000000 n=1; Three
+00000000-0000-0000-0000-000000000004 00000000-0000-0000-0000-000000000001  Four
+"#;
+
+    #[test]
+    fn merge_independent_changes() {
+        let ours = read(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  One, edited
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000000  Two
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000000 n=1;m=T; Three
+00000000-0000-0000-0000-000000000004 00000000-0000-0000-0000-000000000002  Four
+00000000-0000-0000-0000-000000000005 00000000-0000-0000-0000-000000000000  Five
+"#);
+        let theirs = read(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  One
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000000  Two
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000000 n=2; Three
+00000000-0000-0000-0000-000000000004 00000000-0000-0000-0000-000000000001  Four, edited
+00000000-0000-0000-0000-000000000006 00000000-0000-0000-0000-000000000001  Six
+"#);
+        let merge = merge(&read(BASE), &ours, &theirs);
+        assert_eq!(merge.conflicts, vec![]);
+        assert_eq!(
+            shape(&merge.tree),
+            vec![
+                (1, "One, edited".into()),
+                (2, "Six".into()),
+                (1, "Two".into()),
+                (2, "Four, edited".into()),
+                (1, "n=2;m=T;Three".into()),
+                (1, "Five".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_conflicts() {
+        let ours = read(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  One, ours
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000000  Two, ours
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000000 n=2; Three
+"#);
+        let theirs = read(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  One, theirs
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000000 n=3; Three
+00000000-0000-0000-0000-000000000004 00000000-0000-0000-0000-000000000001  Four
+00000000-0000-0000-0000-000000000005 00000000-0000-0000-0000-000000000003  Five
+"#);
+        let merge = merge(&read(BASE), &ours, &theirs);
+        assert_eq!(
+            merge.conflicts.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+            vec![
+                "00000000-0000-0000-0000-000000000001 text changed in both sides",
+                "00000000-0000-0000-0000-000000000002 modified in ours, deleted in theirs",
+                "00000000-0000-0000-0000-000000000003 attribute \"n\" changed in both sides",
+            ]
+        );
+        assert_eq!(
+            shape(&merge.tree),
+            vec![
+                (1, "conflict=T;One, ours".into()),
+                (2, "conflict=\"theirs\";CONFLICT text: One, theirs".into()),
+                (1, "conflict=T;Two, ours".into()),
+                (2, "conflict=\"theirs\";CONFLICT: modified in ours, deleted in theirs".into()),
+                (1, "n=2;conflict=T;Three".into()),
+                (2, "Five".into()),
+                (2, "conflict=\"theirs\";CONFLICT attribute n: n=3;".into()),
+            ]
+        );
+    }
+}
@@ -1,10 +1,15 @@
+use std::rc::Rc;
 use rlua;
 use rlua::Lua;
 use uuid::Uuid;
 use xml::reader::{EventReader, XmlEvent};
 use xml::attribute::OwnedAttribute;
 
+use eval;
+use eval::{Diagnostic, EvalError, EvalResult};
+use links;
 use reader;
+use task;
 use tree;
 
 #[derive(Clone, Debug, PartialEq)]
@@ -14,6 +19,43 @@ pub enum Attribute {
     Boolean(String, bool),
 }
 
+impl Attribute {
+    /// Reads an attribute given as text: `T`/`true` and `F`/`false` are booleans, numbers are
+    /// numbers and anything else is a string (surrounding quotes are optional).
+    pub fn from_text(name: &str, value: &str) -> Attribute {
+        match value {
+            "T" | "true" => Attribute::Boolean(name.into(), true),
+            "F" | "false" => Attribute::Boolean(name.into(), false),
+            _ => match value.parse() {
+                Ok(x) => Attribute::Number(name.into(), x),
+                Err(_) => {
+                    let quoted = value.len() >= 2 && value.starts_with('"') && value.ends_with('"');
+                    let value = if quoted { &value[1..value.len() - 1] } else { value };
+                    Attribute::String(name.into(), value.into())
+                }
+            }
+        }
+    }
+
+    /// The attribute in the syntax of .sofer files, e.g. `done=T;`.
+    pub fn export(&self) -> String {
+        match *self {
+            Attribute::String(ref k, ref v) => format!("{}={:?};", k, v),
+            Attribute::Number(ref k, ref v) => format!("{}={};", k, v),
+            Attribute::Boolean(ref k, true) => format!("{}=T;", k),
+            Attribute::Boolean(ref k, false) => format!("{}=F;", k),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match *self {
+            Attribute::String(ref k, _) => k,
+            Attribute::Number(ref k, _) => k,
+            Attribute::Boolean(ref k, _) => k,
+        }
+    }
+}
+
 fn attributes_from_lua<'lua>(lua_value: rlua::LuaValue<'lua>) -> rlua::LuaResult<Vec<Attribute>> {
     let mut attrs = Vec::new();
     match lua_value {
@@ -42,25 +84,40 @@ fn attributes_from_lua<'lua>(lua_value: rlua::LuaValue<'lua>) -> rlua::LuaResult
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Node {
+    /// The text shown for the node, which can reference other nodes as `((uuid))`.
     pub raw: String,
+    /// Lua code whose result is added to the text when the node is evaluated.
+    pub script: Option<String>,
     pub evaled: Option<String>,
     pub attributes: Vec<Attribute>,
 }
 
 impl Node {
     pub fn new(raw: String, attributes: Vec<Attribute>) -> Node {
+        Node::with_script(raw, None, attributes)
+    }
+
+    pub fn with_script(raw: String, script: Option<String>, attributes: Vec<Attribute>) -> Node {
         Node {
             raw,
+            script,
             evaled: None,
             attributes,
         }
     }
+
+    pub fn get_attribute(&self, name: &str) -> Option<&Attribute> {
+        self.attributes.iter().find(|attr| attr.name() == name)
+    }
 }
 
 impl<'lua> rlua::ToLua<'lua> for Node {
     fn to_lua(self, lua: &'lua rlua::Lua) -> rlua::LuaResult<rlua::LuaValue> {
         let table = lua.create_table();
+        table.set("tags", self.tags().into_iter().collect::<Vec<_>>())?;
         table.set("raw", self.raw)?;
+        table.set("script", self.script)?;
+        table.set("evaled", self.evaled)?;
         Ok(rlua::LuaValue::Table(table))
     }
 }
@@ -70,6 +127,7 @@ impl<'lua> rlua::FromLua<'lua> for Node {
         match lua_value {
             rlua::LuaValue::Table(table) => {
                 let raw: String = table.get("raw")?;
+                let script: Option<String> = table.get("script")?;
 
                 let evaled: Option<String> = match table.get("evaled")? {
                     rlua::LuaValue::String(str) => Some(str.to_str()?.into()),
@@ -81,6 +139,7 @@ impl<'lua> rlua::FromLua<'lua> for Node {
 
                 Ok(Node {
                     raw,
+                    script,
                     evaled,
                     attributes
                 })
@@ -92,53 +151,126 @@ impl<'lua> rlua::FromLua<'lua> for Node {
 
 pub type TreeNode = tree::Tree<Node>;
 
+/// Exposes the `sofer` table to node scripts. `sofer.get(uuid)` returns any node of `root`, or
+/// `nil` if there's none with that UUID.
+fn register_api(lua: &Lua, root: Rc<TreeNode>) -> rlua::LuaResult<()> {
+    let sofer = lua.create_table();
+
+    sofer.set("get", lua.create_function(move |lua, args| {
+        let uuid: String = lua.unpack(args)?;
+        let node = match Uuid::parse_str(&uuid).ok().and_then(|uuid| root.find(uuid)) {
+            Some(node) => node.to_script_lua(lua)?,
+            None => rlua::LuaValue::Nil,
+        };
+        lua.pack(node)
+    }))?;
+
+    lua.globals().set("sofer", sofer)
+}
+
 impl TreeNode {
-    pub fn eval(&self) -> String {
-        let lua = Lua::new();
+    /// The node as scripts get it, with its tags as `node.tags` as well as `node.value.tags`.
+    pub fn to_script_lua<'lua>(&self, lua: &'lua Lua) -> rlua::LuaResult<rlua::LuaValue<'lua>> {
+        let node = rlua::ToLua::to_lua(self.clone(), lua)?;
+        if let rlua::LuaValue::Table(ref table) = node {
+            let value: rlua::LuaTable = table.get("value")?;
+            table.set("tags", value.get::<_, rlua::LuaValue>("tags")?)?;
+        }
+        Ok(node)
+    }
 
-        let mut text = self.value.raw.chars().take_while(|&c| c != '@').collect::<String>();
-        let lua_code = self.value.raw.chars().skip_while(|&c| c != '@').skip(1).collect::<String>();
+    /// Evaluates the node: its text, with the references to other nodes resolved against `root`,
+    /// followed by the value of its script. `sofer.get` calls are also resolved against `root`.
+    pub fn eval(&self, root: &TreeNode) -> Result<String, EvalError> {
+        let lua = Lua::new();
+        register_api(&lua, Rc::new(root.clone())).expect("Couldn't register the sofer Lua API");
+        let (mut text, result) = self.eval_visiting(root, &lua, &mut vec![]);
+        text.push_str(&result?.to_string());
+        Ok(text)
+    }
 
-        let result = if !lua_code.is_empty() {
-            match lua.eval(&lua_code) {
-                Ok(rlua::LuaValue::Function(f)) =>
-                    f.call::<TreeNode, String>(self.clone()).unwrap_or(String::from("error function")),
-                Ok(x) => format!("{:?}", x),
-                Err(err) => format!("{:?}", err),
+    /// The text of the node with its references resolved, and the value of its script. Referenced
+    /// nodes whose script fails are shown with their text alone. Scripts run in `lua`, which has
+    /// the `sofer` API.
+    fn eval_visiting(&self, root: &TreeNode, lua: &Lua, visiting: &mut Vec<Uuid>) -> (String, EvalResult) {
+        visiting.push(self.uuid);
+        let text = links::replace_references(
+            &self.value.raw,
+            |uuid| {
+                if visiting.contains(&uuid) {
+                    None
+                } else {
+                    root.find(uuid).map(|n| {
+                        let (mut text, result) = n.eval_visiting(root, lua, visiting);
+                        if let Ok(value) = result {
+                            text.push_str(&value.to_string());
+                        }
+                        text
+                    })
+                }
             }
-        } else {
-            String::from("")
+        );
+        visiting.pop();
+
+        let result = match self.value.script {
+            Some(ref code) if !code.trim().is_empty() => eval::run(lua, code, self),
+            _ => Ok(eval::Value::Nil),
         };
+        (text, result)
+    }
 
-        text.push_str(&result);
-        text
+    /// Evaluates every node, adding the progress of the tasks among its children (see `task`).
+    /// Nodes whose script fails keep their text alone, and their errors are returned.
+    pub fn eval_all(&mut self) -> Vec<Diagnostic> {
+        let root = Rc::new(self.clone());
+        let lua = Lua::new();
+        register_api(&lua, root.clone()).expect("Couldn't register the sofer Lua API");
+        let mut diagnostics = Vec::new();
+        self.eval_all_in(&root, &lua, &task::Workflow::default(), &mut diagnostics);
+        diagnostics
     }
 
-    pub fn eval_all(&mut self) {
-        self.value.evaled = Some(self.eval());
+    /// `workflow` is the one of the parent.
+    fn eval_all_in(&mut self, root: &TreeNode, lua: &Lua, workflow: &task::Workflow, diagnostics: &mut Vec<Diagnostic>) {
+        let declared = task::declared_workflow(self);
+        let own_workflow = declared.as_ref().unwrap_or(workflow);
+        let (mut evaled, result) = self.eval_visiting(root, lua, &mut vec![]);
+        match result {
+            Ok(value) => evaled.push_str(&value.to_string()),
+            Err(error) => diagnostics.push(Diagnostic { uuid: self.uuid, error }),
+        }
+        if let Some((closed, total)) = task::progress(self, own_workflow) {
+            evaled.push_str(&format!(" [{}/{}]", closed, total));
+        }
+        self.value.evaled = Some(evaled);
 
         match self.first_child {
-            Some(ref mut first_child) => first_child.eval_all(),
+            Some(ref mut first_child) => first_child.eval_all_in(root, lua, own_workflow, diagnostics),
             None => (),
         }
 
         match self.next_sibling {
-            Some(ref mut next_sibling) => next_sibling.eval_all(),
+            Some(ref mut next_sibling) => next_sibling.eval_all_in(root, lua, workflow, diagnostics),
             None => (),
         }
     }
 
-    pub fn import_from_sofer(str: &str) -> TreeNode {
-        reader::nodes_to_tree_node(reader::read_nodes(str))
+    pub fn import_from_sofer(str: &str) -> Result<TreeNode, String> {
+        reader::read_nodes(str).map(reader::nodes_to_tree_node)
+    }
+
+    /// Reads a .sofer file written before scripts had their own field, where they started at an
+    /// `@` in the text.
+    pub fn import_from_legacy_sofer(str: &str) -> Result<TreeNode, String> {
+        reader::read_legacy_nodes(str).map(reader::nodes_to_tree_node)
     }
 
-    pub fn import_from_lua(lua_code: &str) -> TreeNode {
+    pub fn import_from_lua(lua_code: &str) -> Result<TreeNode, String> {
         let lua = rlua::Lua::new();
-        let treenode: TreeNode = lua.eval(lua_code).unwrap();
-        treenode
+        lua.eval::<TreeNode>(lua_code).map_err(|err| err.to_string())
     }
 
-    pub fn import_from_opml(str: &str) -> TreeNode {
+    pub fn import_from_opml(str: &str) -> Result<TreeNode, String> {
         let parser = EventReader::from_str(str);
         let mut reading = false;
         let mut ids = vec![];
@@ -179,35 +311,34 @@ impl TreeNode {
                         ids.pop();
                     }
                 }
-                Err(e) => {
-                    println!("Error: {}", e);
-                    break;
-                }
+                Err(e) => return Err(e.to_string()),
                 _ => {}
             }
         }
-        tree
+        Ok(tree)
+    }
+
+    /// The text of the node in a .sofer line: its escaped text, followed by a tab and its escaped
+    /// script if it has one. With `evaled`, the evaluated text alone.
+    fn sofer_content(&self, evaled: bool) -> String {
+        match (evaled, &self.value.evaled, &self.value.script) {
+            (true, Some(evaled), _) => reader::escape(evaled),
+            (false, _, Some(script)) => format!("{}\t{}", reader::escape(&self.value.raw), reader::escape(script)),
+            _ => reader::escape(&self.value.raw),
+        }
     }
 
     pub fn export_to_sofer(&self, evaled: bool) -> String {
         fn to_vec(n: &TreeNode, evaled: bool) -> Vec<(Uuid, Uuid, String, String)> {
             let mut treenodes = Vec::new();
 
-            let text = if evaled {
-                n.value.evaled.clone().unwrap_or(n.value.raw.clone())
-            } else {
-                n.value.raw.clone()
-            };
+            let text = n.sofer_content(evaled);
 
             treenodes.push((n.uuid, Uuid::nil(), n.export_attributes(), text));
             treenodes.append(&mut to_vec_children(&n, evaled));
 
             for sibling in n.get_siblings() {
-                let text = if evaled {
-                    sibling.value.evaled.clone().unwrap_or(sibling.value.raw.clone())
-                } else {
-                    sibling.value.raw.clone()
-                };
+                let text = sibling.sofer_content(evaled);
 
                 treenodes.push((sibling.uuid, Uuid::nil(), sibling.export_attributes(), text));
                 treenodes.append(&mut to_vec_children(&sibling, evaled));
@@ -219,11 +350,7 @@ impl TreeNode {
         fn to_vec_children(n: &TreeNode, evaled: bool) -> Vec<(Uuid, Uuid, String, String)> {
             let mut treenodes = Vec::new();
             for child in n.get_children() {
-                let text = if evaled {
-                    child.value.evaled.clone().unwrap_or(child.value.raw.clone())
-                } else {
-                    child.value.raw.clone()
-                };
+                let text = child.sofer_content(evaled);
 
                 treenodes.push((child.uuid, n.uuid, child.export_attributes(), text));
                 treenodes.append(&mut to_vec_children(&child, evaled));
@@ -231,14 +358,10 @@ impl TreeNode {
             treenodes
         }
 
-        let mut str = String::new();
-        let mut vec = to_vec(self, evaled);
-        vec.sort_by_key(|k| k.0);
-        vec
-            .iter()
-            .skip(1)
-            .inspect(|x| str.push_str(&format!("{} {} {} {}\n", x.0, x.1, x.2, x.3)))
-            .collect::<Vec<_>>();
+        let mut str = format!("{}\n", reader::HEADER);
+        for x in to_vec(self, evaled).iter().skip(1) {
+            str.push_str(&format!("{} {} {} {}\n", x.0, x.1, x.2, x.3));
+        }
         str
     }
 
@@ -252,6 +375,13 @@ impl TreeNode {
         str.push_str(&format!("{:?}", self.value.raw));
         str.push(',');
 
+        str.push_str("script=");
+        match self.value.script {
+            Some(ref script) => str.push_str(&format!("{:?}", script)),
+            None => str.push_str("nil"),
+        }
+        str.push(',');
+
         str.push_str("evaled=");
         match self.value.evaled {
             Some(ref evaled) => str.push_str(&format!("{:?}", evaled)),
@@ -266,7 +396,7 @@ impl TreeNode {
                 &String(ref k, ref v) => str.push_str(&format!("[\"{}\"]={:?};", k, v)),
                 &Number(ref k, ref v) => str.push_str(&format!("[\"{}\"]={};", k, v)),
                 &Boolean(ref k, true) => str.push_str(&format!("[\"{}\"]=true;", k)),
-                &Boolean(ref k, false) => str.push_str(&format!("[\"{}\"]=true;", k)),
+                &Boolean(ref k, false) => str.push_str(&format!("[\"{}\"]=false;", k)),
             }
         }
         str.push('}');
@@ -295,6 +425,7 @@ impl TreeNode {
             {
                 value = {
                     raw = %,
+                    script = %,
                     evaled = %,
                     attributes = {
                         ["%"] = %,
@@ -322,11 +453,7 @@ impl TreeNode {
         let mut str = String::new();
 
         for (indent, node) in self.traverse() {
-            let text = if evaled {
-                node.value.evaled.clone().unwrap_or(node.value.raw.clone())
-            } else {
-                node.value.raw.clone()
-            };
+            let text = node.rendered_text(self, evaled);
 
             str.push_str(&format!("{}{}\n", repeat(indent, String::from("    ")), text));
         }
@@ -334,19 +461,19 @@ impl TreeNode {
         str
     }
 
-    fn export_attributes(&self) -> String {
-        let mut str = String::new();
-
-        for attr in &self.value.attributes {
-            use node::Attribute::*;
-            match attr {
-                &String(ref k, ref v) => str = format!("{}{}={:?};", str, k, v),
-                &Number(ref k, ref v) => str = format!("{}{}={};", str, k, v),
-                &Boolean(ref k, true) => str = format!("{}{}=T;", str, k),
-                &Boolean(ref k, false) => str = format!("{}{}=F;", str, k),
-            }
+    /// An outline with only the node `uuid` and its descendants, down to `depth` levels below it
+    /// if given, to export part of the outline. With the root, the whole outline, down to `depth`.
+    pub fn focus(&self, uuid: Uuid, depth: Option<usize>) -> Option<TreeNode> {
+        let n = self.subtree(uuid, depth)?;
+        if n.uuid == self.uuid {
+            return Some(n);
         }
+        let mut tree = tree::Tree::new_tree(self.value.clone());
+        tree.set_children(vec![n]);
+        Some(tree)
+    }
 
-        str
+    pub fn export_attributes(&self) -> String {
+        self.value.attributes.iter().map(|attr| attr.export()).collect()
     }
 }
@@ -0,0 +1,562 @@
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use uuid::Uuid;
+
+use node::{Attribute, Node, TreeNode};
+use reader::{escape, unescape, read_attributes};
+use write_file;
+
+/* Tree mutations as invertible operations, and a log of them for undo/redo.
+ *
+ * Positions are given as the parent of a node and the sibling right before it (`None` when it's
+ * the first child), so that applying the inverse of an operation puts everything back where it
+ * was.
+ *
+ * The log can be saved to a text file, one operation per line (tab-separated):
+ *     <done|undone> insert <parent> <after|-> <node count>
+ *     <done|undone> delete <parent> <after|-> <node count>
+ *     <done|undone> move <uuid> <from parent> <from after|-> <to parent> <to after|->
+ *     <done|undone> set-raw <uuid> <old> <new>
+ *     <done|undone> set-script <uuid> <old|-> <new|->
+ *     <done|undone> set-attr <uuid> <name> <index> <old|-> <new|->
+ * `insert` and `delete` are followed by the lines of the subtree, in document order:
+ *     <depth> <uuid> <attributes> <raw> [<script>]
+ */
+
+const HEADER: &str = "sofer-log 1";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    Insert { parent: Uuid, after: Option<Uuid>, node: TreeNode },
+    Delete { parent: Uuid, after: Option<Uuid>, node: TreeNode },
+    Move { uuid: Uuid, from: (Uuid, Option<Uuid>), to: (Uuid, Option<Uuid>) },
+    SetRaw { uuid: Uuid, old: String, new: String },
+    SetScript { uuid: Uuid, old: Option<String>, new: Option<String> },
+    /// `index` is where the attribute is, or goes if it's missing, among the node's attributes.
+    SetAttr { uuid: Uuid, name: String, index: usize, old: Option<Attribute>, new: Option<Attribute> },
+}
+
+impl Operation {
+    /// Inserts `node` as the last child of `parent`.
+    pub fn append(tree: &TreeNode, parent: Uuid, node: TreeNode) -> Option<Operation> {
+        let after = tree.find(parent)?.children().last().map(|n| n.uuid);
+        Some(Operation::Insert { parent, after, node })
+    }
+
+    /// Inserts `node` right after `sibling`.
+    pub fn insert_next_to(tree: &TreeNode, sibling: Uuid, node: TreeNode) -> Option<Operation> {
+        let (parent, _) = tree.position(sibling)?;
+        Some(Operation::Insert { parent, after: Some(sibling), node })
+    }
+
+    pub fn delete(tree: &TreeNode, uuid: Uuid) -> Option<Operation> {
+        let (parent, after) = tree.position(uuid)?;
+        let mut node = tree.find(uuid)?.clone();
+        node.next_sibling = None;
+        Some(Operation::Delete { parent, after, node })
+    }
+
+    pub fn move_to(tree: &TreeNode, uuid: Uuid, parent: Uuid, after: Option<Uuid>) -> Option<Operation> {
+        let from = tree.position(uuid)?;
+        Some(Operation::Move { uuid, from, to: (parent, after) })
+    }
+
+    pub fn set_raw(tree: &TreeNode, uuid: Uuid, new: String) -> Option<Operation> {
+        let old = tree.find(uuid)?.value.raw.clone();
+        Some(Operation::SetRaw { uuid, old, new })
+    }
+
+    /// Sets the script of the node, or removes it if `new` is `None`.
+    pub fn set_script(tree: &TreeNode, uuid: Uuid, new: Option<String>) -> Option<Operation> {
+        let old = tree.find(uuid)?.value.script.clone();
+        Some(Operation::SetScript { uuid, old, new })
+    }
+
+    /// Sets the attribute `name`, or removes it if `new` is `None`.
+    pub fn set_attr(tree: &TreeNode, uuid: Uuid, name: &str, new: Option<Attribute>) -> Option<Operation> {
+        let attributes = &tree.find(uuid)?.value.attributes;
+        let index = attributes.iter().position(|attr| attr.name() == name);
+        let old = index.map(|i| attributes[i].clone());
+        let index = index.unwrap_or(attributes.len());
+        Some(Operation::SetAttr { uuid, name: name.into(), index, old, new })
+    }
+
+    pub fn invert(&self) -> Operation {
+        match self.clone() {
+            Operation::Insert { parent, after, node } => Operation::Delete { parent, after, node },
+            Operation::Delete { parent, after, node } => Operation::Insert { parent, after, node },
+            Operation::Move { uuid, from, to } => Operation::Move { uuid, from: to, to: from },
+            Operation::SetRaw { uuid, old, new } => Operation::SetRaw { uuid, old: new, new: old },
+            Operation::SetScript { uuid, old, new } => Operation::SetScript { uuid, old: new, new: old },
+            Operation::SetAttr { uuid, name, index, old, new } => Operation::SetAttr { uuid, name, index, old: new, new: old },
+        }
+    }
+
+    /// Applies the operation to `tree`. Returns `false`, leaving `tree` untouched, if it can't be
+    /// applied (e.g. the nodes it refers to don't exist).
+    pub fn apply(&self, tree: &mut TreeNode) -> bool {
+        match *self {
+            Operation::Insert { parent, after, ref node } => {
+                tree.find(node.uuid).is_none() && tree.insert_at(parent, after, node.clone())
+            }
+            Operation::Delete { parent, ref node, .. } => {
+                let is_child = tree.find_parent(node.uuid).map(|p| p.uuid == parent).unwrap_or(false);
+                is_child && tree.remove(node.uuid).is_some()
+            }
+            Operation::Move { uuid, from, to } => {
+                if tree.position(uuid).map(|(parent, _)| parent != from.0).unwrap_or(true) {
+                    return false;
+                }
+                let removed = match tree.remove(uuid) {
+                    Some(removed) => removed,
+                    None => return false,
+                };
+                if removed.find(to.0).is_none() && tree.insert_at(to.0, to.1, removed.clone()) {
+                    true
+                } else {
+                    tree.insert_at(from.0, from.1, removed);
+                    false
+                }
+            }
+            Operation::SetRaw { uuid, ref new, .. } => {
+                match tree.find_mut(uuid) {
+                    Some(n) => {
+                        n.value.raw = new.clone();
+                        n.value.evaled = None;
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Operation::SetScript { uuid, ref new, .. } => {
+                match tree.find_mut(uuid) {
+                    Some(n) => {
+                        n.value.script = new.clone();
+                        n.value.evaled = None;
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Operation::SetAttr { uuid, ref name, index, ref new, .. } => {
+                match tree.find_mut(uuid) {
+                    Some(n) => {
+                        let attributes = &mut n.value.attributes;
+                        match (attributes.iter().position(|attr| attr.name() == name), new) {
+                            (Some(i), Some(new)) => attributes[i] = new.clone(),
+                            (Some(i), &None) => { attributes.remove(i); }
+                            (None, Some(new)) => {
+                                let index = index.min(attributes.len());
+                                attributes.insert(index, new.clone());
+                            }
+                            (None, &None) => (),
+                        }
+                        true
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OperationLog {
+    done: Vec<Operation>,
+    undone: Vec<Operation>,
+    /// What was applied to the tree through this log since it was created or loaded, undos
+    /// included. It isn't saved.
+    applied: Vec<Operation>,
+}
+
+impl PartialEq for OperationLog {
+    fn eq(&self, other: &OperationLog) -> bool {
+        self.done == other.done && self.undone == other.undone
+    }
+}
+
+impl OperationLog {
+    pub fn new() -> OperationLog {
+        OperationLog {
+            done: Vec::new(),
+            undone: Vec::new(),
+            applied: Vec::new(),
+        }
+    }
+
+    /// Applies `operation` and records it. Recording a new operation forgets the undone ones.
+    pub fn apply(&mut self, tree: &mut TreeNode, operation: Operation) -> bool {
+        if operation.apply(tree) {
+            self.applied.push(operation.clone());
+            self.done.push(operation);
+            self.undone.clear();
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.done.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.undone.is_empty()
+    }
+
+    pub fn undo(&mut self, tree: &mut TreeNode) -> bool {
+        match self.done.pop() {
+            Some(operation) => {
+                let inverse = operation.invert();
+                if inverse.apply(tree) {
+                    self.applied.push(inverse);
+                    self.undone.push(operation);
+                    true
+                } else {
+                    self.done.push(operation);
+                    false
+                }
+            }
+            None => false,
+        }
+    }
+
+    pub fn redo(&mut self, tree: &mut TreeNode) -> bool {
+        match self.undone.pop() {
+            Some(operation) => {
+                if operation.apply(tree) {
+                    self.applied.push(operation.clone());
+                    self.done.push(operation);
+                    true
+                } else {
+                    self.undone.push(operation);
+                    false
+                }
+            }
+            None => false,
+        }
+    }
+
+    pub fn applied(&self) -> &[Operation] {
+        &self.applied
+    }
+
+    /// Reads a saved log. A missing file is an empty log.
+    pub fn load(path: &Path) -> io::Result<OperationLog> {
+        let mut str = String::new();
+        match fs::File::open(path) {
+            Ok(mut f) => { f.read_to_string(&mut str)?; }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(OperationLog::new()),
+            Err(err) => return Err(err),
+        }
+        OperationLog::parse(&str)
+            .ok_or(io::Error::new(io::ErrorKind::InvalidData, format!("Malformed log {}", path.display())))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut str = String::from(HEADER);
+        str.push('\n');
+        for operation in &self.done {
+            write_operation(&mut str, "done", operation);
+        }
+        for operation in &self.undone {
+            write_operation(&mut str, "undone", operation);
+        }
+
+        write_file(path, &str)
+    }
+
+    fn parse(str: &str) -> Option<OperationLog> {
+        let mut lines = str.lines();
+        if lines.next() != Some(HEADER) {
+            return None;
+        }
+
+        let mut log = OperationLog::new();
+        while lines.clone().next().is_some() {
+            match read_operation(&mut lines)? {
+                ("done", operation) => log.done.push(operation),
+                ("undone", operation) => log.undone.push(operation),
+                _ => return None,
+            }
+        }
+        Some(log)
+    }
+}
+
+/// Reads the next operation of a log, with the state it's in (the first field of its line).
+/// Returns `None` at the end of the lines or if the operation is malformed.
+pub fn read_operation<'a, I>(lines: &mut I) -> Option<(&'a str, Operation)>
+    where I: Iterator<Item = &'a str> {
+    let line = lines.next()?;
+    let fields = line.split('\t').collect::<Vec<_>>();
+    if fields.len() < 2 {
+        return None;
+    }
+    let uuid = |i: usize| fields.get(i).and_then(|f| Uuid::parse_str(f).ok());
+    let optional_uuid = |i: usize| match fields.get(i) {
+        Some(&"-") => Some(None),
+        _ => uuid(i).map(Some),
+    };
+    let script = |i: usize| match fields.get(i) {
+        Some(&"-") => Some(None),
+        Some(f) => Some(Some(unescape(f))),
+        None => None,
+    };
+    let attribute = |i: usize| match fields.get(i) {
+        Some(&"-") => Some(None),
+        Some(f) => read_attributes(&unescape(f)).ok()?.into_iter().next().map(Some),
+        None => None,
+    };
+
+    let operation = match (fields[1], fields.len()) {
+        ("insert", 5) | ("delete", 5) => {
+            let count = fields[4].parse().ok()?;
+            let node = read_subtree(lines, count)?;
+            let (parent, after) = (uuid(2)?, optional_uuid(3)?);
+            if fields[1] == "insert" {
+                Operation::Insert { parent, after, node }
+            } else {
+                Operation::Delete { parent, after, node }
+            }
+        }
+        ("move", 7) => Operation::Move {
+            uuid: uuid(2)?,
+            from: (uuid(3)?, optional_uuid(4)?),
+            to: (uuid(5)?, optional_uuid(6)?),
+        },
+        ("set-raw", 5) => Operation::SetRaw {
+            uuid: uuid(2)?,
+            old: unescape(fields[3]),
+            new: unescape(fields[4]),
+        },
+        ("set-script", 5) => Operation::SetScript {
+            uuid: uuid(2)?,
+            old: script(3)?,
+            new: script(4)?,
+        },
+        ("set-attr", 7) => Operation::SetAttr {
+            uuid: uuid(2)?,
+            name: unescape(fields[3]),
+            index: fields[4].parse().ok()?,
+            old: attribute(5)?,
+            new: attribute(6)?,
+        },
+        _ => return None,
+    };
+
+    Some((fields[0], operation))
+}
+
+fn optional_uuid_field(uuid: Option<Uuid>) -> String {
+    match uuid {
+        Some(uuid) => uuid.to_string(),
+        None => "-".into(),
+    }
+}
+
+fn script_field(script: &Option<String>) -> String {
+    match *script {
+        Some(ref script) => escape(script),
+        None => "-".into(),
+    }
+}
+
+fn attribute_field(attr: &Option<Attribute>) -> String {
+    match *attr {
+        Some(ref attr) => escape(&attr.export()),
+        None => "-".into(),
+    }
+}
+
+fn write_subtree(str: &mut String, n: &TreeNode, depth: usize) {
+    str.push_str(&format!("{}\t{}\t{}\t{}", depth, n.uuid, escape(&n.export_attributes()), escape(&n.value.raw)));
+    if let Some(ref script) = n.value.script {
+        str.push_str(&format!("\t{}", escape(script)));
+    }
+    str.push('\n');
+    for child in n.children() {
+        write_subtree(str, child, depth + 1);
+    }
+}
+
+fn read_subtree<'a, I>(lines: &mut I, count: usize) -> Option<TreeNode>
+    where I: Iterator<Item = &'a str> {
+    // Nodes still open, with their depth. Each one is added to its parent when closed.
+    let mut open: Vec<(usize, TreeNode)> = Vec::new();
+    let close = |open: &mut Vec<(usize, TreeNode)>| {
+        let (_, n) = open.pop().unwrap();
+        match open.last_mut() {
+            Some(&mut (_, ref mut parent)) => {
+                let mut children = parent.get_children();
+                children.push(n);
+                parent.set_children(children);
+                None
+            }
+            None => Some(n),
+        }
+    };
+
+    for _ in 0..count {
+        let fields = lines.next()?.split('\t').collect::<Vec<_>>();
+        if fields.len() != 4 && fields.len() != 5 {
+            return None;
+        }
+        let depth: usize = fields[0].parse().ok()?;
+        if depth > open.len() || (depth == 0 && !open.is_empty()) {
+            return None;
+        }
+        while open.len() > depth {
+            close(&mut open);
+        }
+        open.push((depth, TreeNode {
+            value: Node::with_script(
+                unescape(fields[3]),
+                fields.get(4).map(|script| unescape(script)),
+                read_attributes(&unescape(fields[2])).ok()?,
+            ),
+            uuid: Uuid::parse_str(fields[1]).ok()?,
+            first_child: None,
+            next_sibling: None,
+        }));
+    }
+
+    let mut root = None;
+    while !open.is_empty() {
+        root = close(&mut open);
+    }
+    root
+}
+
+pub fn write_operation(str: &mut String, state: &str, operation: &Operation) {
+    match operation {
+        &Operation::Insert { parent, after, ref node } | &Operation::Delete { parent, after, ref node } => {
+            let kind = match operation {
+                &Operation::Insert { .. } => "insert",
+                _ => "delete",
+            };
+            str.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\n",
+                state, kind, parent, optional_uuid_field(after), node.nodes().len()
+            ));
+            write_subtree(str, node, 0);
+        }
+        &Operation::Move { uuid, from, to } => str.push_str(&format!(
+            "{}\tmove\t{}\t{}\t{}\t{}\t{}\n",
+            state, uuid, from.0, optional_uuid_field(from.1), to.0, optional_uuid_field(to.1)
+        )),
+        &Operation::SetRaw { uuid, ref old, ref new } => str.push_str(&format!(
+            "{}\tset-raw\t{}\t{}\t{}\n",
+            state, uuid, escape(old), escape(new)
+        )),
+        &Operation::SetScript { uuid, ref old, ref new } => str.push_str(&format!(
+            "{}\tset-script\t{}\t{}\t{}\n",
+            state, uuid, script_field(old), script_field(new)
+        )),
+        &Operation::SetAttr { uuid, ref name, index, ref old, ref new } => str.push_str(&format!(
+            "{}\tset-attr\t{}\t{}\t{}\t{}\t{}\n",
+            state, uuid, escape(name), index, attribute_field(old), attribute_field(new)
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tree::Tree;
+
+    fn tree() -> (TreeNode, Vec<Uuid>) {
+        let mut tree = Tree::new_tree(Node::new("".into(), vec![]));
+        let first = Tree::new_child(Node::new("first".into(), vec![Attribute::Number("n".into(), 1.0)]));
+        let second = Tree::new_child(Node::new("second".into(), vec![]));
+        let second_first = Tree::new_child(Node::new("second first".into(), vec![]));
+        tree.insert(Uuid::nil(), first.clone());
+        tree.insert(Uuid::nil(), second.clone());
+        tree.insert(second.uuid, second_first.clone());
+        (tree, vec![first.uuid, second.uuid, second_first.uuid])
+    }
+
+    #[test]
+    fn undo_redo() {
+        let (mut tree, uuids) = tree();
+        let original = tree.clone();
+        let mut log = OperationLog::new();
+
+        let new = Tree::new_child(Node::new("new".into(), vec![]));
+        let operations = vec![
+            Operation::append(&tree, uuids[0], new.clone()).unwrap(),
+            Operation::move_to(&tree, uuids[1], uuids[0], Some(new.uuid)).unwrap(),
+            Operation::set_raw(&tree, uuids[2], "edited".into()).unwrap(),
+            Operation::set_attr(&tree, uuids[0], "n", Some(Attribute::Boolean("n".into(), true))).unwrap(),
+            Operation::set_attr(&tree, uuids[1], "m", Some(Attribute::String("m".into(), "x".into()))).unwrap(),
+        ];
+        for operation in operations {
+            assert!(log.apply(&mut tree, operation));
+        }
+        let delete = Operation::delete(&tree, new.uuid).unwrap();
+        assert!(log.apply(&mut tree, delete));
+
+        let edited = tree.clone();
+        assert_eq!(
+            edited.traverse().iter().map(|&(depth, ref n)| (depth, n.value.raw.as_ref())).collect::<Vec<(i32, &str)>>(),
+            vec![(0, ""), (1, "first"), (2, "second"), (3, "edited")]
+        );
+
+        while log.can_undo() {
+            assert!(log.undo(&mut tree));
+        }
+        assert_eq!(tree, original);
+        while log.can_redo() {
+            assert!(log.redo(&mut tree));
+        }
+        assert_eq!(tree, edited);
+    }
+
+    #[test]
+    fn undo_attribute_removal() {
+        let (mut tree, uuids) = tree();
+        let mut log = OperationLog::new();
+        let operation = Operation::set_attr(&tree, uuids[0], "m", Some(Attribute::Boolean("m".into(), true))).unwrap();
+        assert!(log.apply(&mut tree, operation));
+        let before = tree.clone();
+        let operation = Operation::set_attr(&tree, uuids[0], "n", None).unwrap();
+        assert!(log.apply(&mut tree, operation));
+        assert!(log.undo(&mut tree));
+        assert_eq!(tree, before);
+    }
+
+    #[test]
+    fn invalid_operations() {
+        let (mut tree, uuids) = tree();
+        let original = tree.clone();
+        let into_itself = Operation::move_to(&tree, uuids[1], uuids[2], None).unwrap();
+        assert!(!into_itself.apply(&mut tree));
+        assert_eq!(tree, original);
+        assert!(Operation::delete(&tree, Uuid::new_v4()).is_none());
+        let existing = Operation::Insert { parent: uuids[0], after: None, node: tree.find(uuids[2]).unwrap().clone() };
+        assert!(!existing.apply(&mut tree));
+    }
+
+    #[test]
+    fn log_roundtrip() {
+        let (mut tree, uuids) = tree();
+        let mut log = OperationLog::new();
+        let subtree = Operation::delete(&tree, uuids[1]).unwrap();
+        let operations = vec![
+            Operation::set_raw(&tree, uuids[0], "tab\tand\nnewline".into()).unwrap(),
+            Operation::set_attr(&tree, uuids[0], "n", None).unwrap(),
+            Operation::move_to(&tree, uuids[2], Uuid::nil(), Some(uuids[0])).unwrap(),
+        ];
+        for operation in operations {
+            assert!(log.apply(&mut tree, operation));
+        }
+        log.undone.push(subtree);
+
+        let path = ::std::env::temp_dir().join(format!("sofer-log-test-{}", Uuid::new_v4()));
+        log.save(&path).unwrap();
+        assert_eq!(OperationLog::load(&path).unwrap(), log);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,72 @@
+use std::fmt;
+use regex::Regex;
+
+/* Regular expressions for queries, with the syntax of the `regex` crate, which matches in linear
+ * time. Patterns are compared by their text.
+ */
+
+#[derive(Clone)]
+pub struct Pattern {
+    regex: Regex,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Pattern, String> {
+        Regex::new(pattern).map(|regex| Pattern { regex }).map_err(|err| err.to_string())
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.regex.is_match(text)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.regex.as_str() == other.regex.as_str()
+    }
+}
+
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Pattern({:?})", self.regex.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Pattern::new(pattern).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn pattern_matching() {
+        assert!(matches("bug", "a bug here"));
+        assert!(!matches("^bug", "a bug here"));
+        assert!(matches("^a b.g", "a bug here"));
+        assert!(matches("here$", "a bug here"));
+        assert!(matches("colou?r", "color"));
+        assert!(matches("colou?r", "colour"));
+        assert!(matches("^\\d+-\\d+$", "2024-10"));
+        assert!(!matches("^\\d+-\\d+$", "2024-1a"));
+        assert!(matches("^(todo|doing)$", "doing"));
+        assert!(!matches("^(todo|doing)$", "done"));
+        assert!(matches("^[a-c_]*x", "ab_cx"));
+        assert!(matches("[^a-z]", "abc1"));
+        assert!(!matches("[^a-z]", "abc"));
+        assert!(matches("a.*b.*c", "axxbyyc"));
+        assert!(matches("(ab)+$", "xababab"));
+        assert!(matches("ñ+e", "ññe"));
+        assert!(!matches("^(a*)*b$", &"a".repeat(64)));
+    }
+
+    #[test]
+    fn pattern_errors() {
+        assert!(Pattern::new("(ab").is_err());
+        assert!(Pattern::new("ab)").is_err());
+        assert!(Pattern::new("*a").is_err());
+        assert!(Pattern::new("[z-a]").is_err());
+        assert!(Pattern::new("[ab").is_err());
+    }
+}
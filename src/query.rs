@@ -0,0 +1,572 @@
+use uuid::Uuid;
+
+use date;
+use date::Date;
+use node::{Attribute, TreeNode};
+use pattern::Pattern;
+use tags;
+
+/* Query language:
+ *
+ *     query   := or
+ *     or      := and ("or" and)*
+ *     and     := not ("and" not)*
+ *     not     := "not" not | "(" query ")" | "under" not | test
+ *     test    := "has" NAME
+ *              | "text" ("=" | "!=" | "contains" | "~") STRING
+ *              | "depth" OP NUMBER
+ *              | "uuid" ("=" | "!=") STRING
+ *              | "tag" STRING
+ *              | NAME OP value
+ *              | NAME "~" STRING
+ *     OP      := "=" | "!=" | "<" | "<=" | ">" | ">="
+ *     value   := STRING | NUMBER | "true" | "false"
+ *              | ("today" | "week_start" | "week_end") [("+" | "-") NUMBER]
+ *
+ * `~` matches a regular expression (see `pattern`). `under q` holds when some ancestor matches
+ * `q`. `tag "#work"` holds for nodes with the tag (see `tags`), `tag "work"` being the same.
+ * Top-level nodes have depth 1. Strings that are dates, `YYYY-MM-DD` with an optional time, are
+ * compared as dates, by day unless both have a time, so `due >= today and due <= week_end`
+ * finds what's due this week, at any time of its last day.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn holds<T: PartialOrd>(&self, a: T, b: T) -> bool {
+        match *self {
+            Op::Eq => a == b,
+            Op::Ne => a != b,
+            Op::Lt => a < b,
+            Op::Le => a <= b,
+            Op::Gt => a > b,
+            Op::Ge => a >= b,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Number(f32),
+    Boolean(bool),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Query {
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+    Under(Box<Query>),
+    Has(String),
+    Compare(String, Op, Value),
+    AttributeMatches(String, Pattern),
+    Text(Op, String),
+    TextContains(String),
+    TextMatches(Pattern),
+    Depth(Op, usize),
+    Uuid(Op, Uuid),
+    Tag(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Name(String),
+    String(String),
+    Number(f32),
+    Op(&'static str),
+    LParen,
+    RParen,
+}
+
+fn tokenize(str: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = str.chars().peekable();
+    loop {
+        let c = match chars.next() {
+            Some(c) => c,
+            None => return Ok(tokens),
+        };
+        match c {
+            ' ' | '\t' | '\n' => (),
+            '(' => tokens.push(Token::LParen),
+            ')' => tokens.push(Token::RParen),
+            '=' => tokens.push(Token::Op("=")),
+            '~' => tokens.push(Token::Op("~")),
+            '+' => tokens.push(Token::Op("+")),
+            '-' => tokens.push(Token::Op("-")),
+            '!' | '<' | '>' => {
+                let op = if chars.peek() == Some(&'=') {
+                    chars.next();
+                    match c { '!' => "!=", '<' => "<=", _ => ">=" }
+                } else {
+                    match c { '!' => return Err("expected '=' after '!'".into()), '<' => "<", _ => ">" }
+                };
+                tokens.push(Token::Op(op));
+            }
+            '"' => {
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => string.push(c),
+                            None => return Err("unclosed string".into()),
+                        },
+                        Some(c) => string.push(c),
+                        None => return Err("unclosed string".into()),
+                    }
+                }
+                tokens.push(Token::String(string));
+            }
+            c if c.is_ascii_digit() => {
+                let mut number = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_digit() || c == '.' {
+                        number.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                match number.parse() {
+                    Ok(x) => tokens.push(Token::Number(x)),
+                    Err(_) => return Err(format!("invalid number \"{}\"", number)),
+                }
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut name = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_alphanumeric() || c == '_' || c == '.' {
+                        name.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Name(name));
+            }
+            c => return Err(format!("unexpected '{}'", c)),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        self.peek() == Some(&Token::Name(keyword.into()))
+    }
+
+    fn or(&mut self) -> Result<Query, String> {
+        let mut query = self.and()?;
+        while self.is_keyword("or") {
+            self.next();
+            query = Query::Or(Box::new(query), Box::new(self.and()?));
+        }
+        Ok(query)
+    }
+
+    fn and(&mut self) -> Result<Query, String> {
+        let mut query = self.not()?;
+        while self.is_keyword("and") {
+            self.next();
+            query = Query::And(Box::new(query), Box::new(self.not()?));
+        }
+        Ok(query)
+    }
+
+    fn not(&mut self) -> Result<Query, String> {
+        match self.next() {
+            Some(Token::Name(ref name)) if name == "not" => Ok(Query::Not(Box::new(self.not()?))),
+            Some(Token::Name(ref name)) if name == "under" => Ok(Query::Under(Box::new(self.not()?))),
+            Some(Token::LParen) => {
+                let query = self.or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(query),
+                    _ => Err("expected ')'".into()),
+                }
+            }
+            Some(Token::Name(name)) => self.test(name),
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err("unexpected end of query".into()),
+        }
+    }
+
+    fn op(&mut self) -> Result<Op, String> {
+        match self.next() {
+            Some(Token::Op("=")) => Ok(Op::Eq),
+            Some(Token::Op("!=")) => Ok(Op::Ne),
+            Some(Token::Op("<")) => Ok(Op::Lt),
+            Some(Token::Op("<=")) => Ok(Op::Le),
+            Some(Token::Op(">")) => Ok(Op::Gt),
+            Some(Token::Op(">=")) => Ok(Op::Ge),
+            _ => Err("expected a comparison operator".into()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::String(string)) => Ok(string),
+            _ => Err("expected a string".into()),
+        }
+    }
+
+    fn pattern(&mut self) -> Result<Pattern, String> {
+        let string = self.string()?;
+        Pattern::new(&string).map_err(|err| format!("invalid pattern \"{}\": {}", string, err))
+    }
+
+    fn test(&mut self, name: String) -> Result<Query, String> {
+        match name.as_ref() {
+            "has" => match self.next() {
+                Some(Token::Name(name)) => Ok(Query::Has(name)),
+                _ => Err("expected an attribute name after \"has\"".into()),
+            },
+            "text" => {
+                if self.is_keyword("contains") {
+                    self.next();
+                    Ok(Query::TextContains(self.string()?))
+                } else if self.peek() == Some(&Token::Op("~")) {
+                    self.next();
+                    Ok(Query::TextMatches(self.pattern()?))
+                } else {
+                    match self.op()? {
+                        op @ Op::Eq | op @ Op::Ne => Ok(Query::Text(op, self.string()?)),
+                        _ => Err("text can only be compared with = and !=".into()),
+                    }
+                }
+            }
+            "depth" => {
+                let op = self.op()?;
+                match self.next() {
+                    Some(Token::Number(x)) if x >= 0.0 => Ok(Query::Depth(op, x as usize)),
+                    _ => Err("expected a depth".into()),
+                }
+            }
+            "uuid" => {
+                let op = match self.op()? {
+                    op @ Op::Eq | op @ Op::Ne => op,
+                    _ => return Err("uuid can only be compared with = and !=".into()),
+                };
+                let string = self.string()?;
+                match Uuid::parse_str(&string) {
+                    Ok(uuid) => Ok(Query::Uuid(op, uuid)),
+                    Err(_) => Err(format!("invalid UUID \"{}\"", string)),
+                }
+            }
+            // An attribute can also be named `tag`.
+            "tag" if self.peek().map(|t| matches!(t, Token::String(_))).unwrap_or(false) =>
+                Ok(Query::Tag(tags::normalize(&self.string()?))),
+            _ => {
+                if self.peek() == Some(&Token::Op("~")) {
+                    self.next();
+                    return Ok(Query::AttributeMatches(name, self.pattern()?));
+                }
+                let op = self.op()?;
+                let value = self.value()?;
+                Ok(Query::Compare(name, op, value))
+            }
+        }
+    }
+
+    /// `+` or `-` and the number after it.
+    fn signed_number(&mut self, what: &str) -> Result<f32, String> {
+        let sign = match self.next() {
+            Some(Token::Op("-")) => -1.0,
+            _ => 1.0,
+        };
+        match self.next() {
+            Some(Token::Number(x)) => Ok(sign * x),
+            _ => Err(format!("expected {}", what)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some(&Token::Op("+")) | Some(&Token::Op("-")) => return self.signed_number("a number").map(Value::Number),
+            _ => (),
+        }
+        match self.next() {
+            Some(Token::String(string)) => Ok(Value::String(string)),
+            Some(Token::Number(x)) => Ok(Value::Number(x)),
+            Some(Token::Name(name)) => {
+                let today = Date::today();
+                let date = match name.as_ref() {
+                    "true" => return Ok(Value::Boolean(true)),
+                    "false" => return Ok(Value::Boolean(false)),
+                    "today" => today,
+                    "week_start" => today.add_days(-(today.weekday() as i64)),
+                    "week_end" => today.add_days(6 - today.weekday() as i64),
+                    _ => return Err(format!("unknown value \"{}\"", name)),
+                };
+                let offset = match self.peek() {
+                    Some(&Token::Op("+")) | Some(&Token::Op("-")) => self.signed_number("a number of days")? as i64,
+                    _ => 0,
+                };
+                Ok(Value::String(date.add_days(offset).to_string()))
+            }
+            _ => Err("expected a value".into()),
+        }
+    }
+}
+
+/// Compares `a` and `b` as dates if both are dates, by day unless both have a time, or else as
+/// text.
+fn compare_strings(op: Op, a: &str, b: &str) -> bool {
+    match (Date::parse(a), Date::parse(b)) {
+        (Some(a_day), Some(b_day)) => {
+            let times = if a.len() > 10 && b.len() > 10 {
+                date::parse_timestamp(a).and_then(|a| date::parse_timestamp(b).map(|b| (a, b)))
+            } else {
+                None
+            };
+            match times {
+                Some((a, b)) => op.holds(a, b),
+                None => op.holds(a_day, b_day),
+            }
+        }
+        _ => op.holds(a, b),
+    }
+}
+
+/// The raw text of the node and, if it has been evaluated, its evaled text.
+fn texts(n: &TreeNode) -> Vec<&String> {
+    let mut texts = vec![&n.value.raw];
+    if let Some(ref evaled) = n.value.evaled {
+        texts.push(evaled);
+    }
+    texts
+}
+
+impl Query {
+    pub fn parse(str: &str) -> Result<Query, String> {
+        let mut parser = Parser {
+            tokens: tokenize(str)?,
+            pos: 0,
+        };
+        let query = parser.or()?;
+        match parser.next() {
+            None => Ok(query),
+            Some(token) => Err(format!("unexpected {:?}", token)),
+        }
+    }
+
+    /// `ancestors` goes from the top-level ancestor of `n` to its parent; the (nil) root of the
+    /// outline isn't included.
+    pub fn matches(&self, n: &TreeNode, ancestors: &[&TreeNode]) -> bool {
+        match *self {
+            Query::And(ref a, ref b) => a.matches(n, ancestors) && b.matches(n, ancestors),
+            Query::Or(ref a, ref b) => a.matches(n, ancestors) || b.matches(n, ancestors),
+            Query::Not(ref q) => !q.matches(n, ancestors),
+            Query::Under(ref q) => (0..ancestors.len()).any(|i| q.matches(ancestors[i], &ancestors[..i])),
+            Query::Has(ref name) => n.value.get_attribute(name).is_some(),
+            Query::Compare(ref name, op, ref value) => {
+                match (n.value.get_attribute(name), value) {
+                    (Some(Attribute::String(_, a)), Value::String(b)) => compare_strings(op, a, b),
+                    (Some(&Attribute::Number(_, a)), &Value::Number(b)) => op.holds(a, b),
+                    (Some(&Attribute::Boolean(_, a)), &Value::Boolean(b)) => op.holds(a, b),
+                    _ => false,
+                }
+            }
+            Query::AttributeMatches(ref name, ref pattern) => {
+                match n.value.get_attribute(name) {
+                    Some(Attribute::String(_, value)) => pattern.is_match(value),
+                    _ => false,
+                }
+            }
+            Query::Text(op, ref string) => {
+                let equal = texts(n).into_iter().any(|t| t == string);
+                if op == Op::Eq { equal } else { !equal }
+            }
+            Query::TextContains(ref string) =>
+                texts(n).into_iter().any(|t| t.contains(string.as_str())),
+            Query::TextMatches(ref pattern) =>
+                texts(n).into_iter().any(|t| pattern.is_match(t)),
+            Query::Depth(op, depth) => op.holds(ancestors.len() + 1, depth),
+            Query::Uuid(op, uuid) => op.holds(n.uuid, uuid),
+            Query::Tag(ref tag) => n.value.tags().contains(tag),
+        }
+    }
+}
+
+fn prune_node<'a>(n: &'a TreeNode, query: &Query, ancestors: &mut Vec<&'a TreeNode>) -> Option<TreeNode> {
+    let matches = query.matches(n, ancestors);
+    ancestors.push(n);
+    let children = n.children()
+        .filter_map(|child| prune_node(child, query, ancestors))
+        .collect::<Vec<_>>();
+    ancestors.pop();
+
+    if matches || !children.is_empty() {
+        let mut pruned = TreeNode {
+            value: n.value.clone(),
+            uuid: n.uuid,
+            first_child: None,
+            next_sibling: None,
+        };
+        pruned.set_children(children);
+        Some(pruned)
+    } else {
+        None
+    }
+}
+
+fn collect_matches<'a>(n: &'a TreeNode, query: &Query, ancestors: &mut Vec<&'a TreeNode>, matches: &mut Vec<&'a TreeNode>) {
+    if query.matches(n, ancestors) {
+        matches.push(n);
+    }
+    ancestors.push(n);
+    for child in n.children() {
+        collect_matches(child, query, ancestors, matches);
+    }
+    ancestors.pop();
+}
+
+impl TreeNode {
+    /// Nodes matching `query`, in document order.
+    pub fn query(&self, query: &Query) -> Vec<&TreeNode> {
+        let mut matches = Vec::new();
+        for child in self.children() {
+            collect_matches(child, query, &mut vec![], &mut matches);
+        }
+        matches
+    }
+
+    /// A tree whose top-level nodes are the nodes matching `query`, without their children.
+    pub fn query_flat(&self, query: &Query) -> TreeNode {
+        let mut tree = TreeNode::new_tree(self.value.clone());
+        tree.set_children(
+            self.query(query)
+                .into_iter()
+                .map(|n| TreeNode {
+                    value: n.value.clone(),
+                    uuid: n.uuid,
+                    first_child: None,
+                    next_sibling: None,
+                })
+                .collect()
+        );
+        tree
+    }
+
+    /// The tree with only the nodes matching `query` and their ancestors.
+    pub fn query_pruned(&self, query: &Query) -> TreeNode {
+        let mut tree = TreeNode::new_tree(self.value.clone());
+        tree.set_children(
+            self.children()
+                .filter_map(|child| prune_node(child, query, &mut vec![]))
+                .collect()
+        );
+        tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reader;
+
+    fn tree() -> TreeNode {
+        reader::nodes_to_tree_node(reader::read_nodes(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  Project X
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000001 status="todo";due="2024-05-07T18:00"; Fix the bug
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000001 status="done";estimate=2;tags="docs"; Write docs
+00000000-0000-0000-0000-000000000004 00000000-0000-0000-0000-000000000000  Project Y
+00000000-0000-0000-0000-000000000005 00000000-0000-0000-0000-000000000004 status="todo";estimate=5; Another bug
+"#).unwrap())
+    }
+
+    fn uuids(tree: &TreeNode, query: &str) -> Vec<u8> {
+        tree.query(&Query::parse(query).unwrap())
+            .iter()
+            .map(|n| n.uuid.as_bytes()[15])
+            .collect()
+    }
+
+    #[test]
+    fn query_parse() {
+        assert_eq!(
+            Query::parse("status = \"todo\" and not (estimate > 3 or has due)"),
+            Ok(Query::And(
+                Box::new(Query::Compare("status".into(), Op::Eq, Value::String("todo".into()))),
+                Box::new(Query::Not(Box::new(Query::Or(
+                    Box::new(Query::Compare("estimate".into(), Op::Gt, Value::Number(3.0))),
+                    Box::new(Query::Has("due".into())),
+                )))),
+            ))
+        );
+        assert_eq!(
+            Query::parse("due <= today+7"),
+            Ok(Query::Compare("due".into(), Op::Le, Value::String(Date::today().add_days(7).to_string())))
+        );
+        assert_eq!(Query::parse("due <= today + 7"), Query::parse("due <= today+7"));
+        assert_eq!(
+            Query::parse("due > week_start - 2"),
+            Ok(Query::Compare("due".into(), Op::Gt, Value::String(Date::today().add_days(-(Date::today().weekday() as i64) - 2).to_string())))
+        );
+        assert!(Query::parse("due <= today 7").is_err());
+        assert!(Query::parse("due <= today +").is_err());
+        assert_eq!(Query::parse("estimate > -1"), Ok(Query::Compare("estimate".into(), Op::Gt, Value::Number(-1.0))));
+        assert!(Query::parse("status = ").is_err());
+        assert!(Query::parse("(has due").is_err());
+        assert!(Query::parse("text ~ \"(\"").is_err());
+        assert!(Query::parse("has due due").is_err());
+        assert_eq!(Query::parse("tag \"work\""), Ok(Query::Tag("#work".into())));
+        assert_eq!(Query::parse("tag = \"work\""), Ok(Query::Compare("tag".into(), Op::Eq, Value::String("work".into()))));
+    }
+
+    #[test]
+    fn query_matches() {
+        let tree = tree();
+        assert_eq!(uuids(&tree, "status = \"todo\""), vec![2, 5]);
+        assert_eq!(uuids(&tree, "status = \"todo\" and under text = \"Project X\""), vec![2]);
+        assert_eq!(uuids(&tree, "estimate >= 2 and estimate < 5"), vec![3]);
+        assert_eq!(uuids(&tree, "text contains \"bug\""), vec![2, 5]);
+        assert_eq!(uuids(&tree, "text ~ \"^Project [XY]$\""), vec![1, 4]);
+        assert_eq!(uuids(&tree, "depth = 1"), vec![1, 4]);
+        assert_eq!(uuids(&tree, "due >= \"2024-05-01\" and due <= \"2024-05-07\""), vec![2]);
+        assert_eq!(uuids(&tree, "due = \"2024-05-07\""), vec![2]);
+        assert_eq!(uuids(&tree, "due < \"2024-05-07T12:00\""), Vec::<u8>::new());
+        assert_eq!(uuids(&tree, "under uuid = \"00000000-0000-0000-0000-000000000004\""), vec![5]);
+        assert_eq!(uuids(&tree, "not has status"), vec![1, 4]);
+        assert_eq!(uuids(&tree, "status ~ \"^d\""), vec![3]);
+        assert_eq!(uuids(&tree, "tag \"#docs\" or tag \"@home\""), vec![3]);
+    }
+
+    #[test]
+    fn query_pruned() {
+        let tree = tree();
+        let pruned = tree.query_pruned(&Query::parse("estimate = 5").unwrap());
+        assert_eq!(
+            pruned.traverse().iter().map(|&(depth, ref n)| (depth, n.uuid.as_bytes()[15])).collect::<Vec<_>>(),
+            vec![(0, 0), (1, 4), (2, 5)]
+        );
+        let flat = tree.query_flat(&Query::parse("has status").unwrap());
+        assert_eq!(
+            flat.traverse().iter().map(|&(depth, ref n)| (depth, n.uuid.as_bytes()[15])).collect::<Vec<_>>(),
+            vec![(0, 0), (1, 2), (1, 3), (1, 5)]
+        );
+    }
+}
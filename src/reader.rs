@@ -6,7 +6,8 @@ use node::*;
 
 #[derive(Debug, Clone)]
 pub struct Node {
-    content: String,
+    text: String,
+    script: Option<String>,
     attributes: Vec<Attribute>,
     uuid: Uuid,
     parent_uuid: Uuid,
@@ -14,11 +15,57 @@ pub struct Node {
 
 impl fmt::Display for Node {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{} {} {}", self.uuid, self.parent_uuid, self.content)
+        write!(f, "{} {} {}", self.uuid, self.parent_uuid, escape(&self.text))?;
+        match self.script {
+            Some(ref script) => write!(f, "\t{}", escape(script)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Splits the content of a line into its text and its script, separated by a tab.
+fn split_content(content: &str) -> (String, Option<String>) {
+    match content.find('\t') {
+        Some(i) => (unescape(&content[..i]), Some(unescape(&content[i + 1..]))),
+        None => (unescape(content), None),
+    }
+}
+
+/// Where the script starts in a text written before scripts had their own field: the index of
+/// its first `@`.
+pub fn script_start(raw: &str) -> Option<usize> {
+    raw.find('@')
+}
+
+/// Splits the content of a line written before scripts had their own field, where the script
+/// started at the first `@` and nothing was escaped.
+fn split_legacy_content(content: &str) -> (String, Option<String>) {
+    match script_start(content) {
+        Some(i) => (content[..i].into(), Some(content[i + 1..].into())),
+        None => (content.into(), None),
     }
 }
 
-pub fn read_nodes(str: &str) -> Vec<Node> {
+/// The first line of the .sofer files written since scripts have their own field.
+pub const HEADER: &str = "sofer 2";
+
+/// Reads a .sofer file. Files without `HEADER` were written with the `@` convention, and are
+/// migrated as they're read.
+pub fn read_nodes(str: &str) -> Result<Vec<Node>, String> {
+    let mut lines = str.splitn(2, '\n');
+    match lines.next().map(str::trim_end) {
+        Some(HEADER) => read_nodes_with(lines.next().unwrap_or(""), split_content),
+        Some(first) if first.starts_with("sofer ") => Err(format!("Unknown version of the sofer format: \"{}\"", first)),
+        _ => read_legacy_nodes(str),
+    }
+}
+
+/// Reads a .sofer file written with the `@` convention, to migrate it.
+pub fn read_legacy_nodes(str: &str) -> Result<Vec<Node>, String> {
+    read_nodes_with(str, split_legacy_content)
+}
+
+fn read_nodes_with(str: &str, split: fn(&str) -> (String, Option<String>)) -> Result<Vec<Node>, String> {
     let mut nodes = Vec::new();
     let mut chars = str.chars();
 
@@ -44,21 +91,24 @@ pub fn read_nodes(str: &str) -> Vec<Node> {
                     content.push(' ');
                 }
             }
+            Some('\n') if reading == 0 && uuid_string.is_empty() => (),
             Some('\n') => {
                 let uuid = match Uuid::parse_str(&uuid_string) {
                     Ok(uuid) => uuid,
-                    Err(_) => panic!("Wrong UUID: {}", uuid_string),
+                    Err(_) => return Err(format!("Wrong UUID: {}", uuid_string)),
                 };
 
                 let parent_uuid = match Uuid::parse_str(&parent_uuid_string) {
                     Ok(uuid) => uuid,
-                    Err(_) => panic!("Wrong UUID: {}", parent_uuid_string),
+                    Err(_) => return Err(format!("Wrong UUID: {}", parent_uuid_string)),
                 };
 
-                let attributes = read_attributes(&attributes_string);
+                let attributes = read_attributes(&attributes_string)?;
+                let (text, script) = split(&content);
 
                 nodes.push(Node {
-                    content: content,
+                    text,
+                    script,
                     attributes,
                     uuid,
                     parent_uuid,
@@ -81,13 +131,13 @@ pub fn read_nodes(str: &str) -> Vec<Node> {
             }
             None => {
                 sort_nodes(&mut nodes);
-                return nodes;
+                return Ok(nodes);
             }
         }
     }
 }
 
-fn read_attributes(attributes_string: &str) -> Vec<Attribute> {
+pub fn read_attributes(attributes_string: &str) -> Result<Vec<Attribute>, String> {
     let mut attributes = Vec::new();
     let mut iter = attributes_string.chars().peekable();
     let mut reading = 0;
@@ -125,14 +175,14 @@ fn read_attributes(attributes_string: &str) -> Vec<Attribute> {
                                     attributes.push(
                                         Attribute::Boolean(field, true)
                                     )
-                                } else { panic!(); }
+                                } else { return Err(format!("Wrong attribute: {}={}", field, value)); }
                             }
                             Some('F') => {
                                 if chars.nth(1) == None {
                                     attributes.push(
                                         Attribute::Boolean(field, false)
                                     )
-                                } else { panic!(); }
+                                } else { return Err(format!("Wrong attribute: {}={}", field, value)); }
                             }
                             Some(_) => {
                                 match value.parse() {
@@ -140,10 +190,10 @@ fn read_attributes(attributes_string: &str) -> Vec<Attribute> {
                                         attributes.push(
                                             Attribute::Number(field, num)
                                         ),
-                                    Err(_) => panic!(),
+                                    Err(_) => return Err(format!("Wrong attribute: {}={}", field, value)),
                                 }
                             }
-                            None => panic!(),
+                            None => return Err(format!("Attribute without a value: {}", field)),
                         }
                     }
                     field = String::new();
@@ -154,10 +204,10 @@ fn read_attributes(attributes_string: &str) -> Vec<Attribute> {
                     match reading {
                         0 => field.push(c),
                         1 => value.push(c),
-                        _ => panic!(),
+                        _ => panic!("this should not have happened"),
                     }
                 }
-                None => break attributes,
+                None => break Ok(attributes),
             }
         } else {
             match iter.next() {
@@ -168,81 +218,68 @@ fn read_attributes(attributes_string: &str) -> Vec<Attribute> {
                 Some(c) => {
                     value.push(c);
                 },
-                None => panic!(),
+                None => return Err(format!("Unterminated string in attribute: {}", field)),
             }
         }
     }
 }
 
+/// Escapes backslashes, tabs and newlines, so `str` fits in a tab-separated field.
+pub fn escape(str: &str) -> String {
+    str.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+pub fn unescape(str: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = str.chars();
+    loop {
+        match chars.next() {
+            Some('\\') => match chars.next() {
+                Some('t') => unescaped.push('\t'),
+                Some('n') => unescaped.push('\n'),
+                Some(c) => unescaped.push(c),
+                None => unescaped.push('\\'),
+            },
+            Some(c) => unescaped.push(c),
+            None => return unescaped,
+        }
+    }
+}
+
 pub fn sort_nodes(nodes: &mut Vec<Node>) {
     nodes.sort_by(|n1, n2| n1.parent_uuid.cmp(&n2.parent_uuid))
 }
 
+/// Builds the tree, keeping siblings in the order they are listed. Nodes listed before their
+/// parent, as in files that were sorted by UUID, are placed once their parent is, and nodes whose
+/// parent isn't listed at all are top-level nodes.
 pub fn nodes_to_tree_node(nodes: Vec<Node>) -> TreeNode {
-    let mut tree_nodes: Vec<TreeNode> = Vec::new();
-    nodes
+    let mut treenode = TreeNode::new_tree(node::Node::new("".into(), Vec::new()));
+    let mut pending = nodes
         .iter()
-        .inspect(|n| {
-            match nodes.iter().position(|n_| n_.uuid == n.parent_uuid) {
-                Some(idx) => {
-                    match tree_nodes.get_mut(idx) {
-                        Some(parent) => {
-                            parent.insert(
-                                n.parent_uuid,
-                                nodes_to_one_tree_node(
-                                    &nodes,
-                                    TreeNode {
-                                        value: node::Node::new(n.content.clone(), n.attributes.clone()),
-                                        uuid: n.uuid,
-                                        first_child: None,
-                                        next_sibling: None,
-                                    }
-                                )
-                            );
-                        }
-                        None => (),
-                    }
-                }
-
-                None => {
-                    tree_nodes.push(TreeNode {
-                        value: node::Node::new(n.content.clone(), n.attributes.clone()),
-                        uuid: n.uuid,
-                        first_child: None,
-                        next_sibling: None,
-                    });
-                }
-            }
+        .map(|n| {
+            let listed = nodes.iter().any(|parent| parent.uuid == n.parent_uuid);
+            let parent_uuid = if listed { n.parent_uuid } else { Uuid::nil() };
+            (parent_uuid, n)
         })
-        .collect::<Vec<&Node>>();
-
-    let mut treenode = TreeNode::new_tree(node::Node::new("".into(), Vec::new()));
-
-    for tn in tree_nodes {
-        treenode.insert(Uuid::nil(), tn);
-    }
+        .collect::<Vec<_>>();
 
-    treenode
-}
-
-fn nodes_to_one_tree_node(nodes: &Vec<Node>, tree_node: TreeNode) -> TreeNode {
-    let mut tree_node = tree_node;
-
-    nodes
-        .iter()
-        .inspect(|n| {
-            tree_node.insert(
-                n.parent_uuid,
+    loop {
+        let count = pending.len();
+        pending.retain(|&(parent_uuid, n)| {
+            !treenode.insert(
+                parent_uuid,
                 TreeNode {
-                    value: node::Node::new(n.content.clone(), n.attributes.clone()),
+                    value: node::Node::with_script(n.text.clone(), n.script.clone(), n.attributes.clone()),
                     uuid: n.uuid,
                     first_child: None,
                     next_sibling: None,
-                });
-        })
-        .collect::<Vec<&Node>>();
-
-    tree_node
+                })
+        });
+        if pending.is_empty() || pending.len() == count {
+            return treenode;
+        }
+    }
 }
 
 #[cfg(test)]
@@ -271,17 +308,19 @@ r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 cac
 00000000-0000-0000-0000-000000000014 00000000-0000-0000-0000-000000000002  Otro subnodo en el segundo nodo superior!
 "#;
         assert_eq!(
-            super::nodes_to_tree_node(super::read_nodes(text)),
+            super::nodes_to_tree_node(super::read_nodes(text).unwrap()),
             Tree {
                 value: Node {
                     raw: "".into(),
+                    script: None,
                     evaled: None,
                     attributes: vec![],
                 },
                 uuid: Uuid::parse_str("00000000-0000-0000-0000-000000000000").unwrap(),
                 first_child: Some(Box::new(Tree {
                     value: Node {
-                        raw: "caca de vaca @ function(node) return tostring(node.children[1].value.raw) end".into(),
+                        raw: "caca de vaca ".into(),
+                        script: Some(" function(node) return tostring(node.children[1].value.raw) end".into()),
                         evaled: None,
                         attributes: vec![
                             String("caca".into(), "fa".into()),
@@ -292,14 +331,16 @@ r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 cac
                     uuid: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
                     first_child: Some(Box::new(Tree {
                         value: Node {
-                            raw: "Estos son los campos de este nodo: @function(node) function tabletostring(table) local str = \"\"   for k,v in pairs(node) do str = str .. \", \" .. k .. \"=\" .. tostring(v) end return str end   return tabletostring(node) end".into(),
+                            raw: "Estos son los campos de este nodo: ".into(),
+                            script: Some("function(node) function tabletostring(table) local str = \"\"   for k,v in pairs(node) do str = str .. \", \" .. k .. \"=\" .. tostring(v) end return str end   return tabletostring(node) end".into()),
                             evaled: None,
                             attributes: vec![Number("ñeñe".into(), 231 as f32)],
                         },
                         uuid: Uuid::parse_str("00000000-0000-0000-0000-000000000003").unwrap(),
                         first_child: Some(Box::new(Tree {
                             value: Node {
-                                raw: "Este está todavía más debajo. Nodo 5. @ true".into(),
+                                raw: "Este está todavía más debajo. Nodo 5. ".into(),
+                                script: Some(" true".into()),
                                 evaled: None,
                                 attributes: vec![],
                             },
@@ -307,7 +348,8 @@ r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 cac
                             first_child: None,
                             next_sibling: Some(Box::new(Tree {
                                 value: Node {
-                                    raw: "Este está todavía más debajo. Nodo 6. @ \"ñe\"".into(),
+                                    raw: "Este está todavía más debajo. Nodo 6. ".into(),
+                                    script: Some(" \"ñe\"".into()),
                                     evaled: None,
                                     attributes: vec![],
                                 },
@@ -315,6 +357,7 @@ r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 cac
                                 first_child: Some(Box::new(Tree {
                                     value: Node {
                                         raw: "Este está todavía más debajo. Nodo 9.".into(),
+                                        script: None,
                                         evaled: None,
                                         attributes: vec![],
                                     },
@@ -325,6 +368,7 @@ r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 cac
                                 next_sibling: Some(Box::new(Tree {
                                     value: Node {
                                         raw: "Este está todavía más debajo. Nodo 7.".into(),
+                                        script: None,
                                         evaled: None,
                                         attributes: vec![],
                                     },
@@ -333,6 +377,7 @@ r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 cac
                                     next_sibling: Some(Box::new(Tree {
                                         value: Node {
                                             raw: "Este está todavía más debajo. Nodo 8.".into(),
+                                            script: None,
                                             evaled: None,
                                             attributes: vec![],
                                         },
@@ -345,7 +390,8 @@ r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 cac
                         })),
                         next_sibling: Some(Box::new(Tree {
                             value: Node {
-                                raw: "Este también. Esto nodo tiene este número de hijos @ function(node) return #node.children end".into(),
+                                raw: "Este también. Esto nodo tiene este número de hijos ".into(),
+                                script: Some(" function(node) return #node.children end".into()),
                                 evaled: None,
                                 attributes: vec![],
                             },
@@ -353,6 +399,7 @@ r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 cac
                             first_child: Some(Box::new(Tree {
                                 value: Node {
                                     raw: "Este está todavía más debajo. Nodo 10.".into(),
+                                    script: None,
                                     evaled: None,
                                     attributes: vec![],
                                 },
@@ -361,6 +408,7 @@ r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 cac
                                 next_sibling: Some(Box::new(Tree {
                                     value: Node {
                                         raw: "Este está todavía más debajo. Nodo 11.".into(),
+                                        script: None,
                                         evaled: None,
                                         attributes: vec![],
                                     },
@@ -369,6 +417,7 @@ r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 cac
                                     next_sibling: Some(Box::new(Tree {
                                         value: Node {
                                             raw: "Este está todavía más debajo. Nodo 12.".into(),
+                                            script: None,
                                             evaled: None,
                                             attributes: vec![],
                                         },
@@ -383,7 +432,8 @@ r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 cac
                     })),
                     next_sibling: Some(Box::new(Tree {
                         value: Node {
-                            raw: "Esto es lo que he dicho: @ function(node) return node.value.raw end".into(),
+                            raw: "Esto es lo que he dicho: ".into(),
+                            script: Some(" function(node) return node.value.raw end".into()),
                             evaled: None,
                             attributes: vec![Boolean("ñe".into(), true)],
                         },
@@ -391,6 +441,7 @@ r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 cac
                         first_child: Some(Box::new(Tree {
                             value: Node {
                                 raw: "Un subnodo en el segundo nodo superior!".into(),
+                                script: None,
                                 evaled: None,
                                 attributes: vec![],
                             },
@@ -399,6 +450,7 @@ r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 cac
                             next_sibling: Some(Box::new(Tree {
                                 value: Node {
                                     raw: "Otro subnodo en el segundo nodo superior!".into(),
+                                    script: None,
                                     evaled: None,
                                     attributes: vec![],
                                 },
@@ -414,4 +466,64 @@ r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 cac
             }
         );
     }
+
+    #[test]
+    fn sibling_order() {
+        let mut tree = Tree::new_tree(Node::new("".into(), vec![]));
+        let parent = Tree::new_child(Node::new("parent".into(), vec![]));
+        tree.insert(Uuid::nil(), parent.clone());
+        for raw in &["c", "b", "a", "d"] {
+            tree.insert(parent.uuid, Tree::new_child(Node::new(raw.to_string(), vec![])));
+        }
+        assert_eq!(Tree::import_from_sofer(&tree.export_to_sofer(false)).unwrap(), tree);
+
+        // Files sorted by UUID can list children before their parent.
+        let sorted = Tree::import_from_sofer(
+r#"sofer 2
+00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000003  Child
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000000  First
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000000  Second
+"#).unwrap();
+        assert_eq!(
+            sorted.traverse().iter().map(|&(depth, ref n)| (depth, n.value.raw.as_ref())).collect::<Vec<(i32, &str)>>(),
+            vec![(0, ""), (1, "First"), (1, "Second"), (2, "Child")]
+        );
+    }
+
+    #[test]
+    fn scripts() {
+        let text = "sofer 2\n00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  Mail bob@example.com\\tx: \tfunction(n) return '\\\\t' end\n";
+        let nodes = super::read_nodes(text).unwrap();
+        assert_eq!(nodes[0].text, "Mail bob@example.com\tx: ");
+        assert_eq!(nodes[0].script, Some("function(n) return '\\t' end".into()));
+
+        assert_eq!(super::script_start("Notes"), None);
+        assert_eq!(super::script_start("Count: @total"), Some(7));
+        assert_eq!(super::script_start("@sofer.get(x) @ 1"), Some(0));
+
+        let legacy = "00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  Path C:\\new\\tab @ 1 + 1\n";
+        let nodes = super::read_legacy_nodes(legacy).unwrap();
+        assert_eq!(nodes[0].text, "Path C:\\new\\tab ");
+        assert_eq!(nodes[0].script, Some(" 1 + 1".into()));
+        // Without the header, files are read the same way.
+        assert_eq!(super::read_nodes(legacy).unwrap()[0].script, Some(" 1 + 1".into()));
+        let tree = super::nodes_to_tree_node(nodes);
+        let exported = tree.export_to_sofer(false);
+        assert!(exported.starts_with("sofer 2\n"));
+        assert!(exported.contains("  Path C:\\\\new\\\\tab \t 1 + 1\n"));
+        assert_eq!(super::nodes_to_tree_node(super::read_nodes(&exported).unwrap()), tree);
+
+        assert!(super::read_nodes("sofer 3\n").is_err());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            super::read_nodes("1 00000000-0000-0000-0000-000000000000  Text\n").map(|_| ()),
+            Err("Wrong UUID: 1".into())
+        );
+        assert!(super::read_attributes("done=X;").is_err());
+        assert!(super::read_attributes("name=\"open").is_err());
+        assert_eq!(super::read_attributes("n=2;").map(|attrs| attrs.len()), Ok(1));
+    }
 }
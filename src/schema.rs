@@ -0,0 +1,319 @@
+use std::fmt;
+use uuid::Uuid;
+
+use node::{Attribute, TreeNode};
+
+/* Schemas are declared with attributes:
+ *  - `schema.<field>="<rule>";` on a node constrains the attributes of its children.
+ *  - A top-level node with `schema=T;` is a file-level section: its `schema.<field>` attributes
+ *    constrain every node in the file. The section itself isn't validated.
+ *
+ * A rule is `string`, `number`, `boolean`, `any` or a list of allowed strings separated by `|`
+ * (e.g. `todo|doing|done`). Prefixing it with `?` makes the field optional.
+ *
+ * Attributes without a rule are allowed, as other features keep their own (`status`, `clock`,
+ * ...), but the ones within a couple of letters of a declared field are reported as typos.
+ */
+
+const SCHEMA_PREFIX: &str = "schema.";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Kind {
+    String,
+    Number,
+    Boolean,
+    Any,
+    OneOf(Vec<String>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub field: String,
+    pub kind: Kind,
+    pub optional: bool,
+}
+
+impl Rule {
+    pub fn parse(field: &str, spec: &str) -> Option<Rule> {
+        let spec = spec.trim();
+        let (optional, spec) = match spec.strip_prefix('?') {
+            Some(spec) => (true, spec),
+            None => (false, spec),
+        };
+
+        let kind = match spec {
+            "" => return None,
+            "string" => Kind::String,
+            "number" => Kind::Number,
+            "boolean" => Kind::Boolean,
+            "any" => Kind::Any,
+            _ => {
+                let values = spec.split('|').map(|v| String::from(v.trim())).collect::<Vec<_>>();
+                if values.iter().any(|v| v.is_empty()) {
+                    return None;
+                }
+                Kind::OneOf(values)
+            }
+        };
+
+        Some(Rule {
+            field: field.into(),
+            kind,
+            optional,
+        })
+    }
+
+    fn check(&self, attr: &Attribute) -> Option<String> {
+        match (&self.kind, attr) {
+            (&Kind::Any, _) => None,
+            (&Kind::String, &Attribute::String(..)) => None,
+            (&Kind::Number, &Attribute::Number(..)) => None,
+            (&Kind::Boolean, &Attribute::Boolean(..)) => None,
+            (Kind::OneOf(values), Attribute::String(_, value)) => {
+                if values.contains(value) {
+                    None
+                } else {
+                    Some(format!(
+                        "attribute \"{}\" is \"{}\", expected one of {{{}}}",
+                        self.field, value, values.join(",")
+                    ))
+                }
+            }
+            (kind, attr) => Some(format!(
+                "attribute \"{}\" should be {}, found {}",
+                self.field, kind_name(kind), attribute_kind_name(attr)
+            )),
+        }
+    }
+}
+
+fn kind_name(kind: &Kind) -> &'static str {
+    match *kind {
+        Kind::String => "a string",
+        Kind::Number => "a number",
+        Kind::Boolean => "a boolean",
+        Kind::Any => "anything",
+        Kind::OneOf(_) => "a string",
+    }
+}
+
+fn attribute_kind_name(attr: &Attribute) -> &'static str {
+    match *attr {
+        Attribute::String(..) => "a string",
+        Attribute::Number(..) => "a number",
+        Attribute::Boolean(..) => "a boolean",
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Violation {
+    pub uuid: Uuid,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.uuid, self.message)
+    }
+}
+
+fn is_schema_attribute(name: &str) -> bool {
+    name == "schema" || name.starts_with(SCHEMA_PREFIX)
+}
+
+fn is_schema_section(n: &TreeNode) -> bool {
+    n.value.get_attribute("schema") == Some(&Attribute::Boolean("schema".into(), true))
+}
+
+/// Reads the `schema.<field>` declarations of a node. Malformed declarations are reported as
+/// violations of the declaring node.
+fn declared_rules(n: &TreeNode, violations: &mut Vec<Violation>) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    for attr in &n.value.attributes {
+        if !attr.name().starts_with(SCHEMA_PREFIX) {
+            continue;
+        }
+        let field = &attr.name()[SCHEMA_PREFIX.len()..];
+        match attr {
+            Attribute::String(_, spec) => {
+                match Rule::parse(field, spec) {
+                    Some(rule) => rules.push(rule),
+                    None => violations.push(Violation {
+                        uuid: n.uuid,
+                        message: format!("invalid schema rule \"{}\" for \"{}\"", spec, field),
+                    }),
+                }
+            }
+            _ => violations.push(Violation {
+                uuid: n.uuid,
+                message: format!("schema rule for \"{}\" must be a string", field),
+            }),
+        }
+    }
+    rules
+}
+
+/// Rules in `specific` override the ones in `general` with the same field.
+fn merge_rules(general: &[Rule], specific: &[Rule]) -> Vec<Rule> {
+    let mut rules = general
+        .iter()
+        .filter(|r| !specific.iter().any(|s| s.field == r.field))
+        .cloned()
+        .collect::<Vec<_>>();
+    rules.extend_from_slice(specific);
+    rules
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..b.len() + 1).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for j in 0..b.len() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == b[j] {
+                previous
+            } else {
+                1 + previous.min(row[j]).min(row[j + 1])
+            };
+            previous = current;
+        }
+    }
+    row[b.len()]
+}
+
+fn check_node(n: &TreeNode, rules: &[Rule], violations: &mut Vec<Violation>) {
+    if rules.is_empty() {
+        return;
+    }
+
+    for rule in rules {
+        match n.value.get_attribute(&rule.field) {
+            Some(attr) => {
+                if let Some(message) = rule.check(attr) {
+                    violations.push(Violation { uuid: n.uuid, message });
+                }
+            }
+            None => {
+                if !rule.optional {
+                    violations.push(Violation {
+                        uuid: n.uuid,
+                        message: format!("missing attribute \"{}\"", rule.field),
+                    });
+                }
+            }
+        }
+    }
+
+    for attr in &n.value.attributes {
+        let name = attr.name();
+        if is_schema_attribute(name) || rules.iter().any(|r| r.field == name) {
+            continue;
+        }
+        let suggestion = rules
+            .iter()
+            .map(|r| (edit_distance(name, &r.field), &r.field))
+            .filter(|&(d, _)| d <= 2)
+            .min_by_key(|&(d, _)| d);
+        if let Some((_, field)) = suggestion {
+            violations.push(Violation {
+                uuid: n.uuid,
+                message: format!("unknown attribute \"{}\" (did you mean \"{}\"?)", name, field),
+            });
+        }
+    }
+}
+
+fn validate_children(n: &TreeNode, file_rules: &[Rule], violations: &mut Vec<Violation>) {
+    let rules = merge_rules(file_rules, &declared_rules(n, violations));
+    for child in n.get_children() {
+        if is_schema_section(&child) {
+            continue;
+        }
+        check_node(&child, &rules, violations);
+        validate_children(&child, file_rules, violations);
+    }
+}
+
+impl TreeNode {
+    pub fn validate(&self) -> Vec<Violation> {
+        let mut violations = Vec::new();
+
+        let mut file_rules = Vec::new();
+        for section in self.get_children().iter().filter(|n| is_schema_section(n)) {
+            let rules = declared_rules(section, &mut violations);
+            file_rules = merge_rules(&file_rules, &rules);
+        }
+
+        validate_children(self, &file_rules, &mut violations);
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reader;
+
+    fn read(text: &str) -> TreeNode {
+        reader::nodes_to_tree_node(reader::read_nodes(text).unwrap())
+    }
+
+    #[test]
+    fn rule_parse() {
+        assert_eq!(
+            Rule::parse("status", "todo|doing|done"),
+            Some(Rule {
+                field: "status".into(),
+                kind: Kind::OneOf(vec!["todo".into(), "doing".into(), "done".into()]),
+                optional: false,
+            })
+        );
+        assert_eq!(
+            Rule::parse("estimate", "?number"),
+            Some(Rule { field: "estimate".into(), kind: Kind::Number, optional: true })
+        );
+        assert_eq!(Rule::parse("estimate", "a||b"), None);
+        assert_eq!(Rule::parse("estimate", ""), None);
+    }
+
+    #[test]
+    fn validate_children_rules() {
+        let tree = read(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 schema.status="todo|doing|done";schema.estimate="number"; Tasks
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000001 status="todo";estimate=3;completed_at="2026-10-18T10:00"; Fine
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000001 stauts="todo";estimate=3; Typo
+00000000-0000-0000-0000-000000000004 00000000-0000-0000-0000-000000000001 status="later";estimate=T; Wrong values
+00000000-0000-0000-0000-000000000005 00000000-0000-0000-0000-000000000004  Grandchildren aren't constrained
+"#);
+        let uuid = |n| Uuid::parse_str(&format!("00000000-0000-0000-0000-00000000000{}", n)).unwrap();
+        assert_eq!(
+            tree.validate(),
+            vec![
+                Violation { uuid: uuid(3), message: "missing attribute \"status\"".into() },
+                Violation { uuid: uuid(3), message: "unknown attribute \"stauts\" (did you mean \"status\"?)".into() },
+                Violation { uuid: uuid(4), message: "attribute \"status\" is \"later\", expected one of {todo,doing,done}".into() },
+                Violation { uuid: uuid(4), message: "attribute \"estimate\" should be a number, found a boolean".into() },
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_file_rules() {
+        let tree = read(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 schema=T;schema.done="?boolean"; Schema
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000000 done=T; Fine
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000002 done="yes"; Nested
+00000000-0000-0000-0000-000000000004 00000000-0000-0000-0000-000000000000 schema.done=3; Bad declaration
+"#);
+        let uuid = |n| Uuid::parse_str(&format!("00000000-0000-0000-0000-00000000000{}", n)).unwrap();
+        assert_eq!(
+            tree.validate(),
+            vec![
+                Violation { uuid: uuid(3), message: "attribute \"done\" should be a boolean, found a string".into() },
+                Violation { uuid: uuid(4), message: "schema rule for \"done\" must be a string".into() },
+            ]
+        );
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use uuid::Uuid;
+
+use node::{Attribute, TreeNode};
+use reader::{escape, unescape};
+
+/* Full-text index over a directory of outlines, stored in `INDEX_FILE_NAME` inside it.
+ *
+ * The index is made of one segment per outline file. Each segment records the modification time
+ * and size of its file, the indexed nodes (documents) and the inverted index of their terms, so
+ * that updating the index only re-reads the files that changed.
+ *
+ * On disk, every line is a tab-separated record:
+ *     file <path> <mtime> <size>
+ *     node <uuid> <ancestors path> <text>
+ *     term <term> <node index>:<count> <node index>:<count> ...
+ * `node` and `term` records belong to the last `file` record before them.
+ */
+
+pub const INDEX_FILE_NAME: &str = ".sofer-index";
+const HEADER: &str = "sofer-index 1";
+const SNIPPET_LENGTH: usize = 60;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Document {
+    pub uuid: Uuid,
+    pub path: String,
+    pub text: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Segment {
+    file: String,
+    mtime: u64,
+    size: u64,
+    documents: Vec<Document>,
+    postings: BTreeMap<String, Vec<(usize, u32)>>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Index {
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hit {
+    pub score: f32,
+    pub file: String,
+    pub uuid: Uuid,
+    pub path: String,
+    pub snippet: String,
+}
+
+pub fn terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(|term| term.to_lowercase())
+        .collect()
+}
+
+fn display_text(n: &TreeNode) -> String {
+    n.value.raw.trim().into()
+}
+
+fn index_node(n: &TreeNode, ancestors: &mut Vec<String>, segment: &mut Segment) {
+    let mut indexed = n.value.raw.clone();
+    if let Some(ref evaled) = n.value.evaled {
+        indexed.push(' ');
+        indexed.push_str(evaled);
+    }
+    for attr in &n.value.attributes {
+        if let Attribute::String(_, value) = attr {
+            indexed.push(' ');
+            indexed.push_str(value);
+        }
+    }
+
+    let doc = segment.documents.len();
+    let mut counts: BTreeMap<String, u32> = BTreeMap::new();
+    for term in terms(&indexed) {
+        *counts.entry(term).or_insert(0) += 1;
+    }
+    for (term, count) in counts {
+        segment.postings.entry(term).or_default().push((doc, count));
+    }
+
+    let text = display_text(n);
+    segment.documents.push(Document {
+        uuid: n.uuid,
+        path: ancestors.join(" / "),
+        text: match n.value.evaled {
+            Some(ref evaled) => evaled.clone(),
+            None => text.clone(),
+        },
+    });
+
+    ancestors.push(text);
+    for child in n.children() {
+        index_node(child, ancestors, segment);
+    }
+    ancestors.pop();
+}
+
+fn snippet(text: &str, terms: &[String]) -> String {
+    let chars = text.chars().collect::<Vec<_>>();
+    let lowercase = chars.iter().map(|c| c.to_lowercase().next().unwrap_or(*c)).collect::<String>();
+    let start = terms
+        .iter()
+        .filter_map(|term| lowercase.find(term.as_str()))
+        .min()
+        .map(|byte| lowercase[..byte].chars().count())
+        .unwrap_or(0);
+
+    let from = start.saturating_sub(SNIPPET_LENGTH / 3).min(chars.len().saturating_sub(SNIPPET_LENGTH));
+    let to = (from + SNIPPET_LENGTH).min(chars.len());
+    let mut snippet = String::new();
+    if from > 0 {
+        snippet.push_str("...");
+    }
+    snippet.extend(&chars[from..to]);
+    if to < chars.len() {
+        snippet.push_str("...");
+    }
+    snippet
+}
+
+fn file_stamp(path: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let mtime = match metadata.modified()?.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    };
+    Ok((mtime, metadata.len()))
+}
+
+/// Every `.sofer` file under `dir`, recursively. Symlinked directories are skipped, as they could
+/// form a cycle.
+pub fn outline_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_type = fs::symlink_metadata(&path)?.file_type();
+        if file_type.is_dir() {
+            files.append(&mut outline_files(&path)?);
+        } else if file_type.is_symlink() && path.is_dir() {
+            continue;
+        } else if path.extension().map(|ext| ext == "sofer").unwrap_or(false) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn read_outline(path: &Path) -> io::Result<TreeNode> {
+    let mut str = String::new();
+    fs::File::open(path)?.read_to_string(&mut str)?;
+    TreeNode::import_from_sofer(&str).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+impl Index {
+    pub fn new() -> Index {
+        Index { segments: Vec::new() }
+    }
+
+    /// Reads an index. A missing or unreadable index is treated as empty, so it gets rebuilt.
+    pub fn load(path: &Path) -> Index {
+        let mut str = String::new();
+        match fs::File::open(path) {
+            Ok(mut f) => {
+                if f.read_to_string(&mut str).is_err() {
+                    return Index::new();
+                }
+            }
+            Err(_) => return Index::new(),
+        }
+        Index::parse(&str).unwrap_or_default()
+    }
+
+    fn parse(str: &str) -> Option<Index> {
+        let mut lines = str.lines();
+        if lines.next() != Some(HEADER) {
+            return None;
+        }
+
+        let mut segments: Vec<Segment> = Vec::new();
+        for line in lines {
+            let fields = line.split('\t').collect::<Vec<_>>();
+            match (fields[0], fields.len()) {
+                ("file", 4) => segments.push(Segment {
+                    file: unescape(fields[1]),
+                    mtime: fields[2].parse().ok()?,
+                    size: fields[3].parse().ok()?,
+                    documents: Vec::new(),
+                    postings: BTreeMap::new(),
+                }),
+                ("node", 4) => segments.last_mut()?.documents.push(Document {
+                    uuid: Uuid::parse_str(fields[1]).ok()?,
+                    path: unescape(fields[2]),
+                    text: unescape(fields[3]),
+                }),
+                ("term", n) if n >= 3 => {
+                    let mut postings = Vec::new();
+                    for posting in &fields[2..] {
+                        let mut parts = posting.split(':');
+                        let doc = parts.next()?.parse().ok()?;
+                        let count = parts.next()?.parse().ok()?;
+                        postings.push((doc, count));
+                    }
+                    segments.last_mut()?.postings.insert(unescape(fields[1]), postings);
+                }
+                _ => return None,
+            }
+        }
+        Some(Index { segments })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut str = String::from(HEADER);
+        str.push('\n');
+        for segment in &self.segments {
+            str.push_str(&format!("file\t{}\t{}\t{}\n", escape(&segment.file), segment.mtime, segment.size));
+            for doc in &segment.documents {
+                str.push_str(&format!("node\t{}\t{}\t{}\n", doc.uuid, escape(&doc.path), escape(&doc.text)));
+            }
+            for (term, postings) in &segment.postings {
+                str.push_str("term\t");
+                str.push_str(&escape(term));
+                for &(doc, count) in postings {
+                    str.push_str(&format!("\t{}:{}", doc, count));
+                }
+                str.push('\n');
+            }
+        }
+
+        let tmp = path.with_extension("tmp");
+        {
+            let mut f = fs::File::create(&tmp)?;
+            f.write_all(str.as_bytes())?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, path)
+    }
+
+    /// Replaces the segment of `file` with the nodes of `tree`.
+    pub fn add(&mut self, file: &str, mtime: u64, size: u64, tree: &TreeNode) {
+        let mut segment = Segment {
+            file: file.into(),
+            mtime,
+            size,
+            documents: Vec::new(),
+            postings: BTreeMap::new(),
+        };
+        for child in tree.children() {
+            index_node(child, &mut vec![], &mut segment);
+        }
+        self.segments.retain(|s| s.file != file);
+        self.segments.push(segment);
+    }
+
+    /// Re-indexes the files whose modification time or size changed and forgets the ones that are
+    /// not in `files` anymore. Files that can't be read or parsed are left out of the index. Returns
+    /// how many files were re-indexed, and an error for each file that was left out.
+    pub fn update(&mut self, files: &[PathBuf]) -> (usize, Vec<String>) {
+        let names = files.iter().map(|f| f.to_string_lossy().into_owned()).collect::<Vec<_>>();
+        self.segments.retain(|s| names.contains(&s.file));
+
+        let mut updated = 0;
+        let mut errors = Vec::new();
+        for (path, name) in files.iter().zip(names.iter()) {
+            let (mtime, size) = match file_stamp(path) {
+                Ok(stamp) => stamp,
+                Err(err) => {
+                    self.segments.retain(|s| &s.file != name);
+                    errors.push(format!("{}: {}", name, err));
+                    continue;
+                }
+            };
+            let up_to_date = self.segments
+                .iter()
+                .any(|s| &s.file == name && s.mtime == mtime && s.size == size);
+            if up_to_date {
+                continue;
+            }
+
+            match read_outline(path) {
+                Ok(tree) => {
+                    self.add(name, mtime, size, &tree);
+                    updated += 1;
+                }
+                Err(err) => {
+                    self.segments.retain(|s| &s.file != name);
+                    errors.push(format!("{}: {}", name, err));
+                }
+            }
+        }
+        (updated, errors)
+    }
+
+    /// Nodes containing every term of `query`, best first. Scores are the sum, over the terms, of
+    /// the term frequency times its inverse document frequency.
+    pub fn search(&self, query: &str) -> Vec<Hit> {
+        let terms = terms(query);
+        if terms.is_empty() {
+            return Vec::new();
+        }
+
+        let total = self.segments.iter().map(|s| s.documents.len()).sum::<usize>() as f32;
+        let idfs = terms
+            .iter()
+            .map(|term| {
+                let df = self.segments
+                    .iter()
+                    .map(|s| s.postings.get(term).map(|p| p.len()).unwrap_or(0))
+                    .sum::<usize>() as f32;
+                (1.0 + total / df.max(1.0)).ln()
+            })
+            .collect::<Vec<_>>();
+
+        let mut hits = Vec::new();
+        for segment in &self.segments {
+            let mut scores: BTreeMap<usize, (usize, f32)> = BTreeMap::new();
+            for (term, idf) in terms.iter().zip(idfs.iter()) {
+                for &(doc, count) in segment.postings.get(term).map(|p| p.as_slice()).unwrap_or(&[]) {
+                    let score = scores.entry(doc).or_insert((0, 0.0));
+                    score.0 += 1;
+                    score.1 += count as f32 * idf;
+                }
+            }
+
+            for (doc, (matched, score)) in scores {
+                let document = match segment.documents.get(doc) {
+                    Some(document) if matched == terms.len() => document,
+                    _ => continue,
+                };
+                hits.push(Hit {
+                    score,
+                    file: segment.file.clone(),
+                    uuid: document.uuid,
+                    path: document.path.clone(),
+                    snippet: snippet(&document.text, &terms),
+                });
+            }
+        }
+
+        hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(::std::cmp::Ordering::Equal));
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index() -> Index {
+        let mut index = Index::new();
+        index.add("a.sofer", 1, 2, &TreeNode::import_from_sofer(
+r#"sofer 2
+00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  Projects
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000001 tag="bug"; Fix the parser bug, the bug is nasty
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000001  Write the parser docs @ "evaled"
+"#).unwrap());
+        index.add("b.sofer", 1, 2, &TreeNode::import_from_sofer(
+r#"00000000-0000-0000-0000-000000000004 00000000-0000-0000-0000-000000000000  Another bug
+"#).unwrap());
+        index
+    }
+
+    #[test]
+    fn search_ranking() {
+        let index = index();
+        let hits = index.search("Bug");
+        assert_eq!(
+            hits.iter().map(|h| (h.file.as_ref(), h.uuid.as_bytes()[15])).collect::<Vec<_>>(),
+            vec![("a.sofer", 2), ("b.sofer", 4)]
+        );
+        assert_eq!(hits[0].path, "Projects");
+        assert_eq!(hits[0].snippet, "Fix the parser bug, the bug is nasty");
+
+        let hits = index.search("parser docs");
+        assert_eq!(hits.iter().map(|h| h.uuid.as_bytes()[15]).collect::<Vec<_>>(), vec![3]);
+        assert!(index.search("evaled").len() == 1);
+        assert!(index.search("missing").is_empty());
+    }
+
+    #[test]
+    fn index_roundtrip() {
+        let mut index = index();
+        index.segments[0].documents[0].text = "tab\there\nnewline \\".into();
+        let path = ::std::env::temp_dir().join(format!("sofer-index-test-{}", Uuid::new_v4()));
+        index.save(&path).unwrap();
+        assert_eq!(Index::load(&path), index);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn update_skips_bad_files() {
+        let dir = ::std::env::temp_dir().join(format!("sofer-search-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("good.sofer"), "sofer 2\n00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  find me\n").unwrap();
+        fs::write(dir.join("sub/bad.sofer"), "sofer 3\nlost\n").unwrap();
+        ::std::os::unix::fs::symlink(&dir, dir.join("sub/loop")).unwrap();
+
+        let files = outline_files(&dir).unwrap();
+        assert_eq!(files, vec![dir.join("good.sofer"), dir.join("sub/bad.sofer")]);
+        let mut index = Index::new();
+        let (updated, errors) = index.update(&files);
+        assert_eq!(updated, 1);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("bad.sofer"));
+        assert_eq!(index.search("find").len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn snippets() {
+        let text = "0123456789".repeat(10) + "needle" + &"9876543210".repeat(10);
+        let snippet = snippet(&text, &["needle".into()]);
+        assert!(snippet.starts_with("...") && snippet.ends_with("..."));
+        assert!(snippet.contains("needle"));
+        assert_eq!(snippet.chars().count(), SNIPPET_LENGTH + 6);
+        assert_eq!(super::snippet("short text with a needle", &["needle".into()]), "short text with a needle");
+    }
+}
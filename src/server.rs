@@ -0,0 +1,466 @@
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use eval::EvalError;
+use json::Json;
+use node::{Attribute, Node, TreeNode};
+use oplog::Operation;
+use query::Query;
+use tree::Tree;
+use {export, import, write_file, Format};
+
+/* JSON-RPC 2.0 server that keeps an outline in memory, for front-ends that stay open. Messages
+ * are JSON objects, one per line.
+ *
+ * Methods (UUIDs are strings, and `after` is the sibling to put a node after, or null for the
+ * first child):
+ *     open {path, format?}              save {path?, format?}
+ *     get {uuid?, depth?}               eval {uuid}           eval_all {}
+ *     insert {parent, after?, text}     move {uuid, parent, after?}
+ *     delete {uuid}                     edit {uuid, text}
+ *     set_script {uuid, script?}        set_attribute {uuid, name, value?}
+ *     query {query, tree?}
+ * `eval_all` returns the evaluated text of every node as `evaled` and the errors of their scripts
+ * as `diagnostics` ({uuid, message, line, traceback}). A failing `eval` is an error with the same
+ * fields as its data.
+ * Every change made to the outline is also sent as a `changed` notification, and opening a file
+ * as an `opened` one, so that all the views of the outline can follow it.
+ */
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+/// The request was well formed, but it couldn't be done (e.g. the node doesn't exist).
+const FAILED: i64 = -32000;
+
+struct Error {
+    code: i64,
+    message: String,
+    /// More about the error, like the line and the traceback of a failing script.
+    data: Option<Json>,
+}
+
+fn invalid_params(message: String) -> Error {
+    Error { code: INVALID_PARAMS, message, data: None }
+}
+
+fn failed(message: String) -> Error {
+    Error { code: FAILED, message, data: None }
+}
+
+fn not_found(uuid: Uuid) -> Error {
+    failed(format!("Couldn't find node with UUID \"{}\"", uuid))
+}
+
+fn eval_error_json(err: &EvalError) -> Json {
+    Json::object(vec![
+        ("message", Json::String(err.message.clone())),
+        ("line", err.line.map(|line| Json::Number(line as f64)).unwrap_or(Json::Null)),
+        ("traceback", err.traceback.clone().map(Json::String).unwrap_or(Json::Null)),
+    ])
+}
+
+fn script_failed(err: &EvalError) -> Error {
+    Error { code: FAILED, message: err.to_string(), data: Some(eval_error_json(err)) }
+}
+
+fn str_param<'a>(params: &'a Json, name: &str) -> Result<&'a str, Error> {
+    params.get(name)
+        .and_then(|p| p.as_str())
+        .ok_or(invalid_params(format!("\"{}\" must be a string", name)))
+}
+
+fn optional_str_param<'a>(params: &'a Json, name: &str) -> Result<Option<&'a str>, Error> {
+    match params.get(name) {
+        None | Some(&Json::Null) => Ok(None),
+        Some(_) => str_param(params, name).map(Some),
+    }
+}
+
+fn uuid_param(params: &Json, name: &str) -> Result<Uuid, Error> {
+    let str = str_param(params, name)?;
+    Uuid::parse_str(str).map_err(|_| invalid_params(format!("Couldn't read UUID \"{}\"", str)))
+}
+
+fn optional_uuid_param(params: &Json, name: &str) -> Result<Option<Uuid>, Error> {
+    match params.get(name) {
+        None | Some(&Json::Null) => Ok(None),
+        Some(_) => uuid_param(params, name).map(Some),
+    }
+}
+
+fn format_param(params: &Json) -> Result<Option<Format>, Error> {
+    match optional_str_param(params, "format")? {
+        Some(format) => format.parse().map(Some).map_err(invalid_params),
+        None => Ok(None),
+    }
+}
+
+fn uuid_json(uuid: Uuid) -> Json {
+    Json::string(uuid.to_string())
+}
+
+fn optional_uuid_json(uuid: Option<Uuid>) -> Json {
+    uuid.map(uuid_json).unwrap_or(Json::Null)
+}
+
+/// A node and its subtree, down to `depth` levels of children.
+fn node_json(n: &TreeNode, depth: Option<usize>) -> Json {
+    let mut fields = vec![
+        ("uuid", uuid_json(n.uuid)),
+        ("text", Json::string(n.value.raw.clone())),
+        ("script", n.value.script.clone().map(Json::String).unwrap_or(Json::Null)),
+        ("evaled", n.value.evaled.clone().map(Json::String).unwrap_or(Json::Null)),
+        ("attributes", Json::Object(
+            n.value.attributes.iter().map(|attr| (attr.name().to_string(), Json::attribute_value(attr))).collect()
+        )),
+        ("child_count", Json::Number(n.children().count() as f64)),
+    ];
+    if depth != Some(0) {
+        fields.push((
+            "children",
+            Json::Array(n.children().map(|child| node_json(child, depth.map(|d| d - 1))).collect()),
+        ));
+    }
+    Json::object(fields)
+}
+
+fn operation_json(operation: &Operation) -> Json {
+    match *operation {
+        Operation::Insert { parent, after, ref node } => Json::object(vec![
+            ("change", Json::string("inserted")),
+            ("uuid", uuid_json(node.uuid)),
+            ("parent", uuid_json(parent)),
+            ("after", optional_uuid_json(after)),
+            ("node", node_json(node, None)),
+        ]),
+        Operation::Delete { parent, ref node, .. } => Json::object(vec![
+            ("change", Json::string("deleted")),
+            ("uuid", uuid_json(node.uuid)),
+            ("parent", uuid_json(parent)),
+        ]),
+        Operation::Move { uuid, to, .. } => Json::object(vec![
+            ("change", Json::string("moved")),
+            ("uuid", uuid_json(uuid)),
+            ("parent", uuid_json(to.0)),
+            ("after", optional_uuid_json(to.1)),
+        ]),
+        Operation::SetRaw { uuid, ref new, .. } => Json::object(vec![
+            ("change", Json::string("edited")),
+            ("uuid", uuid_json(uuid)),
+            ("text", Json::string(new.clone())),
+        ]),
+        Operation::SetScript { uuid, ref new, .. } => Json::object(vec![
+            ("change", Json::string("script-set")),
+            ("uuid", uuid_json(uuid)),
+            ("script", new.clone().map(Json::String).unwrap_or(Json::Null)),
+        ]),
+        Operation::SetAttr { uuid, ref name, ref new, .. } => Json::object(vec![
+            ("change", Json::string("attribute-set")),
+            ("uuid", uuid_json(uuid)),
+            ("name", Json::string(name.clone())),
+            ("value", new.as_ref().map(Json::attribute_value).unwrap_or(Json::Null)),
+        ]),
+    }
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    Json::object(vec![
+        ("jsonrpc", Json::string("2.0")),
+        ("method", Json::string(method)),
+        ("params", params),
+    ])
+}
+
+pub struct Server {
+    tree: TreeNode,
+    path: Option<PathBuf>,
+    format: Format,
+}
+
+impl Default for Server {
+    fn default() -> Server {
+        Server::new()
+    }
+}
+
+impl Server {
+    /// A server with an empty outline.
+    pub fn new() -> Server {
+        Server {
+            tree: Tree::new_tree(Node::new(String::new(), Vec::new())),
+            path: None,
+            format: Format::Sofer,
+        }
+    }
+
+    /// Handles a message and returns the messages to send back: the response, unless the
+    /// message was a notification, and the notifications of the changes it made.
+    pub fn handle(&mut self, message: &str) -> Vec<Json> {
+        let request = match Json::parse(message) {
+            Ok(request) => request,
+            Err(err) => return vec![response(Json::Null, Err(Error { code: PARSE_ERROR, message: err, data: None }))],
+        };
+        let id = request.get("id").cloned();
+        let method = match request.get("method").and_then(|m| m.as_str()) {
+            Some(method) => method,
+            None => return vec![response(
+                id.unwrap_or(Json::Null),
+                Err(Error { code: INVALID_REQUEST, message: "The request has no method".into(), data: None }),
+            )],
+        };
+        let params = request.get("params").cloned().unwrap_or(Json::Object(Vec::new()));
+
+        let mut notifications = Vec::new();
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| self.call(method, &params, &mut notifications))) {
+            Ok(result) => result,
+            Err(payload) => Err(Error {
+                code: INTERNAL_ERROR,
+                message: payload.downcast_ref::<String>().cloned()
+                    .or(payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or("Internal error".into()),
+                data: None,
+            }),
+        };
+
+        let mut messages = Vec::new();
+        if let Some(id) = id {
+            messages.push(response(id, result));
+        }
+        messages.append(&mut notifications);
+        messages
+    }
+
+    /// Reads messages from `input` and writes what `handle` returns to `output`, until the end
+    /// of `input`.
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> io::Result<()> {
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            for message in self.handle(&line) {
+                writeln!(output, "{}", message)?;
+            }
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Opens `path` as if an `open` request had been made.
+    pub fn open(&mut self, path: &Path, format: Format) -> io::Result<()> {
+        let mut str = String::new();
+        fs::File::open(path)?.read_to_string(&mut str)?;
+        self.tree = import(&str, format).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        self.path = Some(path.to_path_buf());
+        self.format = format;
+        Ok(())
+    }
+
+    fn apply(&mut self, operation: Operation, notifications: &mut Vec<Json>) -> Result<(), Error> {
+        if operation.apply(&mut self.tree) {
+            notifications.push(notification("changed", operation_json(&operation)));
+            Ok(())
+        } else {
+            Err(failed("Couldn't make the change".into()))
+        }
+    }
+
+    fn call(&mut self, method: &str, params: &Json, notifications: &mut Vec<Json>) -> Result<Json, Error> {
+        match method {
+            "open" => {
+                let path = PathBuf::from(str_param(params, "path")?);
+                let format = format_param(params)?.unwrap_or(Format::Sofer);
+                self.open(&path, format).map_err(|err| failed(err.to_string()))?;
+                notifications.push(notification("opened", Json::object(vec![
+                    ("path", Json::string(path.display().to_string())),
+                ])));
+                Ok(Json::Null)
+            }
+            "save" => {
+                let path = match optional_str_param(params, "path")? {
+                    Some(path) => PathBuf::from(path),
+                    None => self.path.clone().ok_or(invalid_params("No file is open, a path is needed".into()))?,
+                };
+                let format = format_param(params)?.unwrap_or(self.format);
+                let str = export(&self.tree, format, false).map_err(invalid_params)?;
+                write_file(&path, &str).map_err(|err| failed(err.to_string()))?;
+                Ok(Json::Null)
+            }
+            "get" => {
+                let uuid = optional_uuid_param(params, "uuid")?.unwrap_or(self.tree.uuid);
+                let depth = params.get("depth").and_then(|d| d.as_f64()).map(|d| d as usize);
+                let n = self.tree.find(uuid).ok_or(not_found(uuid))?;
+                Ok(node_json(n, depth))
+            }
+            "eval" => {
+                let uuid = uuid_param(params, "uuid")?;
+                let n = self.tree.find(uuid).ok_or(not_found(uuid))?;
+                n.eval(&self.tree).map(Json::String).map_err(|err| script_failed(&err))
+            }
+            "eval_all" => {
+                let diagnostics = self.tree.eval_all();
+                let evaled = Json::Object(
+                    self.tree.nodes()
+                        .into_iter()
+                        .skip(1)
+                        .map(|n| (n.uuid.to_string(), n.value.evaled.clone().map(Json::String).unwrap_or(Json::Null)))
+                        .collect()
+                );
+                let diagnostics = diagnostics.iter()
+                    .map(|d| {
+                        let mut json = eval_error_json(&d.error);
+                        if let Json::Object(ref mut fields) = json {
+                            fields.insert(0, ("uuid".into(), Json::String(d.uuid.to_string())));
+                        }
+                        json
+                    })
+                    .collect();
+                Ok(Json::object(vec![("evaled", evaled), ("diagnostics", Json::Array(diagnostics))]))
+            }
+            "insert" => {
+                let parent = uuid_param(params, "parent")?;
+                let n = Tree::new_child(Node::new(str_param(params, "text")?.into(), Vec::new()));
+                let uuid = n.uuid;
+                let operation = match params.get("after") {
+                    None => Operation::append(&self.tree, parent, n).ok_or(not_found(parent))?,
+                    Some(_) => Operation::Insert { parent, after: optional_uuid_param(params, "after")?, node: n },
+                };
+                self.apply(operation, notifications)?;
+                Ok(Json::object(vec![("uuid", uuid_json(uuid))]))
+            }
+            "move" => {
+                let uuid = uuid_param(params, "uuid")?;
+                let parent = uuid_param(params, "parent")?;
+                let after = match params.get("after") {
+                    Some(_) => optional_uuid_param(params, "after")?,
+                    None => self.tree.find(parent)
+                        .ok_or(not_found(parent))?
+                        .children()
+                        .map(|n| n.uuid)
+                        .filter(|&child| child != uuid)
+                        .last(),
+                };
+                let operation = Operation::move_to(&self.tree, uuid, parent, after).ok_or(not_found(uuid))?;
+                self.apply(operation, notifications).map(|_| Json::Null)
+            }
+            "delete" => {
+                let uuid = uuid_param(params, "uuid")?;
+                let operation = Operation::delete(&self.tree, uuid).ok_or(not_found(uuid))?;
+                self.apply(operation, notifications).map(|_| Json::Null)
+            }
+            "edit" => {
+                let uuid = uuid_param(params, "uuid")?;
+                let text = str_param(params, "text")?;
+                let operation = Operation::set_raw(&self.tree, uuid, text.into()).ok_or(not_found(uuid))?;
+                self.apply(operation, notifications).map(|_| Json::Null)
+            }
+            "set_script" => {
+                let uuid = uuid_param(params, "uuid")?;
+                let script = match params.get("script") {
+                    None | Some(&Json::Null) => None,
+                    Some(Json::String(s)) => Some(s.clone()),
+                    Some(_) => return Err(invalid_params("\"script\" must be a string".into())),
+                };
+                let operation = Operation::set_script(&self.tree, uuid, script).ok_or(not_found(uuid))?;
+                self.apply(operation, notifications).map(|_| Json::Null)
+            }
+            "set_attribute" => {
+                let uuid = uuid_param(params, "uuid")?;
+                let name = str_param(params, "name")?;
+                let value = match params.get("value") {
+                    None | Some(&Json::Null) => None,
+                    Some(Json::String(s)) => Some(Attribute::String(name.into(), s.clone())),
+                    Some(&Json::Number(x)) => Some(Attribute::Number(name.into(), x as f32)),
+                    Some(&Json::Bool(b)) => Some(Attribute::Boolean(name.into(), b)),
+                    Some(_) => return Err(invalid_params("\"value\" must be a string, a number or a boolean".into())),
+                };
+                let operation = Operation::set_attr(&self.tree, uuid, name, value).ok_or(not_found(uuid))?;
+                self.apply(operation, notifications).map(|_| Json::Null)
+            }
+            "query" => {
+                let query = Query::parse(str_param(params, "query")?).map_err(invalid_params)?;
+                if params.get("tree").and_then(|t| t.as_bool()).unwrap_or(false) {
+                    Ok(node_json(&self.tree.query_pruned(&query), None))
+                } else {
+                    Ok(Json::Array(self.tree.query(&query).into_iter().map(|n| node_json(n, Some(0))).collect()))
+                }
+            }
+            _ => Err(Error { code: METHOD_NOT_FOUND, message: format!("Method \"{}\" not found", method), data: None }),
+        }
+    }
+}
+
+fn response(id: Json, result: Result<Json, Error>) -> Json {
+    let (key, value) = match result {
+        Ok(result) => ("result", result),
+        Err(err) => {
+            let mut fields = vec![
+                ("code", Json::Number(err.code as f64)),
+                ("message", Json::String(err.message)),
+            ];
+            if let Some(data) = err.data {
+                fields.push(("data", data));
+            }
+            ("error", Json::object(fields))
+        }
+    };
+    Json::object(vec![("jsonrpc", Json::string("2.0")), ("id", id), (key, value)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(server: &mut Server, message: &str) -> Vec<String> {
+        server.handle(message).iter().map(|m| m.to_string()).collect()
+    }
+
+    #[test]
+    fn server_requests() {
+        let mut server = Server::new();
+        let messages = call(&mut server, r#"{"jsonrpc":"2.0","id":1,"method":"insert","params":{"parent":"00000000-0000-0000-0000-000000000000","text":"One"}}"#);
+        assert_eq!(messages.len(), 2);
+        let uuid = server.tree.children().next().unwrap().uuid;
+        assert_eq!(messages[0], format!(r#"{{"jsonrpc":"2.0","id":1,"result":{{"uuid":"{}"}}}}"#, uuid));
+        assert!(messages[1].starts_with(r#"{"jsonrpc":"2.0","method":"changed","params":{"change":"inserted""#));
+
+        call(&mut server, &format!(r#"{{"jsonrpc":"2.0","id":2,"method":"set_attribute","params":{{"uuid":"{}","name":"done","value":true}}}}"#, uuid));
+        assert_eq!(
+            call(&mut server, r#"{"jsonrpc":"2.0","id":3,"method":"query","params":{"query":"done = true"}}"#)[0],
+            format!(
+                r#"{{"jsonrpc":"2.0","id":3,"result":[{{"uuid":"{}","text":"One","script":null,"evaled":null,"attributes":{{"done":true}},"child_count":0}}]}}"#,
+                uuid
+            )
+        );
+
+        assert_eq!(
+            call(&mut server, r#"{"jsonrpc":"2.0","id":4,"method":"delete","params":{"uuid":"00000000-0000-0000-0000-000000000009"}}"#),
+            vec![r#"{"jsonrpc":"2.0","id":4,"error":{"code":-32000,"message":"Couldn't find node with UUID \"00000000-0000-0000-0000-000000000009\""}}"#]
+        );
+        assert_eq!(
+            call(&mut server, r#"{"jsonrpc":"2.0","id":5,"method":"fly"}"#),
+            vec![r#"{"jsonrpc":"2.0","id":5,"error":{"code":-32601,"message":"Method \"fly\" not found"}}"#]
+        );
+
+        call(&mut server, &format!(r#"{{"jsonrpc":"2.0","id":6,"method":"set_script","params":{{"uuid":"{}","script":"error('no data')"}}}}"#, uuid));
+        assert!(
+            call(&mut server, &format!(r#"{{"jsonrpc":"2.0","id":7,"method":"eval","params":{{"uuid":"{}"}}}}"#, uuid))[0]
+                .starts_with(r#"{"jsonrpc":"2.0","id":7,"error":{"code":-32000,"message":"line 1: no data","data":{"message":"no data","line":1,"traceback":"stack traceback:"#)
+        );
+        assert!(
+            call(&mut server, r#"{"jsonrpc":"2.0","id":8,"method":"eval_all"}"#)[0]
+                .contains(&format!(r#""diagnostics":[{{"uuid":"{}","message":"no data","line":1,"#, uuid))
+        );
+        assert_eq!(
+            call(&mut server, "{"),
+            vec![r#"{"jsonrpc":"2.0","id":null,"error":{"code":-32700,"message":"Expected '\"' at 1"}}"#]
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use ids::{self, all_uuids, short_id};
+use node::{Attribute, Node, TreeNode};
+use oplog::Operation;
+use tree::Tree;
+use write_file;
+
+/* Interactive shell on an outline kept in memory. Nodes are named as in `ids`, with index paths
+ * starting at the current node, so that `1`, `2`... are the nodes of its listing. Listings show
+ * the shortest UUID prefixes that name each node.
+ */
+
+const HELP: &str = "\
+ls [NODE]               List the children of a node
+cd NODE                 Go to a node
+add TEXT                Add a node under the current one
+edit NODE TEXT          Replace the text of a node
+mv NODE PARENT          Move a node under another one, as its last child
+rm NODE                 Delete a node and its subtree
+attr NODE [NAME [VALUE]] List the attributes of a node, or set one, or remove it without VALUE
+script NODE [CODE]      Show the Lua script of a node, or set it, or remove it with `-`
+eval NODE               Evaluate a node
+save [FILE]             Write the outline, by default to the file it was read from
+quit                    Leave, refusing to if there are unsaved changes (quit! to leave anyway)
+";
+
+/// The first line of a node's text, cut to a length that fits in a prompt or listing.
+fn title(n: &TreeNode, length: usize) -> String {
+    let line = n.value.raw.lines().next().unwrap_or("");
+    if line.chars().count() > length {
+        format!("{}…", line.chars().take(length - 1).collect::<String>())
+    } else {
+        line.into()
+    }
+}
+
+/// Splits the first word off `str`.
+fn word(str: &str) -> (&str, &str) {
+    let str = str.trim_start();
+    match str.find(char::is_whitespace) {
+        Some(end) => (&str[..end], str[end..].trim_start()),
+        None => (str, ""),
+    }
+}
+
+pub struct Shell {
+    tree: TreeNode,
+    path: PathBuf,
+    current: Uuid,
+    modified: bool,
+}
+
+impl Shell {
+    /// A shell on the outline in `path`, in the sofer format. A missing file is an empty
+    /// outline.
+    pub fn open(path: &Path) -> io::Result<Shell> {
+        let tree = match fs::File::open(path) {
+            Ok(mut f) => {
+                let mut str = String::new();
+                f.read_to_string(&mut str)?;
+                TreeNode::import_from_sofer(&str).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Tree::new_tree(Node::new(String::new(), Vec::new())),
+            Err(err) => return Err(err),
+        };
+        let current = tree.uuid;
+        Ok(Shell { tree, path: path.to_path_buf(), current, modified: false })
+    }
+
+    /// Reads commands from `input` until it ends or `quit`, writing prompts and results to
+    /// `output`.
+    pub fn run<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<()> {
+        loop {
+            write!(output, "{}> ", self.prompt())?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(output)?;
+                return Ok(());
+            }
+            match word(&line).0 {
+                "quit" | "exit" if self.modified =>
+                    writeln!(output, "There are unsaved changes. Use `save`, or `quit!` to leave anyway.")?,
+                "quit" | "exit" | "quit!" | "exit!" => return Ok(()),
+                _ => match self.execute(&line) {
+                    Ok(ref out) if out.is_empty() => (),
+                    Ok(out) => writeln!(output, "{}", out)?,
+                    Err(err) => writeln!(output, "error: {}", err)?,
+                },
+            }
+        }
+    }
+
+    /// The path from the root to the current node, by the titles of the nodes.
+    pub fn prompt(&self) -> String {
+        let mut titles = Vec::new();
+        let mut uuid = self.current;
+        while let Some(parent) = self.tree.find_parent(uuid) {
+            titles.push(title(self.tree.find(uuid).unwrap(), 16));
+            uuid = parent.uuid;
+        }
+        titles.reverse();
+        format!("/{}", titles.join("/"))
+    }
+
+    fn resolve(&self, name: &str) -> Result<Uuid, String> {
+        ids::resolve_from(&self.tree, self.current, name)
+    }
+
+    fn apply(&mut self, operation: Option<Operation>) -> Result<(), String> {
+        let operation = operation.ok_or("No such node".to_string())?;
+        if operation.apply(&mut self.tree) {
+            self.modified = true;
+            // The current node may have been deleted.
+            if self.tree.find(self.current).is_none() {
+                self.current = self.tree.uuid;
+            }
+            Ok(())
+        } else {
+            Err("Couldn't make the change".into())
+        }
+    }
+
+    fn list(&self, uuid: Uuid) -> String {
+        let uuids = all_uuids(&self.tree);
+        let n = self.tree.find(uuid).unwrap();
+        let mut lines = Vec::new();
+        for (i, child) in n.children().enumerate() {
+            let count = child.children().count();
+            lines.push(format!(
+                "{:>3}  {}  {}{}",
+                i + 1,
+                short_id(&uuids, child.uuid),
+                title(child, 60),
+                if count > 0 { format!(" [{}]", count) } else { String::new() },
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// Runs a command and returns what it prints.
+    pub fn execute(&mut self, line: &str) -> Result<String, String> {
+        let (command, args) = word(line.trim());
+        match command {
+            "" => Ok(String::new()),
+            "help" => Ok(HELP.trim_end().into()),
+            "ls" => {
+                let uuid = if args.is_empty() { self.current } else { self.resolve(args)? };
+                Ok(self.list(uuid))
+            }
+            "cd" => {
+                self.current = self.resolve(if args.is_empty() { "/" } else { args })?;
+                Ok(String::new())
+            }
+            "add" => {
+                let n = Tree::new_child(Node::new(args.into(), Vec::new()));
+                let uuid = n.uuid;
+                let operation = Operation::append(&self.tree, self.current, n);
+                self.apply(operation)?;
+                Ok(short_id(&all_uuids(&self.tree), uuid))
+            }
+            "edit" => {
+                let (name, text) = word(args);
+                let uuid = self.resolve(name)?;
+                let operation = Operation::set_raw(&self.tree, uuid, text.into());
+                self.apply(operation).map(|_| String::new())
+            }
+            "mv" => {
+                let (name, parent) = word(args);
+                let (uuid, parent) = (self.resolve(name)?, self.resolve(parent)?);
+                let after = self.tree.find(parent).unwrap()
+                    .children()
+                    .map(|n| n.uuid)
+                    .filter(|&child| child != uuid)
+                    .last();
+                let operation = Operation::move_to(&self.tree, uuid, parent, after);
+                self.apply(operation).map(|_| String::new())
+            }
+            "rm" => {
+                let uuid = self.resolve(args)?;
+                let operation = Operation::delete(&self.tree, uuid);
+                self.apply(operation).map(|_| String::new())
+            }
+            "attr" => {
+                let (name, rest) = word(args);
+                let uuid = self.resolve(name)?;
+                let (attr, value) = word(rest);
+                if attr.is_empty() {
+                    let n = self.tree.find(uuid).unwrap();
+                    return Ok(n.value.attributes.iter().map(|a| a.export()).collect::<Vec<_>>().join("\n"));
+                }
+                let value = if value.is_empty() { None } else { Some(Attribute::from_text(attr, value)) };
+                let operation = Operation::set_attr(&self.tree, uuid, attr, value);
+                self.apply(operation).map(|_| String::new())
+            }
+            "script" => {
+                let (name, code) = word(args);
+                let uuid = self.resolve(name)?;
+                let script = match code {
+                    "" => return Ok(self.tree.find(uuid).unwrap().value.script.clone().unwrap_or_default()),
+                    "-" => None,
+                    code => Some(code.into()),
+                };
+                let operation = Operation::set_script(&self.tree, uuid, script);
+                self.apply(operation).map(|_| String::new())
+            }
+            "eval" => {
+                let uuid = self.resolve(args)?;
+                self.tree.find(uuid).unwrap().eval(&self.tree).map_err(|err| err.to_string())
+            }
+            "save" => {
+                let path = if args.is_empty() { self.path.clone() } else { PathBuf::from(args) };
+                write_file(&path, &self.tree.export_to_sofer(false)).map_err(|err| err.to_string())?;
+                self.modified = false;
+                Ok(format!("Saved to {}", path.display()))
+            }
+            _ => Err(format!("Unknown command \"{}\". Try `help`.", command)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_commands() {
+        let mut shell = Shell {
+            tree: TreeNode::import_from_sofer(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  Projects
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000001  Sofer
+a0000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000000  Inbox
+"#).unwrap(),
+            path: PathBuf::from("unused.sofer"),
+            current: Uuid::nil(),
+            modified: false,
+        };
+        assert_eq!(
+            shell.execute("ls"),
+            Ok("  1  00000000-0000-0000-0000-000000000001  Projects [1]\n  2  a000  Inbox".into())
+        );
+        assert_eq!(shell.execute("cd 1"), Ok(String::new()));
+        assert_eq!(shell.execute("cd 1"), Ok(String::new()));
+        assert_eq!(shell.prompt(), "/Projects/Sofer");
+
+        assert!(shell.execute("add Bugs").is_ok());
+        shell.execute("attr 1 priority 2").unwrap();
+        assert_eq!(shell.execute("attr 1"), Ok("priority=2;".into()));
+        shell.execute("edit 1 Bugs").unwrap();
+        shell.execute("script 1 function(n) return ' x' end").unwrap();
+        assert_eq!(shell.execute("script 1"), Ok("function(n) return ' x' end".into()));
+        assert_eq!(shell.execute("eval 1"), Ok("Bugs x".into()));
+
+        shell.execute("mv 1 a0000000").unwrap();
+        assert_eq!(shell.execute("ls"), Ok(String::new()));
+        assert!(shell.execute("ls a0000000").unwrap().ends_with("Bugs"));
+        assert!(shell.execute("cd 00000000-0000").unwrap_err().contains("is ambiguous"));
+
+        assert_eq!(shell.execute("rm .."), Ok(String::new()));
+        assert_eq!(shell.prompt(), "/");
+        assert!(shell.modified);
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use rusqlite;
+use rusqlite::{Connection, OptionalExtension, Transaction, NO_PARAMS};
+use uuid::Uuid;
+
+use node::{Attribute, Node, TreeNode};
+use oplog::Operation;
+
+/* Storage of an outline in a SQLite database, one row per node, so that changes are row updates
+ * instead of rewrites of the whole outline.
+ *
+ * The root has no parent, and the position of a node is its index among its siblings. Deleting
+ * a node deletes its subtree and its attributes through the foreign keys. Attributes are kept by
+ * their position, as a node can have two with the same name, and changing one by name changes
+ * the first, like in `Operation::apply`.
+ */
+
+const SCHEMA: &str = "
+    PRAGMA foreign_keys = ON;
+    CREATE TABLE IF NOT EXISTS nodes (
+        uuid TEXT PRIMARY KEY,
+        parent TEXT REFERENCES nodes(uuid) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        raw TEXT NOT NULL,
+        script TEXT,
+        evaled TEXT
+    );
+    CREATE INDEX IF NOT EXISTS nodes_by_parent ON nodes(parent, position);
+    CREATE TABLE IF NOT EXISTS attributes (
+        uuid TEXT NOT NULL REFERENCES nodes(uuid) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        value NOT NULL,
+        PRIMARY KEY (uuid, position)
+    );
+";
+
+pub struct Database {
+    connection: Connection,
+}
+
+fn read_attribute(name: String, kind: &str, row: &::rusqlite::Row, column: usize) -> rusqlite::Result<Attribute> {
+    Ok(match kind {
+        "number" => Attribute::Number(name, row.get::<_, f64>(column)? as f32),
+        "boolean" => Attribute::Boolean(name, row.get(column)?),
+        _ => Attribute::String(name, row.get(column)?),
+    })
+}
+
+fn insert_attribute(tx: &Transaction, uuid: Uuid, position: i64, attr: &Attribute) -> rusqlite::Result<()> {
+    let sql = "INSERT INTO attributes (uuid, name, position, kind, value) VALUES (?1, ?2, ?3, ?4, ?5)";
+    match *attr {
+        Attribute::String(ref name, ref value) =>
+            tx.execute(sql, params![uuid.to_string(), name, position, "string", value]),
+        Attribute::Number(ref name, value) =>
+            tx.execute(sql, params![uuid.to_string(), name, position, "number", value as f64]),
+        Attribute::Boolean(ref name, value) =>
+            tx.execute(sql, params![uuid.to_string(), name, position, "boolean", value]),
+    }.map(|_| ())
+}
+
+fn insert_subtree(tx: &Transaction, n: &TreeNode, parent: Option<Uuid>, position: i64) -> rusqlite::Result<()> {
+    tx.execute(
+        "INSERT INTO nodes (uuid, parent, position, raw, script, evaled) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![n.uuid.to_string(), parent.map(|p| p.to_string()), position, n.value.raw, n.value.script, n.value.evaled],
+    )?;
+    for (i, attr) in n.value.attributes.iter().enumerate() {
+        insert_attribute(tx, n.uuid, i as i64, attr)?;
+    }
+    for (i, child) in n.children().enumerate() {
+        insert_subtree(tx, child, Some(n.uuid), i as i64)?;
+    }
+    Ok(())
+}
+
+/// Parent and position of a node, if it's in the database.
+fn place(tx: &Transaction, uuid: Uuid) -> rusqlite::Result<Option<(Option<String>, i64)>> {
+    tx.query_row(
+        "SELECT parent, position FROM nodes WHERE uuid = ?1",
+        params![uuid.to_string()],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()
+}
+
+/// Position for a new child of `parent` right after `after`, making room for it. `None` if
+/// `after` isn't a child of `parent`.
+fn open_position(tx: &Transaction, parent: Uuid, after: Option<Uuid>) -> rusqlite::Result<Option<i64>> {
+    let position = match after {
+        Some(after) => match place(tx, after)? {
+            Some((Some(ref p), position)) if *p == parent.to_string() => position + 1,
+            _ => return Ok(None),
+        },
+        None => 0,
+    };
+    tx.execute(
+        "UPDATE nodes SET position = position + 1 WHERE parent = ?1 AND position >= ?2",
+        params![parent.to_string(), position],
+    )?;
+    Ok(Some(position))
+}
+
+/// Takes a node out of the order of its siblings.
+fn close_position(tx: &Transaction, parent: &str, position: i64) -> rusqlite::Result<()> {
+    tx.execute(
+        "UPDATE nodes SET position = position - 1 WHERE parent = ?1 AND position > ?2",
+        params![parent, position],
+    ).map(|_| ())
+}
+
+/// Makes room for an attribute at `index` among the attributes of `uuid`, or after them if there
+/// aren't that many.
+fn open_attribute_position(tx: &Transaction, uuid: Uuid, index: usize) -> rusqlite::Result<i64> {
+    let position: Option<i64> = tx.query_row(
+        "SELECT MIN(position) FROM (SELECT position FROM attributes WHERE uuid = ?1 ORDER BY position LIMIT -1 OFFSET ?2)",
+        params![uuid.to_string(), index as i64],
+        |row| row.get(0),
+    )?;
+    match position {
+        Some(position) => {
+            // Through negative positions, so that no two attributes share one in between.
+            tx.execute(
+                "UPDATE attributes SET position = -position - 1 WHERE uuid = ?1 AND position >= ?2",
+                params![uuid.to_string(), position],
+            )?;
+            tx.execute(
+                "UPDATE attributes SET position = -position WHERE uuid = ?1 AND position < 0",
+                params![uuid.to_string()],
+            )?;
+            Ok(position)
+        }
+        None => tx.query_row(
+            "SELECT COALESCE(MAX(position) + 1, 0) FROM attributes WHERE uuid = ?1",
+            params![uuid.to_string()],
+            |row| row.get(0),
+        ),
+    }
+}
+
+fn apply_in(tx: &Transaction, operation: &Operation) -> rusqlite::Result<bool> {
+    match *operation {
+        Operation::Insert { parent, after, ref node } => {
+            if place(tx, node.uuid)?.is_some() || place(tx, parent)?.is_none() {
+                return Ok(false);
+            }
+            match open_position(tx, parent, after)? {
+                Some(position) => insert_subtree(tx, node, Some(parent), position).map(|_| true),
+                None => Ok(false),
+            }
+        }
+        Operation::Delete { parent, ref node, .. } => {
+            match place(tx, node.uuid)? {
+                Some((Some(ref p), position)) if *p == parent.to_string() => {
+                    tx.execute("DELETE FROM nodes WHERE uuid = ?1", params![node.uuid.to_string()])?;
+                    close_position(tx, p, position).map(|_| true)
+                }
+                _ => Ok(false),
+            }
+        }
+        Operation::Move { uuid, from, to } => {
+            let (old_parent, old_position) = match place(tx, uuid)? {
+                Some((Some(ref p), position)) if *p == from.0.to_string() => (p.clone(), position),
+                _ => return Ok(false),
+            };
+            let into_itself: bool = tx.query_row(
+                "WITH RECURSIVE subtree(uuid) AS (
+                    SELECT ?1 UNION ALL SELECT nodes.uuid FROM nodes JOIN subtree ON nodes.parent = subtree.uuid
+                )
+                SELECT COUNT(*) > 0 FROM subtree WHERE uuid = ?2",
+                params![uuid.to_string(), to.0.to_string()],
+                |row| row.get(0),
+            )?;
+            if into_itself || place(tx, to.0)?.is_none() {
+                return Ok(false);
+            }
+            // Out of the way first, so that it doesn't count as a sibling.
+            tx.execute("UPDATE nodes SET parent = NULL WHERE uuid = ?1", params![uuid.to_string()])?;
+            close_position(tx, &old_parent, old_position)?;
+            match open_position(tx, to.0, to.1)? {
+                Some(position) => {
+                    tx.execute(
+                        "UPDATE nodes SET parent = ?1, position = ?2 WHERE uuid = ?3",
+                        params![to.0.to_string(), position, uuid.to_string()],
+                    )?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }
+        Operation::SetRaw { uuid, ref new, .. } => {
+            tx.execute(
+                "UPDATE nodes SET raw = ?1, evaled = NULL WHERE uuid = ?2",
+                params![new, uuid.to_string()],
+            ).map(|changed| changed > 0)
+        }
+        Operation::SetScript { uuid, ref new, .. } => {
+            tx.execute(
+                "UPDATE nodes SET script = ?1, evaled = NULL WHERE uuid = ?2",
+                params![new, uuid.to_string()],
+            ).map(|changed| changed > 0)
+        }
+        Operation::SetAttr { uuid, ref name, index, ref new, .. } => {
+            if place(tx, uuid)?.is_none() {
+                return Ok(false);
+            }
+            let position: Option<i64> = tx.query_row(
+                "SELECT MIN(position) FROM attributes WHERE uuid = ?1 AND name = ?2",
+                params![uuid.to_string(), name],
+                |row| row.get(0),
+            )?;
+            if let Some(position) = position {
+                tx.execute(
+                    "DELETE FROM attributes WHERE uuid = ?1 AND position = ?2",
+                    params![uuid.to_string(), position],
+                )?;
+            }
+            if let Some(new) = new {
+                let position = match position {
+                    Some(position) => position,
+                    None => open_attribute_position(tx, uuid, index)?,
+                };
+                insert_attribute(tx, uuid, position, new)?;
+            }
+            Ok(true)
+        }
+    }
+}
+
+/// Opens the database at `path`, creating its tables if they don't exist.
+fn open_connection(path: &Path) -> rusqlite::Result<Connection> {
+    let connection = Connection::open(path)?;
+    connection.execute_batch(SCHEMA)?;
+    Ok(connection)
+}
+
+fn load_tree(connection: &Connection) -> rusqlite::Result<TreeNode> {
+    let mut attributes: BTreeMap<String, Vec<Attribute>> = BTreeMap::new();
+    let mut statement = connection.prepare(
+        "SELECT uuid, name, kind, value FROM attributes ORDER BY uuid, position"
+    )?;
+    let mut rows = statement.query(NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        let kind: String = row.get(2)?;
+        let attr = read_attribute(row.get(1)?, &kind, row, 3)?;
+        attributes.entry(row.get(0)?).or_default().push(attr);
+    }
+
+    let mut children: BTreeMap<Option<String>, Vec<TreeNode>> = BTreeMap::new();
+    let mut statement = connection.prepare(
+        "SELECT uuid, parent, raw, script, evaled FROM nodes ORDER BY parent, position"
+    )?;
+    let mut rows = statement.query(NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        let uuid: String = row.get(0)?;
+        let node = TreeNode {
+            value: Node {
+                raw: row.get(2)?,
+                script: row.get(3)?,
+                evaled: row.get(4)?,
+                attributes: attributes.remove(&uuid).unwrap_or_default(),
+            },
+            uuid: Uuid::parse_str(&uuid).map_err(|_| ::rusqlite::Error::InvalidColumnType(
+                0, "uuid".into(), ::rusqlite::types::Type::Text
+            ))?,
+            first_child: None,
+            next_sibling: None,
+        };
+        children.entry(row.get(1)?).or_default().push(node);
+    }
+
+    fn attach(n: &mut TreeNode, children: &mut BTreeMap<Option<String>, Vec<TreeNode>>) {
+        let mut own = children.remove(&Some(n.uuid.to_string())).unwrap_or_default();
+        for child in own.iter_mut() {
+            attach(child, children);
+        }
+        n.set_children(own);
+    }
+    let mut root = match children.remove(&None).and_then(|roots| roots.into_iter().next()) {
+        Some(root) => root,
+        None => TreeNode::new_tree(Node::new(String::new(), Vec::new())),
+    };
+    attach(&mut root, &mut children);
+    Ok(root)
+}
+
+impl Database {
+    pub fn open(path: &Path) -> Result<Database, String> {
+        open_connection(path)
+            .map(|connection| Database { connection })
+            .map_err(|err| err.to_string())
+    }
+
+    /// Replaces the whole outline in the database with `tree`.
+    pub fn save(&mut self, tree: &TreeNode) -> Result<(), String> {
+        let tx = self.connection.transaction().map_err(|err| err.to_string())?;
+        tx.execute("DELETE FROM nodes", NO_PARAMS)
+            .and_then(|_| insert_subtree(&tx, tree, None, 0))
+            .map_err(|err| err.to_string())?;
+        tx.commit().map_err(|err| err.to_string())
+    }
+
+    /// Reads the outline. An empty database is an empty outline.
+    pub fn load(&self) -> Result<TreeNode, String> {
+        load_tree(&self.connection).map_err(|err| err.to_string())
+    }
+
+    /// Applies `operations`, in order, to the rows they touch, in one transaction. Returns
+    /// `false`, leaving the database untouched, if any of them can't be applied, like
+    /// `Operation::apply`.
+    pub fn apply(&mut self, operations: &[Operation]) -> Result<bool, String> {
+        let tx = self.connection.transaction().map_err(|err| err.to_string())?;
+        for operation in operations {
+            if !apply_in(&tx, operation).map_err(|err| err.to_string())? {
+                return Ok(false);
+            }
+        }
+        tx.commit().map_err(|err| err.to_string())?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tree::Tree;
+
+    fn database() -> Database {
+        Database::open(Path::new(":memory:")).unwrap()
+    }
+
+    #[test]
+    fn sqlite_roundtrip_and_operations() {
+        let mut tree = TreeNode::import_from_sofer(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000 done=F;n=2;tag="x";n=3; One
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000000  Two
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000001  Three
+"#).unwrap();
+        tree.eval_all();
+        let mut db = database();
+        db.save(&tree).unwrap();
+        assert_eq!(db.load().unwrap(), tree);
+
+        let uuid = |n: u8| Uuid::parse_str(&format!("00000000-0000-0000-0000-0000000000{:02}", n)).unwrap();
+        let child = Tree::new_child(Node::new("Four".into(), vec![]));
+        let operations = vec![
+            Operation::insert_next_to(&tree, uuid(1), child.clone()).unwrap(),
+            Operation::move_to(&tree, uuid(2), uuid(1), None).unwrap(),
+            Operation::set_raw(&tree, uuid(3), "Three, edited".into()).unwrap(),
+            Operation::set_attr(&tree, uuid(1), "done", Some(Attribute::Boolean("done".into(), true))).unwrap(),
+            Operation::set_attr(&tree, uuid(1), "n", None).unwrap(),
+            Operation::set_script(&tree, uuid(2), Some("1 + 1".into())).unwrap(),
+        ];
+        for operation in operations {
+            assert!(operation.apply(&mut tree));
+            assert!(db.apply(&[operation]).unwrap());
+            assert_eq!(db.load().unwrap(), tree);
+        }
+
+        // Undoing the removal of an attribute puts it back where it was.
+        let operation = Operation::set_attr(&tree, uuid(1), "tag", None).unwrap();
+        for operation in [operation.clone(), operation.invert()] {
+            assert!(operation.apply(&mut tree));
+            assert!(db.apply(&[operation]).unwrap());
+            assert_eq!(db.load().unwrap(), tree);
+        }
+        assert_eq!(tree.find(uuid(1)).unwrap().value.attributes[1].name(), "tag");
+
+        let operation = Operation::delete(&tree, uuid(1)).unwrap();
+        assert!(operation.apply(&mut tree));
+        assert!(db.apply(&[operation]).unwrap());
+        assert_eq!(db.load().unwrap(), tree);
+
+        // Moving a node into itself is refused, along with the changes applied with it.
+        let edit = Operation::set_raw(&tree, child.uuid, "Four, edited".into()).unwrap();
+        let operation = Operation::Move { uuid: child.uuid, from: (Uuid::nil(), None), to: (child.uuid, None) };
+        assert!(!db.apply(&[edit, operation]).unwrap());
+        assert_eq!(db.load().unwrap(), tree);
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use node::{Attribute, Node, TreeNode};
+
+/* Tags are `#tag` and `@context` words in the text of a node, and the comma-separated ones of
+ * its `tags` attribute, like `tags="work,@home";`, where a tag without `#` or `@` is a `#` one.
+ * They're kept with their `#` or `@`. A tag is a word after a space or at the start of the text,
+ * starting with a letter and made of letters, digits, `_` and `-`, ending the text or followed by
+ * a space or a punctuation mark.
+ */
+
+const TAGS: &str = "tags";
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+/// The word of a tag or a context starting right after the `#` or `@` at `i`, if there's one.
+fn tag_word(text: &str, i: usize) -> Option<&str> {
+    if text[..i].chars().next_back().map(|c| !c.is_whitespace() && c != '(').unwrap_or(false) {
+        return None;
+    }
+    let rest = &text[i + 1..];
+    if !rest.chars().next().map(char::is_alphabetic).unwrap_or(false) {
+        return None;
+    }
+    let end = rest.find(|c| !is_word_char(c)).unwrap_or(rest.len());
+    let word = rest[..end].trim_end_matches('-');
+    let after = &rest[word.len()..];
+    let ends = match after.chars().next() {
+        None => true,
+        Some(c) if c.is_whitespace() || ",;:!?)]".contains(c) => true,
+        Some('.') => after[1..].chars().next().map(char::is_whitespace).unwrap_or(true),
+        Some(_) => false,
+    };
+    if ends {
+        Some(word)
+    } else {
+        None
+    }
+}
+
+/// The `#tag` and `@context` words of `text`.
+pub fn parse(text: &str) -> BTreeSet<String> {
+    text.match_indices(['#', '@'])
+        .filter_map(|(i, sigil)| tag_word(text, i).map(|word| format!("{}{}", sigil, word)))
+        .collect()
+}
+
+/// `tag` with `#` if it has neither `#` nor `@`.
+pub fn normalize(tag: &str) -> String {
+    let tag = tag.trim();
+    if tag.starts_with('#') || tag.starts_with('@') {
+        tag.into()
+    } else {
+        format!("#{}", tag)
+    }
+}
+
+impl Node {
+    /// The tags of the text and of the `tags` attribute.
+    pub fn tags(&self) -> BTreeSet<String> {
+        let mut tags = parse(&self.raw);
+        if let Some(Attribute::String(_, value)) = self.get_attribute(TAGS) {
+            tags.extend(value.split(',').filter(|t| !t.trim().is_empty()).map(normalize));
+        }
+        tags
+    }
+}
+
+/// The tags of the nodes of `tree` with the number of nodes that have them, the most used first.
+pub fn count(tree: &TreeNode) -> Vec<(String, usize)> {
+    let mut counts = BTreeMap::new();
+    for n in tree.nodes() {
+        for tag in n.value.tags() {
+            *counts.entry(tag).or_insert(0) += 1;
+        }
+    }
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_by_key(|&(_, count)| ::std::cmp::Reverse(count));
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(raw: &str, attributes: Vec<Attribute>) -> Vec<String> {
+        Node::new(raw.into(), attributes).tags().into_iter().collect()
+    }
+
+    #[test]
+    fn tags_and_contexts() {
+        assert_eq!(tags("Call Bob #work @phone, (#urgent).", vec![]), vec!["#urgent", "#work", "@phone"]);
+        assert_eq!(tags("# Title, issue#3 and #-x", vec![]), Vec::<String>::new());
+        assert_eq!(tags("Mail bob@example.com @return", vec![]), vec!["@return"]);
+        assert_eq!(tags("Notes", vec![Attribute::String("tags".into(), "work,@home".into())]), vec!["#work", "@home"]);
+    }
+
+    #[test]
+    fn count_tags() {
+        let tree = TreeNode::import_from_sofer(
+r#"sofer 2
+00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  Plan #work
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000001 tags="work"; Call @phone
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000000  Groceries @shop
+"#).unwrap();
+        assert_eq!(count(&tree), vec![("#work".into(), 2), ("@phone".into(), 1), ("@shop".into(), 1)]);
+    }
+
+    #[test]
+    fn tags_in_scripts() {
+        let mut n = ::tree::Tree::new_tree(Node::new("Call #work @phone: ".into(), vec![]));
+        n.value.script = Some("function(n) return table.concat(n.tags, ' ') .. '/' .. #n.value.tags end".into());
+        assert_eq!(n.eval(&n.clone()), Ok("Call #work @phone: #work @phone/2".into()));
+    }
+}
@@ -0,0 +1,270 @@
+use std::fmt;
+use uuid::Uuid;
+
+use date;
+use habit;
+use node::{Attribute, TreeNode};
+use oplog::Operation;
+
+/* Tasks are nodes with a `status` attribute holding a state of their workflow. A workflow is
+ * declared on a node with `workflow="TODO,DOING|DONE,CANCELLED";` and applies to it and its
+ * descendants: the states before `|` are open and the ones after it are closed. Nodes without a
+ * workflow declared above them use that one.
+ *
+ * Closing a task sets its `completed_at` to the time, and reopening it removes it, except for
+ * recurring items (see `habit`), which are reopened right away. `eval_all`
+ * adds the progress of the tasks among the children of a node to its evaled text, as `[2/5]`.
+ */
+
+const STATUS: &str = "status";
+const COMPLETED_AT: &str = "completed_at";
+const WORKFLOW: &str = "workflow";
+const DEFAULT_WORKFLOW: &str = "TODO,DOING|DONE,CANCELLED";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Workflow {
+    pub open: Vec<String>,
+    pub closed: Vec<String>,
+}
+
+impl Workflow {
+    /// Reads `OPEN,...|CLOSED,...`. Without `|`, the last state is the only closed one.
+    pub fn parse(str: &str) -> Option<Workflow> {
+        let words = |str: &str| {
+            str.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        let (open, closed) = match str.find('|') {
+            Some(i) => (words(&str[..i]), words(&str[i + 1..])),
+            None => {
+                let mut open = words(str);
+                let closed = open.pop().into_iter().collect();
+                (open, closed)
+            }
+        };
+        if open.is_empty() || closed.is_empty() {
+            None
+        } else {
+            Some(Workflow { open, closed })
+        }
+    }
+
+    pub fn contains(&self, state: &str) -> bool {
+        self.open.iter().chain(&self.closed).any(|s| s == state)
+    }
+
+    pub fn is_closed(&self, state: &str) -> bool {
+        self.closed.iter().any(|s| s == state)
+    }
+}
+
+impl Default for Workflow {
+    fn default() -> Workflow {
+        Workflow::parse(DEFAULT_WORKFLOW).unwrap()
+    }
+}
+
+impl fmt::Display for Workflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}|{}", self.open.join(","), self.closed.join(","))
+    }
+}
+
+/// The workflow declared on `n` itself, if any.
+pub fn declared_workflow(n: &TreeNode) -> Option<Workflow> {
+    match n.value.get_attribute(WORKFLOW) {
+        Some(Attribute::String(_, spec)) => Workflow::parse(spec),
+        _ => None,
+    }
+}
+
+/// The workflow of the node `uuid`: the one declared on it or on its closest ancestor.
+pub fn workflow(tree: &TreeNode, uuid: Uuid) -> Workflow {
+    let mut current = tree.find(uuid);
+    while let Some(n) = current {
+        if let Some(workflow) = declared_workflow(n) {
+            return workflow;
+        }
+        current = tree.find_parent(n.uuid);
+    }
+    Workflow::default()
+}
+
+pub fn status(n: &TreeNode) -> Option<&str> {
+    match n.value.get_attribute(STATUS) {
+        Some(Attribute::String(_, state)) => Some(state),
+        _ => None,
+    }
+}
+
+/// The changes that set the status of the node `uuid` to `state`, or remove it if `state` is
+/// `None`, and update its `completed_at`. `now` is in seconds since the Unix epoch.
+pub fn set_status(tree: &TreeNode, uuid: Uuid, state: Option<&str>, now: i64) -> Result<Vec<Operation>, String> {
+    let n = tree.find(uuid).ok_or(format!("Couldn't find node with UUID \"{}\"", uuid))?;
+    let workflow = workflow(tree, uuid);
+    if let Some(state) = state {
+        if !workflow.contains(state) {
+            return Err(format!("\"{}\" isn't a state of the workflow \"{}\"", state, workflow));
+        }
+    }
+
+    let was_closed = status(n).map(|s| workflow.is_closed(s)).unwrap_or(false);
+    let closed = state.map(|s| workflow.is_closed(s)).unwrap_or(false);
+    if closed && !was_closed {
+        // Recurring items are reopened for their next occurrence instead.
+        if let Some(operations) = habit::complete(tree, uuid, &workflow.open[0], now) {
+            return Ok(operations);
+        }
+    }
+    let status = state.map(|s| Attribute::String(STATUS.into(), s.into()));
+    let mut operations = vec![Operation::set_attr(tree, uuid, STATUS, status).unwrap()];
+    if closed && !was_closed {
+        let completed_at = Attribute::String(COMPLETED_AT.into(), date::timestamp(now));
+        operations.push(Operation::set_attr(tree, uuid, COMPLETED_AT, Some(completed_at)).unwrap());
+    } else if !closed && n.value.get_attribute(COMPLETED_AT).is_some() {
+        operations.push(Operation::set_attr(tree, uuid, COMPLETED_AT, None).unwrap());
+    }
+    Ok(operations)
+}
+
+/// The changes that move the node `uuid` along its workflow: a node that isn't a task becomes
+/// one in the first open state, an open task is closed with the first closed state, and a closed
+/// one is reopened with the first open state.
+pub fn toggle(tree: &TreeNode, uuid: Uuid, now: i64) -> Result<Vec<Operation>, String> {
+    let n = tree.find(uuid).ok_or(format!("Couldn't find node with UUID \"{}\"", uuid))?;
+    let workflow = workflow(tree, uuid);
+    let state = match status(n) {
+        Some(state) if workflow.contains(state) && !workflow.is_closed(state) => workflow.closed[0].clone(),
+        _ => workflow.open[0].clone(),
+    };
+    set_status(tree, uuid, Some(&state), now)
+}
+
+pub struct Task {
+    pub uuid: Uuid,
+    pub status: String,
+    pub closed: bool,
+    pub text: String,
+}
+
+/// The tasks of `tree`, in order, that are in one of `states`, or in any state if it's empty.
+/// `open` and `closed` stand for all the open or closed states.
+pub fn list(tree: &TreeNode, states: &[&str]) -> Vec<Task> {
+    fn collect(n: &TreeNode, workflow: &Workflow, states: &[&str], tasks: &mut Vec<Task>) {
+        let declared = declared_workflow(n);
+        let workflow = declared.as_ref().unwrap_or(workflow);
+        if let Some(status) = status(n) {
+            let closed = workflow.is_closed(status);
+            let wanted = states.is_empty() || states.iter().any(|&s| {
+                s == status || (s == "open" && !closed) || (s == "closed" && closed)
+            });
+            if wanted {
+                tasks.push(Task {
+                    uuid: n.uuid,
+                    status: status.into(),
+                    closed,
+                    text: n.value.raw.lines().next().unwrap_or("").into(),
+                });
+            }
+        }
+        for child in n.children() {
+            collect(child, workflow, states, tasks);
+        }
+    }
+
+    let mut tasks = Vec::new();
+    collect(tree, &Workflow::default(), states, &mut tasks);
+    tasks
+}
+
+/// How many of the tasks among the children of `n` are closed, and how many there are, if
+/// there are any. `workflow` is the workflow of `n`.
+pub fn progress(n: &TreeNode, workflow: &Workflow) -> Option<(usize, usize)> {
+    let (mut closed, mut total) = (0, 0);
+    for child in n.children() {
+        if let Some(status) = status(child) {
+            let declared = declared_workflow(child);
+            total += 1;
+            if declared.as_ref().unwrap_or(workflow).is_closed(status) {
+                closed += 1;
+            }
+        }
+    }
+    if total > 0 {
+        Some((closed, total))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree() -> TreeNode {
+        TreeNode::import_from_sofer(
+r#"00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  Project
+00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000001 status="TODO"; Write
+00000000-0000-0000-0000-000000000003 00000000-0000-0000-0000-000000000001 status="DONE"; Read
+00000000-0000-0000-0000-000000000004 00000000-0000-0000-0000-000000000001  Notes
+00000000-0000-0000-0000-000000000005 00000000-0000-0000-0000-000000000000 workflow="OPEN|SHIPPED"; Release
+00000000-0000-0000-0000-000000000006 00000000-0000-0000-0000-000000000005 status="OPEN"; Tag
+"#).unwrap()
+    }
+
+    fn uuid(n: u8) -> Uuid {
+        Uuid::parse_str(&format!("00000000-0000-0000-0000-{:012}", n)).unwrap()
+    }
+
+    fn apply(tree: &mut TreeNode, operations: Vec<Operation>) {
+        for operation in operations {
+            assert!(operation.apply(tree));
+        }
+    }
+
+    #[test]
+    fn workflows() {
+        assert_eq!(Workflow::parse("A,B,C"), Some(Workflow { open: vec!["A".into(), "B".into()], closed: vec!["C".into()] }));
+        assert_eq!(Workflow::parse("A |"), None);
+        let tree = tree();
+        assert_eq!(workflow(&tree, uuid(2)), Workflow::default());
+        assert_eq!(workflow(&tree, uuid(6)).to_string(), "OPEN|SHIPPED");
+    }
+
+    #[test]
+    fn toggle_and_set_status() {
+        let mut tree = tree();
+        let operations = toggle(&tree, uuid(2), 86400).unwrap();
+        apply(&mut tree, operations);
+        let n = tree.find(uuid(2)).unwrap();
+        assert_eq!(status(n), Some("DONE"));
+        assert_eq!(n.value.get_attribute(COMPLETED_AT), Some(&Attribute::String(COMPLETED_AT.into(), "1970-01-02T00:00:00Z".into())));
+
+        let operations = toggle(&tree, uuid(2), 0).unwrap();
+        apply(&mut tree, operations);
+        let n = tree.find(uuid(2)).unwrap();
+        assert_eq!(status(n), Some("TODO"));
+        assert_eq!(n.value.get_attribute(COMPLETED_AT), None);
+
+        let operations = toggle(&tree, uuid(4), 0).unwrap();
+        apply(&mut tree, operations);
+        assert_eq!(status(tree.find(uuid(4)).unwrap()), Some("TODO"));
+
+        assert!(set_status(&tree, uuid(6), Some("DONE"), 0).is_err());
+        let operations = set_status(&tree, uuid(6), Some("SHIPPED"), 0).unwrap();
+        apply(&mut tree, operations);
+        assert_eq!(list(&tree, &["closed"]).iter().map(|t| t.uuid).collect::<Vec<_>>(), vec![uuid(3), uuid(6)]);
+        assert_eq!(list(&tree, &["TODO"]).len(), 2);
+    }
+
+    #[test]
+    fn progress_in_eval_all() {
+        let mut tree = tree();
+        tree.eval_all();
+        assert_eq!(tree.find(uuid(1)).unwrap().value.evaled, Some("Project [1/2]".into()));
+        assert_eq!(tree.find(uuid(5)).unwrap().value.evaled, Some("Release [0/1]".into()));
+        assert_eq!(tree.find(uuid(4)).unwrap().value.evaled, Some("Notes".into()));
+    }
+}
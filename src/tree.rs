@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use rlua;
 use uuid::Uuid;
 
@@ -169,6 +170,194 @@ impl<T> Tree<T>
             }
         }
     }
+
+    /// Inserts `new_node` as the first child of the node `parent_uuid`.
+    pub fn insert_first(&mut self, parent_uuid: Uuid, mut new_node: Tree<T>) -> bool {
+        match self.find_mut(parent_uuid) {
+            Some(parent) => {
+                new_node.next_sibling = parent.first_child.take();
+                parent.first_child = Some(Box::new(new_node));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Inserts `new_node` under the node `parent_uuid`, right after its child `after`, or as its
+    /// first child if `after` is `None`.
+    pub fn insert_at(&mut self, parent_uuid: Uuid, after: Option<Uuid>, new_node: Tree<T>) -> bool {
+        match after {
+            Some(after) => {
+                let is_child = self.find(parent_uuid)
+                    .map(|parent| parent.children().any(|child| child.uuid == after))
+                    .unwrap_or(false);
+                is_child && self.insert_next_to(after, new_node)
+            }
+            None => self.insert_first(parent_uuid, new_node),
+        }
+    }
+
+    /// Parent of the node `uuid`, searching the descendants of `self`.
+    pub fn find_parent(&self, uuid: Uuid) -> Option<&Tree<T>> {
+        for child in self.children() {
+            if child.uuid == uuid {
+                return Some(self);
+            }
+            if let Some(parent) = child.find_parent(uuid) {
+                return Some(parent);
+            }
+        }
+        None
+    }
+
+    /// Where the node `uuid` is: the UUID of its parent and of the sibling right before it, if
+    /// any.
+    pub fn position(&self, uuid: Uuid) -> Option<(Uuid, Option<Uuid>)> {
+        let parent = self.find_parent(uuid)?;
+        let mut previous = None;
+        for child in parent.children() {
+            if child.uuid == uuid {
+                break;
+            }
+            previous = Some(child.uuid);
+        }
+        Some((parent.uuid, previous))
+    }
+
+    /// Detaches the node `uuid`, with its children, from the descendants of `self`.
+    pub fn remove(&mut self, uuid: Uuid) -> Option<Tree<T>> {
+        let is_first_child = self.first_child.as_ref().map(|n| n.uuid == uuid).unwrap_or(false);
+        if is_first_child {
+            let mut removed = self.first_child.take().unwrap();
+            self.first_child = removed.next_sibling.take();
+            return Some(*removed);
+        }
+
+        let mut current = self.first_child.as_deref_mut();
+        while let Some(n) = current {
+            let is_next_sibling = n.next_sibling.as_ref().map(|n| n.uuid == uuid).unwrap_or(false);
+            if is_next_sibling {
+                let mut removed = n.next_sibling.take().unwrap();
+                n.next_sibling = removed.next_sibling.take();
+                return Some(*removed);
+            }
+            if let Some(removed) = n.remove(uuid) {
+                return Some(removed);
+            }
+            current = n.next_sibling.as_deref_mut();
+        }
+        None
+    }
+
+    /// Replaces the children of this node with `children`, in order.
+    pub fn set_children(&mut self, children: Vec<Tree<T>>) {
+        let mut next = None;
+        for mut child in children.into_iter().rev() {
+            child.next_sibling = next;
+            next = Some(Box::new(child));
+        }
+        self.first_child = next;
+    }
+
+    /// A copy of the node `uuid` with its descendants, down to `depth` levels below it if given.
+    /// The copy has no siblings.
+    pub fn subtree(&self, uuid: Uuid, depth: Option<usize>) -> Option<Tree<T>> {
+        let mut n = self.find(uuid)?.clone();
+        n.next_sibling = None;
+        if let Some(depth) = depth {
+            n.prune(depth);
+        }
+        Some(n)
+    }
+
+    /// A copy of this node with its descendants, without its siblings, where every node has a new
+    /// UUID. Also returns the new UUIDs by the old ones.
+    pub fn clone_with_new_ids(&self) -> (Tree<T>, HashMap<Uuid, Uuid>) {
+        let mut copy = self.clone();
+        copy.next_sibling = None;
+        let mut ids = HashMap::new();
+        copy.renew_ids(&mut ids);
+        (copy, ids)
+    }
+
+    fn renew_ids(&mut self, ids: &mut HashMap<Uuid, Uuid>) {
+        let uuid = Uuid::new_v4();
+        ids.insert(self.uuid, uuid);
+        self.uuid = uuid;
+        let mut child = self.first_child.as_deref_mut();
+        while let Some(n) = child {
+            n.renew_ids(ids);
+            child = n.next_sibling.as_deref_mut();
+        }
+    }
+
+    fn prune(&mut self, depth: usize) {
+        let children = if depth == 0 {
+            Vec::new()
+        } else {
+            self.get_children().into_iter().map(|mut child| { child.prune(depth - 1); child }).collect()
+        };
+        self.set_children(children);
+    }
+
+    /// Iterates over the children without cloning them, unlike `get_children`.
+    pub fn children<'a>(&'a self) -> Children<'a, T> {
+        Children { next: self.first_child.as_deref() }
+    }
+
+    /// This node and all the ones below it, depth first, without cloning them, unlike `traverse`.
+    pub fn nodes(&self) -> Vec<&Tree<T>> {
+        fn push<'a, T: Clone>(n: &'a Tree<T>, nodes: &mut Vec<&'a Tree<T>>) {
+            nodes.push(n);
+            for child in n.children() {
+                push(child, nodes);
+            }
+        }
+        let mut nodes = Vec::new();
+        push(self, &mut nodes);
+        nodes
+    }
+}
+
+/// Values that refer to other nodes by UUID, so that copies of a subtree can be made to refer to
+/// the copied nodes (see `deep_clone_with_new_ids`).
+pub trait RemapIds {
+    /// Changes every UUID `ids` has to the one it maps to.
+    fn remap_ids(&mut self, ids: &HashMap<Uuid, Uuid>);
+}
+
+impl<T> Tree<T>
+    where T: Clone + RemapIds {
+    /// A copy of this node with its descendants, where every node has a new UUID, as
+    /// `clone_with_new_ids`. The values of the copied nodes refer to the copies instead of the
+    /// nodes they were copied from, and to other nodes as before.
+    pub fn deep_clone_with_new_ids(&self) -> (Tree<T>, HashMap<Uuid, Uuid>) {
+        fn remap<T: Clone + RemapIds>(n: &mut Tree<T>, ids: &HashMap<Uuid, Uuid>) {
+            n.value.remap_ids(ids);
+            let mut child = n.first_child.as_deref_mut();
+            while let Some(c) = child {
+                remap(c, ids);
+                child = c.next_sibling.as_deref_mut();
+            }
+        }
+        let (mut copy, ids) = self.clone_with_new_ids();
+        remap(&mut copy, &ids);
+        (copy, ids)
+    }
+}
+
+pub struct Children<'a, T: 'a> {
+    next: Option<&'a Tree<T>>,
+}
+
+impl<'a, T> Iterator for Children<'a, T> {
+    type Item = &'a Tree<T>;
+
+    fn next(&mut self) -> Option<&'a Tree<T>> {
+        let current = self.next;
+        self.next = current.and_then(|n| n.next_sibling.as_deref());
+        current
+    }
 }
 
 impl<'lua, T> rlua::ToLua<'lua> for Tree<T>
@@ -460,6 +649,127 @@ mod tests {
         )
     }
 
+    #[test]
+    fn tree_children() {
+        let mut tree = Tree::new_tree("top");
+        let first = Tree::new_child("first");
+        let second = Tree::new_child("second");
+        let first_first = Tree::new_child("first first");
+        tree.insert(Uuid::nil(), first.clone());
+        tree.insert(Uuid::nil(), second.clone());
+        tree.insert(first.uuid, first_first.clone());
+        assert_eq!(
+            tree.children().map(|x| x.value).collect::<Vec<_>>(),
+            vec!["first", "second"]
+        );
+        assert_eq!(
+            tree.find(second.uuid).unwrap().children().count(),
+            0
+        );
+
+        tree.set_children(vec![second.clone(), first.clone()]);
+        assert_eq!(
+            tree.children().map(|x| x.value).collect::<Vec<_>>(),
+            vec!["second", "first"]
+        );
+    }
+
+    #[test]
+    fn tree_remove_and_insert_at() {
+        let mut tree = Tree::new_tree("top");
+        let first = Tree::new_child("first");
+        let second = Tree::new_child("second");
+        let third = Tree::new_child("third");
+        let second_first = Tree::new_child("second first");
+        tree.insert(Uuid::nil(), first.clone());
+        tree.insert(Uuid::nil(), second.clone());
+        tree.insert(Uuid::nil(), third.clone());
+        tree.insert(second.uuid, second_first.clone());
+
+        assert_eq!(tree.position(first.uuid), Some((Uuid::nil(), None)));
+        assert_eq!(tree.position(third.uuid), Some((Uuid::nil(), Some(second.uuid))));
+        assert_eq!(tree.position(second_first.uuid), Some((second.uuid, None)));
+        assert_eq!(tree.position(Uuid::nil()), None);
+
+        let removed = tree.remove(second.uuid).unwrap();
+        assert_eq!(removed.value, "second");
+        assert!(removed.next_sibling.is_none());
+        assert_eq!(removed.get_children().iter().map(|x| x.value).collect::<Vec<_>>(), vec!["second first"]);
+        assert_eq!(tree.get_children().iter().map(|x| x.value).collect::<Vec<_>>(), vec!["first", "third"]);
+        assert!(tree.remove(second_first.uuid).is_none());
+
+        assert!(!tree.insert_at(Uuid::nil(), Some(second.uuid), removed.clone()));
+        assert!(tree.insert_at(third.uuid, None, removed.clone()));
+        assert_eq!(tree.find_parent(second_first.uuid).unwrap().uuid, second.uuid);
+        let removed = tree.remove(second.uuid).unwrap();
+        assert!(tree.insert_at(Uuid::nil(), Some(first.uuid), removed));
+        assert_eq!(tree.get_children().iter().map(|x| x.value).collect::<Vec<_>>(), vec!["first", "second", "third"]);
+        assert!(tree.insert_at(Uuid::nil(), None, Tree::new_child("zeroth")));
+        assert_eq!(tree.get_children()[0].value, "zeroth");
+    }
+
+    #[test]
+    fn tree_subtree() {
+        let mut tree = Tree::new_tree("top");
+        let first = Tree::new_child("first");
+        let second = Tree::new_child("second");
+        let first_first = Tree::new_child("first first");
+        tree.insert(Uuid::nil(), first.clone());
+        tree.insert(Uuid::nil(), second.clone());
+        tree.insert(first.uuid, first_first.clone());
+        tree.insert(first_first.uuid, Tree::new_child("first first first"));
+
+        let subtree = tree.subtree(first.uuid, None).unwrap();
+        assert!(subtree.next_sibling.is_none());
+        assert_eq!(subtree.traverse().len(), 3);
+        let subtree = tree.subtree(first.uuid, Some(1)).unwrap();
+        assert_eq!(subtree.traverse().iter().map(|x| x.1.value).collect::<Vec<_>>(), vec!["first", "first first"]);
+        assert!(tree.subtree(Uuid::nil(), Some(0)).unwrap().first_child.is_none());
+        assert!(tree.subtree(Uuid::new_v4(), None).is_none());
+    }
+
+    #[test]
+    fn tree_clone_with_new_ids() {
+        let mut tree = Tree::new_tree("top");
+        let first = Tree::new_child("first");
+        let first_first = Tree::new_child("first first");
+        tree.insert(Uuid::nil(), first.clone());
+        tree.insert(Uuid::nil(), Tree::new_child("second"));
+        tree.insert(first.uuid, first_first.clone());
+
+        let (copy, ids) = tree.find(first.uuid).unwrap().clone_with_new_ids();
+        assert!(copy.next_sibling.is_none());
+        assert_eq!(ids.len(), 2);
+        assert_eq!(copy.uuid, ids[&first.uuid]);
+        assert_eq!(copy.get_children()[0].uuid, ids[&first_first.uuid]);
+        assert_ne!(copy.uuid, first.uuid);
+        assert_eq!(copy.get_children()[0].value, "first first");
+    }
+
+    impl RemapIds for Uuid {
+        fn remap_ids(&mut self, ids: &HashMap<Uuid, Uuid>) {
+            if let Some(new) = ids.get(self) {
+                *self = *new;
+            }
+        }
+    }
+
+    #[test]
+    fn tree_deep_clone_with_new_ids() {
+        let mut tree = Tree::new_tree(Uuid::nil());
+        let outside = Tree::new_child(Uuid::nil());
+        let first = Tree::new_child(Uuid::nil());
+        let child = Tree::new_child(outside.uuid);
+        tree.insert(Uuid::nil(), outside.clone());
+        tree.insert(Uuid::nil(), first.clone());
+        tree.insert(first.uuid, child.clone());
+        tree.find_mut(first.uuid).unwrap().value = child.uuid;
+
+        let (copy, ids) = tree.find(first.uuid).unwrap().deep_clone_with_new_ids();
+        assert_eq!(copy.value, ids[&child.uuid]);
+        assert_eq!(copy.get_children()[0].value, outside.uuid);
+    }
+
     #[test]
     fn tree_from_lua() {
         let lua_code = r#"
@@ -0,0 +1,97 @@
+/* Exercises the C interface of sofer-core. From the root of the repository:
+ *
+ *     cargo build
+ *     cc tests/c/ffi_test.c -Iinclude -Ltarget/debug -lsofer_core -o target/ffi_test
+ *     LD_LIBRARY_PATH=target/debug target/ffi_test
+ */
+
+#include <assert.h>
+#include <stdio.h>
+#include <string.h>
+
+#include "sofer.h"
+
+#define ROOT "00000000-0000-0000-0000-000000000000"
+
+static const char *OUTLINE =
+    "00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000000  One\n"
+    "00000000-0000-0000-0000-000000000002 00000000-0000-0000-0000-000000000001  Two\n";
+
+static void expect_text(const SoferTree *tree, const char *uuid, const char *expected) {
+    char *text = sofer_tree_text(tree, uuid);
+    assert(text != NULL);
+    assert(strcmp(text, expected) == 0);
+    sofer_string_free(text);
+}
+
+int main(void) {
+    SoferTree *tree = sofer_tree_load(OUTLINE, "sofer");
+    assert(tree != NULL);
+    assert(sofer_tree_contains(tree, "00000000-0000-0000-0000-000000000002") == 1);
+    expect_text(tree, "00000000-0000-0000-0000-000000000001", "One");
+
+    /* Editing. */
+    char *three = sofer_tree_insert(tree, ROOT, "00000000-0000-0000-0000-000000000001", "Three");
+    assert(three != NULL);
+    int result = sofer_tree_move(tree, "00000000-0000-0000-0000-000000000002", three, NULL);
+    assert(result == 0);
+    result = sofer_tree_set_text(tree, "00000000-0000-0000-0000-000000000001", "Sum: ");
+    assert(result == 0);
+    result = sofer_tree_set_script(tree, "00000000-0000-0000-0000-000000000001", "function(node) return tostring(1 + 1) end");
+    assert(result == 0);
+
+    char *evaled = sofer_tree_eval(tree, "00000000-0000-0000-0000-000000000001");
+    assert(evaled != NULL);
+    assert(strcmp(evaled, "Sum: 2") == 0);
+    sofer_string_free(evaled);
+
+    /* Children, in order. */
+    SoferChildren *children = sofer_tree_children(tree, ROOT);
+    assert(children != NULL);
+    char *child = sofer_children_next(children);
+    assert(strcmp(child, "00000000-0000-0000-0000-000000000001") == 0);
+    sofer_string_free(child);
+    child = sofer_children_next(children);
+    assert(strcmp(child, three) == 0);
+    sofer_string_free(child);
+    child = sofer_children_next(children);
+    assert(child == NULL);
+    sofer_children_free(children);
+
+    /* Saving and loading back. */
+    char *saved = sofer_tree_save(tree, "sofer", 0);
+    assert(saved != NULL);
+    SoferTree *loaded = sofer_tree_load(saved, "sofer");
+    sofer_string_free(saved);
+    assert(loaded != NULL);
+    expect_text(loaded, "00000000-0000-0000-0000-000000000002", "Two");
+    result = sofer_tree_delete(loaded, three);
+    assert(result == 0);
+    assert(sofer_tree_contains(loaded, "00000000-0000-0000-0000-000000000002") == 0);
+    sofer_tree_free(loaded);
+
+    result = sofer_tree_save_sqlite(tree, "target/ffi_test.db");
+    assert(result == 0);
+    loaded = sofer_tree_load_sqlite("target/ffi_test.db");
+    assert(loaded != NULL);
+    expect_text(loaded, three, "Three");
+    sofer_tree_free(loaded);
+    remove("target/ffi_test.db");
+    sofer_string_free(three);
+
+    /* Errors. */
+    result = sofer_tree_delete(tree, "not a uuid");
+    assert(result == -1);
+    assert(strstr(sofer_last_error(), "not a uuid") != NULL);
+    char *missing = sofer_tree_text(tree, "00000000-0000-0000-0000-000000000009");
+    assert(missing == NULL);
+    assert(strstr(sofer_last_error(), "Couldn't find node") != NULL);
+    loaded = sofer_tree_load("", "html");
+    assert(loaded == NULL);
+    saved = sofer_tree_save(tree, "opml", 0);
+    assert(saved == NULL);
+
+    sofer_tree_free(tree);
+    printf("ok\n");
+    return 0;
+}